@@ -4,7 +4,8 @@ use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 
-const CPU_CLOCK: f32 = 1_789_772.5;  // 1.789 MHz
+use crate::region::Region;
+
 const _CLOCK_DIV: usize = 7457;      // 7457分周
 
 const _DUTY_12P5: f32 = 0.125;       // Duty 12.5％
@@ -48,6 +49,16 @@ lazy_static! {
         noise_tbl.iter().map(|&x| x as f32).collect()
     };
 
+    pub static ref NOISE_TBL_PAL: Vec<f32> = {
+        let noise_tbl: Vec<u16> = vec![
+            0x0002, 0x0004, 0x0007, 0x000F, 0x001E,
+            0x002C, 0x003B, 0x004A, 0x005E, 0x0076,
+            0x00B1, 0x00EC, 0x0162, 0x01D8, 0x03B1, 0x0761,
+        ];
+
+        noise_tbl.iter().map(|&x| x as f32).collect()
+    };
+
     pub static ref  LENGTH_COUNTER_TBL: Vec<u8> = vec![
         0x05, 0x7F, 0x0A, 0x01, 0x14, 0x02, 0x28, 0x03,
         0x50, 0x04, 0x1E, 0x05, 0x07, 0x06, 0x0D, 0x07,
@@ -65,18 +76,32 @@ pub struct APU {
     status: StatusRegister,
     cycles: usize,
     counter: usize,
+    region: Region,
+
+    // ヘッドレス(音声出力なし)の場合はデバイスも送信先も無く、何も送らない
+    ch1_device: Option<AudioDevice<SquareWave>>,
+    ch1_sender: EventSender<SquareEvent>,
+
+    ch2_device: Option<AudioDevice<SquareWave>>,
+    ch2_sender: EventSender<SquareEvent>,
 
-    ch1_device: AudioDevice<SquareWave>,
-    ch1_sender: Sender<SquareEvent>,
+    ch3_device: Option<AudioDevice<TriangleWave>>,
+    ch3_sender: EventSender<TriangleEvent>,
 
-    ch2_device: AudioDevice<SquareWave>,
-    ch2_sender: Sender<SquareEvent>,
+    ch4_device: Option<AudioDevice<NoiseWave>>,
+    ch4_sender: EventSender<NoiseEvent>,
+}
 
-    ch3_device: AudioDevice<TriangleWave>,
-    ch3_sender: Sender<TriangleEvent>,
+// 各チャンネルの音声スレッドへの送信
+struct EventSender<T>(Option<Sender<T>>);
 
-    ch4_device: AudioDevice<NoiseWave>,
-    ch4_sender: Sender<NoiseEvent>,
+impl<T> EventSender<T> {
+    fn send(&self, event: T) {
+        // 音声スレッドが落ちている時はエラーにする (ヘッドレスなら送らない)
+        if let Some(sender) = &self.0 {
+            sender.send(event).unwrap();
+        }
+    }
 }
 
 impl APU {
@@ -95,44 +120,74 @@ impl APU {
             status: StatusRegister::new(),
             cycles: 0,
             counter: 0,
+            region: Region::NTSC,
 
-            ch1_device: ch1_device,
-            ch1_sender: ch1_sender,
+            ch1_device: Some(ch1_device),
+            ch1_sender: EventSender(Some(ch1_sender)),
 
-            ch2_device: ch2_device,
-            ch2_sender: ch2_sender,
+            ch2_device: Some(ch2_device),
+            ch2_sender: EventSender(Some(ch2_sender)),
 
-            ch3_device: ch3_device,
-            ch3_sender: ch3_sender,
+            ch3_device: Some(ch3_device),
+            ch3_sender: EventSender(Some(ch3_sender)),
 
-            ch4_device: ch4_device,
-            ch4_sender: ch4_sender,
+            ch4_device: Some(ch4_device),
+            ch4_sender: EventSender(Some(ch4_sender)),
         }
     }
 
+    // 音声出力なし (テストROMランナー等のヘッドレス実行用)
+    pub fn new_headless() -> Self {
+        APU {
+            ch1_register: Ch1Register::new(),
+            ch2_register: Ch2Register::new(),
+            ch3_register: Ch3Register::new(),
+            ch4_register: Ch4Register::new(),
+            frame_counter: FrameCounter::new(),
+            status: StatusRegister::new(),
+            cycles: 0,
+            counter: 0,
+            region: Region::NTSC,
+
+            ch1_device: None,
+            ch1_sender: EventSender(None),
+
+            ch2_device: None,
+            ch2_sender: EventSender(None),
+
+            ch3_device: None,
+            ch3_sender: EventSender(None),
+
+            ch4_device: None,
+            ch4_sender: EventSender(None),
+        }
+    }
+
+    // 音程とフレームシーケンサの周期が変わる
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
     pub fn write1ch(&mut self, addr: u16, value: u8) {
         self.ch1_register.write(addr, value);
 
             self.ch1_sender
             .send(SquareEvent::Note(SquareNote {
                 duty: self.ch1_register.duty,
-            }))
-                .unwrap();
+            }));
 
         self.ch1_sender
                 .send(SquareEvent::Envelope(Envelope::new(
                 self.ch1_register.volume,
                     self.ch1_register.envelope_flag,
                     !self.ch1_register.key_off_counter_flag,
-                )))
-            .unwrap();
+                )));
 
         self.ch1_sender
             .send(SquareEvent::LengthCounter(LengthCounter::new(
                 self.ch1_register.key_off_counter_flag,
                 LENGTH_COUNTER_TBL[self.ch1_register.key_off_count as usize],
-            )))
-            .unwrap();
+            )));
 
         self.ch1_sender
             .send(SquareEvent::Sweep(Sweep::new(
@@ -141,11 +196,11 @@ impl APU {
                 self.ch1_register.sweep_direction,
                 self.ch1_register.sweep_timer_count,
                 self.ch1_register.sweep_enabled,
-            )))
-            .unwrap();
+                self.region.cpu_clock(),
+            )));
 
         if addr == 0x4003 {
-            self.ch1_sender.send(SquareEvent::Reset()).unwrap();
+            self.ch1_sender.send(SquareEvent::Reset());
         }
     }
 
@@ -155,23 +210,20 @@ impl APU {
             self.ch2_sender
             .send(SquareEvent::Note(SquareNote {
                 duty: self.ch2_register.duty,
-            }))
-                .unwrap();
+            }));
 
         self.ch2_sender
                 .send(SquareEvent::Envelope(Envelope::new(
                     self.ch2_register.volume,
                     self.ch2_register.envelope_flag,
                     !self.ch2_register.key_off_counter_flag,
-                )))
-            .unwrap();
+                )));
 
         self.ch2_sender
             .send(SquareEvent::LengthCounter(LengthCounter::new(
                 self.ch2_register.key_off_counter_flag,
                 LENGTH_COUNTER_TBL[self.ch2_register.key_off_count as usize],
-            )))
-            .unwrap();
+            )));
 
         self.ch2_sender
             .send(SquareEvent::Sweep(Sweep::new(
@@ -180,11 +232,11 @@ impl APU {
                 self.ch2_register.sweep_direction,
                 self.ch2_register.sweep_timer_count,
                 self.ch2_register.sweep_enabled,
-            )))
-            .unwrap();
+                self.region.cpu_clock(),
+            )));
 
         if addr == 0x4007 {
-            self.ch2_sender.send(SquareEvent::Reset()).unwrap();
+            self.ch2_sender.send(SquareEvent::Reset());
         }
     }
 
@@ -194,25 +246,28 @@ impl APU {
         self.ch3_sender
             .send(TriangleEvent::Note(TriangleNote {
                 frequency: self.ch2_register.frequency,
-            }))
-            .unwrap();
+                cpu_clock: self.region.cpu_clock(),
+            }));
 
         self.ch3_sender
             .send(TriangleEvent::LengthCounter(LengthCounter::new(
                 self.ch3_register.key_off_counter_flag,
                 LENGTH_COUNTER_TBL[self.ch3_register.key_off_count as usize],
-            )))
-            .unwrap();
+            )));
 
         if addr == 0x400B {
-            self.ch3_sender.send(TriangleEvent::Reset()).unwrap();
+            self.ch3_sender.send(TriangleEvent::Reset());
         }
     }
 
     pub fn write4ch(&mut self, addr: u16, value: u8) {
         self.ch4_register.write(addr, value);
 
-        let hz = CPU_CLOCK / NOISE_TBL[self.ch4_register.frequency as usize];
+        let noise_tbl: &[f32] = match self.region {
+            Region::PAL => &NOISE_TBL_PAL,
+            Region::NTSC | Region::DENDY => &NOISE_TBL,
+        };
+        let hz = self.region.cpu_clock() / noise_tbl[self.ch4_register.frequency as usize];
         let is_long = match self.ch4_register.kind {
             NoiseKind::Long => true,
             _ => false,
@@ -224,26 +279,23 @@ impl APU {
                 hz: hz,
                 is_long: is_long,
                 volume: volume,
-            }))
-            .unwrap();
+            }));
 
         self.ch4_sender
             .send(NoiseEvent::Envelope(Envelope::new(
                 self.ch4_register.volume,
                 self.ch4_register.envelope_flag,
                 !self.ch4_register.key_off_counter_flag,
-            )))
-            .unwrap();
+            )));
 
         self.ch4_sender
             .send(NoiseEvent::LengthCounter(LengthCounter::new(
                 self.ch4_register.key_off_counter_flag,
                 LENGTH_COUNTER_TBL[self.ch4_register.key_off_count as usize],
-            )))
-            .unwrap();
+            )));
 
         if addr == 0x400F {
-            self.ch4_sender.send(NoiseEvent::Reset()).unwrap();
+            self.ch4_sender.send(NoiseEvent::Reset());
         }
     }
 
@@ -253,32 +305,33 @@ impl APU {
         res
     }
 
+    // $4015を読んだ時に返る値 (フレームIRQフラグはクリアしない)
+    pub fn peek_status(&self) -> u8 {
+        self.status.bits()
+    }
+
     pub fn write_status(&mut self, data: u8) {
         self.status.update(data);
 
         self.ch1_sender
             .send(SquareEvent::Enable(
                 self.status.contains(StatusRegister::ENABLE_1CH),
-            ))
-            .unwrap();
+            ));
 
         self.ch2_sender
             .send(SquareEvent::Enable(
                 self.status.contains(StatusRegister::ENABLE_2CH),
-            ))
-            .unwrap();
+            ));
 
         self.ch3_sender
             .send(TriangleEvent::Enable(
                 self.status.contains(StatusRegister::ENABLE_3CH),
-            ))
-            .unwrap();
+            ));
 
         self.ch4_sender
             .send(NoiseEvent::Enable(
                 self.status.contains(StatusRegister::ENABLE_4CH),
-            ))
-            .unwrap();
+            ));
     }
 
     pub fn irq(&self) -> bool {
@@ -294,7 +347,7 @@ impl APU {
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
-        let interval = 7457;
+        let interval = self.region.apu_frame_interval();
         if self.cycles >= interval {
             self.cycles -= interval;
             self.counter += 1;
@@ -341,29 +394,25 @@ impl APU {
     }
 
     fn send_envelope_tick(&self) {
-        self.ch1_sender.send(SquareEvent::EnvelopeTick()).unwrap();
-        self.ch2_sender.send(SquareEvent::EnvelopeTick()).unwrap();
-        self.ch4_sender.send(NoiseEvent::EnvelopeTick()).unwrap();
+        self.ch1_sender.send(SquareEvent::EnvelopeTick());
+        self.ch2_sender.send(SquareEvent::EnvelopeTick());
+        self.ch4_sender.send(NoiseEvent::EnvelopeTick());
     }
 
     fn send_length_counter_tick(&self) {
         self.ch1_sender
-            .send(SquareEvent::LengthCounterTick())
-            .unwrap();
+            .send(SquareEvent::LengthCounterTick());
         self.ch2_sender
-            .send(SquareEvent::LengthCounterTick())
-            .unwrap();
+            .send(SquareEvent::LengthCounterTick());
         self.ch3_sender
-            .send(TriangleEvent::LengthCounterTick())
-            .unwrap();
+            .send(TriangleEvent::LengthCounterTick());
         self.ch4_sender
-            .send(NoiseEvent::LengthCounterTick())
-            .unwrap();
+            .send(NoiseEvent::LengthCounterTick());
     }
 
     fn send_sweep_tick(&self) {
-        self.ch1_sender.send(SquareEvent::SweepTick()).unwrap();
-        self.ch2_sender.send(SquareEvent::SweepTick()).unwrap();
+        self.ch1_sender.send(SquareEvent::SweepTick());
+        self.ch2_sender.send(SquareEvent::SweepTick());
     }
 }
 
@@ -677,6 +726,7 @@ struct Sweep {
     timer_count: u8,
     enabled: bool,
     counter: u8,
+    cpu_clock: f32,
 }
 
 impl Sweep {
@@ -686,6 +736,7 @@ impl Sweep {
         direction: u8,
         timer_count: u8,
         enabled: bool,
+        cpu_clock: f32,
     ) -> Self {
         Sweep {
             org_freq: frequency,
@@ -695,6 +746,7 @@ impl Sweep {
             timer_count,
             enabled,
             counter: 0,
+            cpu_clock,
         }
     }
 
@@ -732,7 +784,7 @@ impl Sweep {
         if self.frequency == 0 {
             return 0.0;
         }
-        CPU_CLOCK / (16.0 * (self.frequency as f32 + 1.0))
+        self.cpu_clock / (16.0 * (self.frequency as f32 + 1.0))
     }
 
     fn reset(&mut self) {
@@ -851,7 +903,7 @@ fn init_square(sdl_context: &sdl2::Sdl) -> (AudioDevice<SquareWave>, Sender<Squa
             note: SquareNote::new(),
             envelope: Envelope::new(0, false, false),
             length_counter: LengthCounter::new(false, 0),
-            sweep: Sweep::new(0, 0, 0, 0, false),
+            sweep: Sweep::new(0, 0, 0, 0, false, Region::NTSC.cpu_clock()),
         })
         .unwrap();
 
@@ -871,15 +923,19 @@ enum TriangleEvent {
 #[derive(Debug, Clone, PartialEq)]
 struct TriangleNote {
     frequency: u16,
+    cpu_clock: f32,
 }
 
 impl TriangleNote {
     fn new() -> Self {
-        TriangleNote { frequency: 0 }
+        TriangleNote {
+            frequency: 0,
+            cpu_clock: Region::NTSC.cpu_clock(),
+        }
     }
 
     fn hz(&self) -> f32 {
-        CPU_CLOCK / (32.0 * (self.frequency as f32 + 1.0))
+        self.cpu_clock / (32.0 * (self.frequency as f32 + 1.0))
     }
 }
 
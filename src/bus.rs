@@ -12,6 +12,13 @@ const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 const PRG_ROM: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xFFFF;
 
+// デバッガのウォッチポイント用に記録するアクセスの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     // prg_rom: Vec<u8>,
@@ -21,6 +28,13 @@ pub struct Bus<'call> {
     apu: APU,
 
     cycles: usize,
+    // PALではCPU 1サイクルが3.2ドットなので、端数をためておく
+    ppu_dot_remainder: usize,
+    // 実行中の命令のサイクル数と、そのうち既にPPU/APUを進めた分
+    instruction_cycles: u8,
+    ticked_cycles: u8,
+    // Some の間はCPUからのアクセスを (アドレス, 値, 種類) で記録する (デバッガ用)
+    pub access_log: Option<Vec<(u16, u8, Access)>>,
     gameloop_callback: Box<dyn FnMut(&PPU, &mut GamePad) + 'call>,
 }
 
@@ -29,7 +43,10 @@ impl<'a> Bus<'a> {
     where
         F: FnMut(&PPU, &mut GamePad) + 'call,
     {
-        let ppu = PPU::new(rom.chr_rom, rom.mirroring, rom.is_chr_ram);
+        let mut ppu = PPU::new(rom.mirroring);
+        ppu.set_region(rom.region);
+        let mut apu = apu;
+        apu.set_region(rom.region);
         Bus {
             cpu_vram: [0; 2048],
             // prg_rom: rom.prg_rom,
@@ -38,10 +55,27 @@ impl<'a> Bus<'a> {
             gamepad_2: GamePad::new(),
             apu: apu,
             cycles: 0,
+            ppu_dot_remainder: 0,
+            instruction_cycles: 0,
+            ticked_cycles: 0,
+            access_log: None,
             gameloop_callback: Box::from(gameloop_callback),
         }
     }
 
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    // 電源投入からのCPUサイクル数
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
     // fn read_prg_rom(&self, mut addr: u16) -> u8 {
     //     addr -= 0x8000;
     //     if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
@@ -50,44 +84,85 @@ impl<'a> Bus<'a> {
     //     }
     //     self.prg_rom[addr as usize]
     // }
+}
 
-    pub fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
+pub trait Mem {
+    fn mem_read(&mut self, addr: u16) -> u8;
+    fn mem_write(&mut self, addr: u16, data: u8);
+
+    // mem_read()と同じ値を副作用なしで返す (トレース、デバッガ、メモリビューア用)
+    fn peek(&self, addr: u16) -> u8;
+
+    // 副作用なしで書き込む (RAM/WRAMのみ、レジスタやROMは無視) (デバッガ用)
+    fn poke(&mut self, addr: u16, data: u8);
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+    // 命令の実行前にその命令のサイクル数 (ページ跨ぎ等の追加分を除く) を知らせる
+    // 命令の途中でのレジスタアクセスのタイミングを合わせるのに使う
+    fn begin_instruction(&mut self, _cycles: u8) {}
+
+    // CPUの命令実行に合わせてバス上のデバイス(PPU/APU等)を進める
+    // 単純なRAMだけのバス(テスト用等)では何もしない
+    fn tick(&mut self, _cycles: u8) {}
+
+    fn poll_nmi_status(&mut self) -> Option<i32> {
+        None
+    }
+
+    fn poll_apu_irq(&mut self) -> bool {
+        false
+    }
+}
+
+impl Bus<'_> {
+    // CPUのサイクル数だけPPU/APUを進める
+    fn advance(&mut self, cycles: u8) {
+        let (numerator, denominator) = self.ppu.region().ppu_dots_per_cpu_cycle();
+        let dots = cycles as usize * numerator + self.ppu_dot_remainder;
+        self.ppu_dot_remainder = dots % denominator;
+        self.ppu.tick((dots / denominator) as u8);
 
         self.apu.tick(cycles);
 
-        if !nmi_before && nmi_after {
+        if self.ppu.take_frame_ready() {
             (self.gameloop_callback)(&self.ppu, &mut self.gamepad_1);
         }
     }
 
-    pub fn poll_nmi_status(&mut self) -> Option<i32> {
-        if self.ppu.clear_nmi_interrupt {
-            self.ppu.clear_nmi_interrupt = false;
-            self.ppu.nmi_interrupt = None;
-            return None;
-        }
-        let res = self.ppu.nmi_interrupt;
-        self.ppu.nmi_interrupt = None;
-        res
+    // PPUのレジスタへのアクセスは命令の最後のサイクルで起きるものとして、そこまで進めておく
+    // ($2002を読むタイミングでVBlankフラグやNMIが変わるので)
+    // 命令毎のアクセスのタイミングは持っていないので、最後のサイクル以外でアクセスする命令
+    // (INC $2007 等のRMWの読み込み、ページを跨ぐ LDA $20FF,X の余分な読み込み等) ではずれる
+    fn catch_up(&mut self) {
+        self.advance_to(self.instruction_cycles.saturating_sub(1));
     }
 
-    pub fn poll_apu_irq(&mut self) -> bool {
-        self.apu.irq()
+    // 実行中の命令の target サイクル目まで進める
+    fn advance_to(&mut self, target: u8) {
+        if target > self.ticked_cycles {
+            let cycles = target - self.ticked_cycles;
+            self.ticked_cycles = target;
+            self.advance(cycles);
+        }
     }
-}
 
-pub trait Mem {
-    fn mem_read(&mut self, addr: u16) -> u8;
-    fn mem_write(&mut self, addr: u16, data: u8);
-}
+    // OAM DMAの間はCPUが止まるので、その分PPU/APUだけを進める
+    // $4014 に書き込んだサイクルの後で 513 サイクル、奇数サイクルからなら揃えるためにもう1サイクル
+    fn oam_dma(&mut self, page: u8) {
+        let mut values: [u8; 256] = [0; 256];
+        for i in 0x00..=0xFF {
+            values[i] = self.mem_read((page as u16) << 8 | i as u16);
+        }
+        self.ppu.write_to_oam_dma(values);
 
-impl Mem for Bus<'_> {
-    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.advance_to(self.instruction_cycles);
+        let dma_cycles = 513 + (self.cycles + self.ticked_cycles as usize) % 2;
+        self.cycles += dma_cycles;
+        for _ in 0..dma_cycles {
+            self.advance(1);
+        }
+    }
+
+    fn bus_read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
@@ -100,8 +175,9 @@ impl Mem for Bus<'_> {
                 );
                 v
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                warn!("Attempt to read from write-only PPU address {:X}", addr);
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.io_latch(),
+            0x4014 => {
+                warn!("Attempt to read from write-only address {:X}", addr);
                 0
             }
             0x2002 => self.ppu.read_status(),
@@ -110,7 +186,7 @@ impl Mem for Bus<'_> {
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 debug!("READ PPU MIRROR: {:04X} => {:04X}", addr, mirror_down_addr);
-                self.mem_read(mirror_down_addr)
+                self.bus_read(mirror_down_addr)
             }
             0x4015 => self.apu.read_status(),
             0x4016 => self.gamepad_1.read(),
@@ -133,7 +209,11 @@ impl Mem for Bus<'_> {
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    fn bus_write(&mut self, addr: u16, data: u8) {
+        // PPUのレジスタへの書き込みは全てI/Oラッチに残る (ミラーは下で$2000～$2007に戻る)
+        if (0x2000..=0x2007).contains(&addr) {
+            self.ppu.refresh_io_latch(data, 0xFF);
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b_0000_0111_1111_1111;
@@ -171,7 +251,7 @@ impl Mem for Bus<'_> {
             }
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirror_down_addr, data);
+                self.bus_write(mirror_down_addr, data);
             }
             0x4000..=0x4003 => self.apu.write1ch(addr, data),
             0x4004..=0x4007 => self.apu.write2ch(addr, data),
@@ -197,15 +277,7 @@ impl Mem for Bus<'_> {
                 // $XX を書き込むと、256 バイトのデータが
                 // CPU ページ $XX00 ～ $XXFF から内部 PPU OAM にアップロードされます
                 // このページは通常、内部 RAM (通常は $0200 ～ $02FF) にありますが、カートリッジ RAM または ROM も使用できます。
-                let mut values: [u8; 256] = [0; 256];
-                for i in 0x00..=0xFF {
-                    values[i] = self.mem_read((data as u16) << 8 | i as u16);
-                }
-                self.ppu.write_to_oam_dma(values);
-                // Not counting the OAMDMA write tick, the above procedure takes 513 CPU cycles (+1 on odd CPU cycles)
-                for _ in 0..513 {
-                    self.ppu.tick(1);
-                }
+                self.oam_dma(data);
             }
             0x6000..=0x7FFF => {
                 MAPPER.lock().unwrap().write(addr, data);
@@ -228,3 +300,114 @@ impl Mem for Bus<'_> {
         }
     }
 }
+
+impl Mem for Bus<'_> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        if (0x2000..=PPU_REGISTERS_MIRRORS_END).contains(&addr) {
+            self.catch_up();
+        }
+        let data = self.bus_read(addr);
+        if let Some(log) = &mut self.access_log {
+            log.push((addr, data, Access::Read));
+        }
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(log) = &mut self.access_log {
+            log.push((addr, data, Access::Write));
+        }
+        if (0x2000..=PPU_REGISTERS_MIRRORS_END).contains(&addr) {
+            self.catch_up();
+        }
+        self.bus_write(addr, data)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b_0000_0111_1111_1111) as usize] = data,
+            0x6000..=0x7FFF => MAPPER.lock().unwrap().poke(addr, data),
+            _ => {}
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b_0000_0111_1111_1111) as usize],
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.io_latch(),
+            0x4014 => 0,
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.peek_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b00100000_00000111),
+            0x4015 => self.apu.peek_status(),
+            0x4016 => self.gamepad_1.peek(),
+            0x6000..=PRG_ROM_END => MAPPER.lock().unwrap().peek(addr),
+            _ => 0,
+        }
+    }
+
+    fn begin_instruction(&mut self, cycles: u8) {
+        self.instruction_cycles = cycles;
+        self.ticked_cycles = 0;
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+
+        // catch_up() で進めた分を除く
+        let remaining = cycles.saturating_sub(self.ticked_cycles);
+        self.instruction_cycles = 0;
+        self.ticked_cycles = 0;
+        self.advance(remaining);
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<i32> {
+        if self.ppu.clear_nmi_interrupt {
+            self.ppu.clear_nmi_interrupt = false;
+            self.ppu.nmi_interrupt = None;
+            return None;
+        }
+        let res = self.ppu.nmi_interrupt;
+        self.ppu.nmi_interrupt = None;
+        res
+    }
+
+    fn poll_apu_irq(&mut self) -> bool {
+        self.apu.irq()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::Region;
+    use crate::rom::Rom;
+
+    // STA $4014 (4サイクル) を cycles サイクル目から実行して、増えたCPUサイクルとPPUのドット数
+    fn run_oam_dma(region: Region, cycles: usize) -> (usize, usize) {
+        let mut rom = Rom::mem_blank();
+        rom.region = region;
+        let mut bus = Bus::new(rom, APU::new_headless(), |_, _| {});
+        bus.begin_instruction(cycles as u8);
+        bus.tick(cycles as u8);
+        let dots = |bus: &Bus| bus.ppu.scanline() * 341 + bus.ppu.dot();
+        let (start_cycles, start_dots) = (bus.cycles(), dots(&bus));
+
+        bus.mem_write(0x0200, 0x12);
+        bus.begin_instruction(4);
+        bus.mem_write(0x4014, 0x02);
+        bus.tick(4);
+        assert_eq!(bus.ppu.peek_oam_data(), 0x12);
+        (bus.cycles() - start_cycles, dots(&bus) - start_dots)
+    }
+
+    #[test]
+    fn test_oam_dma_cycles() {
+        // 書き込みの後が偶数サイクルなら 513、奇数なら 514 サイクル止まる
+        assert_eq!(run_oam_dma(Region::NTSC, 0), (4 + 513, (4 + 513) * 3));
+        assert_eq!(run_oam_dma(Region::NTSC, 1), (4 + 514, (4 + 514) * 3));
+        // PALは1サイクル3.2ドット
+        assert_eq!(run_oam_dma(Region::PAL, 0), (4 + 513, (4 + 513) * 16 / 5));
+    }
+}
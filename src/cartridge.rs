@@ -1,3 +1,4 @@
+use crate::region::Region;
 use crate::rom::Rom;
 use std::fs::File;
 use std::io::Read;
@@ -7,6 +8,13 @@ pub fn load_rom(path: &str) -> Rom {
     let metadata = std::fs::metadata(path).expect("unable to read metadata");
     let mut buffer = vec![0; metadata.len() as usize];
     f.read(&mut buffer).expect("buffer overflow");
-    let rom = Rom::new(&buffer).expect("load error");
+    let mut rom = Rom::new(&buffer).expect("load error");
+    // ヘッダに地域が書かれていなければ、データベースかファイル名で判断する
+    if Region::from_header(&buffer).is_none() {
+        let region = Region::from_database(&buffer).expect("can't read region database");
+        if let Some(region) = region.or_else(|| Region::from_file_name(path)) {
+            rom.region = region;
+        }
+    }
     rom
 }
\ No newline at end of file
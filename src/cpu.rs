@@ -1,6 +1,7 @@
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 use crate::opcode::{call, CPU_OPS_CODES};
 use crate::bus::{Bus, Mem};
+use crate::disasm::{binary, disasm};
 
 const FLAG_CARRY: u8 = 1 << 0;
 const FLAG_ZERO: u8 = 1 << 1;
@@ -13,6 +14,25 @@ const FLAG_NEGATIVE: u8 = 1 << 7;
 
 const SIGN_BIT: u8 = 1 << 7;
 
+// ANE($8B)/LXA($AB)の不安定な"マジック定数"
+// 実機ではチップや温度で $00/$EE/$FF 等にばらつく
+// main.rs と rscom-test では RSCOM_MAGIC_CONSTANT=FF 等 (16進) で変えられる
+pub const DEFAULT_MAGIC_CONSTANT: u8 = 0xEE;
+
+// RSCOM_MAGIC_CONSTANT の値 (指定が無ければ DEFAULT_MAGIC_CONSTANT)
+pub fn magic_constant_from_env() -> Result<u8, String> {
+    match std::env::var("RSCOM_MAGIC_CONSTANT") {
+        Ok(s) => parse_magic_constant(&s),
+        Err(_) => Ok(DEFAULT_MAGIC_CONSTANT),
+    }
+}
+
+// "EE", "$EE", "0xEE" のどれでもよい
+pub fn parse_magic_constant(s: &str) -> Result<u8, String> {
+    let hex = s.trim().trim_start_matches('$').trim_start_matches("0x");
+    u8::from_str_radix(hex, 16).map_err(|_| format!("invalid magic constant '{}'", s.trim()))
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
@@ -32,6 +52,12 @@ pub enum AddressingMode {
     NoneAddressing,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    NMI,
+    IRQ,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum CycleCalcMode {
@@ -70,7 +96,7 @@ impl OpCode {
     }
 }
 
-pub struct CPU<'a> {
+pub struct CPU<M: Mem> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
@@ -78,14 +104,19 @@ pub struct CPU<'a> {
     pub program_counter: u16,
     pub stack_pointer: u8,
     // pub memory: [u8; 0x10000], // 0xFFFF
-    pub bus: Bus<'a>,
+    pub bus: M,
+    pub magic_constant: u8, // ANE/LXA用
+    // 直前のservice_interrupts()で割り込みに入った (デバッガ用)
+    pub last_interrupt: Option<Interrupt>,
 
     add_cycles: u8,
+    jammed: bool,
 }
 
-pub static mut IN_TRACE: bool = false;
+// trace()の出力先 (ログのフォーマットで通常のログと区別する)
+pub const TRACE_LOG_TARGET: &str = "rscom::trace";
 
-impl Mem for CPU<'_> {
+impl<M: Mem> Mem for CPU<M> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
@@ -93,10 +124,18 @@ impl Mem for CPU<'_> {
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.bus.mem_write(addr, data)
     }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.bus.poke(addr, data)
+    }
 }
 
-impl<'a> CPU<'a> {
-    pub fn new(bus: Bus<'a>) -> CPU<'a> {
+impl<M: Mem> CPU<M> {
+    pub fn new(bus: M) -> CPU<M> {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -106,7 +145,10 @@ impl<'a> CPU<'a> {
             stack_pointer: 0xFD, // FIXME あってる？
             // memory: [0x00; 0x10000],
             bus: bus,
+            magic_constant: DEFAULT_MAGIC_CONSTANT,
+            last_interrupt: None,
             add_cycles: 0,
+            jammed: false,
         }
     }
 
@@ -215,6 +257,18 @@ impl<'a> CPU<'a> {
         (hi << 8) | (lo as u16)
     }
 
+    pub fn peek_u16(&self, pos: u16) -> u16 {
+        // mem_read_u16()と同じページ境界の扱い
+        if pos == 0x00FF || pos == 0x02FF {
+            let lo = self.peek(pos) as u16;
+            let hi = self.peek(pos & 0xFF00) as u16;
+            return (hi << 8) | lo;
+        }
+        let lo = self.peek(pos) as u16;
+        let hi = self.peek(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
     pub fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0x00FF) as u8;
@@ -235,8 +289,28 @@ impl<'a> CPU<'a> {
         // FIXME あってる？
         self.status = FLAG_INTERRRUPT | FLAG_BREAK2;
         self.stack_pointer = 0xFD;
+        self.jammed = false;
+
+        self.program_counter = self.mem_read_u16(0xFFFC);
+        // リセットシーケンスは7サイクル掛かる (nestest.logもCYC:7から始まる)
+        // バスを通してPPU/APUも7サイクル分 (NTSCのPPUで21ドット) 進むので、
+        // 最初のVBlankやAPUのフレームIRQまでの時間もその分短くなる (実機と同じ)
+        self.bus.tick(7);
+    }
+
+    // リセットボタン (電源投入と違いA/X/Yは保持、SPは3減ってI=1になる)
+    pub fn soft_reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status |= FLAG_INTERRRUPT;
+        self.jammed = false;
 
         self.program_counter = self.mem_read_u16(0xFFFC);
+        self.bus.tick(7);
+    }
+
+    // JAM命令でCPUが停止しているか (リセットでのみ復帰)
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
     }
 
     pub fn load(&mut self) {
@@ -249,51 +323,81 @@ impl<'a> CPU<'a> {
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi();
+            self.step_with_callback(&mut callback);
+            if self.jammed {
+                return;
             }
+        }
+    }
 
-            if self.bus.poll_apu_irq() {
-                self.apu_irq();
-            }
+    pub fn step(&mut self) -> u8 {
+        self.step_with_callback(&mut |_| {})
+    }
+
+    // 割り込みを処理した後、1命令だけ実行する
+    // 戻り値は実行した命令のCPUサイクル数
+    pub fn step_with_callback<F>(&mut self, callback: &mut F) -> u8
+    where
+        F: FnMut(&mut CPU<M>),
+    {
+        self.service_interrupts();
+        self.execute(callback)
+    }
 
-            let opscode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
+    // 保留中の割り込みがあればハンドラに入る (PCはハンドラの先頭になる)
+    // step()の最初に呼ばれる。デバッガは命令の前に呼んで、次に実行する命令を確定させる
+    pub fn service_interrupts(&mut self) {
+        self.last_interrupt = None;
 
-            let op = self.find_ops(opscode);
-            match op {
-                Some(op) => {
-                    self.add_cycles = 0;
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt_nmi();
+            self.last_interrupt = Some(Interrupt::NMI);
+        }
 
-                    callback(self);
-                    call(self, &op);
+        if self.bus.poll_apu_irq() && self.apu_irq() {
+            self.last_interrupt = Some(Interrupt::IRQ);
+        }
+    }
 
-                    match op.cycle_calc_mode {
-                        CycleCalcMode::None => {
-                            self.add_cycles = 0;
-                        }
-                        CycleCalcMode::Page => {
-                            if self.add_cycles > 1 {
-                                panic!(
-                                    "Unexpected cycle_calc. {} {:?} => {}",
-                                    op.name, op.addressing_mode, self.add_cycles
-                                )
-                            }
-                        }
-                        _ => {}
-                    }
+    fn execute<F>(&mut self, callback: &mut F) -> u8
+    where
+        F: FnMut(&mut CPU<M>),
+    {
+        let opscode = self.mem_read(self.program_counter);
+        self.program_counter += 1;
 
-                    self.bus.tick(op.cycles + self.add_cycles);
+        let op = self.find_ops(opscode);
+        match op {
+            Some(op) => {
+                self.add_cycles = 0;
 
-                    // if program_conter_state == self.program_counter {
-                    //   self.program_counter += (op.len - 1) as u16
-                    // }
+                callback(self);
+                self.bus.begin_instruction(op.cycles);
+                call(self, &op);
+
+                match op.cycle_calc_mode {
+                    CycleCalcMode::None => {
+                        self.add_cycles = 0;
+                    }
+                    CycleCalcMode::Page => {
+                        if self.add_cycles > 1 {
+                            panic!(
+                                "Unexpected cycle_calc. {} {:?} => {}",
+                                op.name, op.addressing_mode, self.add_cycles
+                            )
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {} // panic!("no implementation {:<02X}", opscode),
+
+                let cycles = op.cycles + self.add_cycles;
+                self.bus.tick(cycles);
+                cycles
             }
+            _ => 0, // panic!("no implementation {:<02X}", opscode),
         }
     }
 
@@ -310,11 +414,12 @@ impl<'a> CPU<'a> {
         self.program_counter = self.mem_read_u16(0xFFFA);
     }
 
-    fn apu_irq(&mut self) {
+    // 割り込みに入ったらtrue (Iフラグが立っていれば無視)
+    fn apu_irq(&mut self) -> bool {
         info!("** APU_IRQ **");
 
         if self.status & FLAG_INTERRRUPT != 0 {
-            return;
+            return false;
         }
         info!("  => CALL");
 
@@ -323,9 +428,10 @@ impl<'a> CPU<'a> {
         self.program_counter = self.mem_read_u16(0xFFFE);
         self.status |= FLAG_BREAK;
         self.bus.tick(2);
+        true
     }
 
-    fn find_ops(&mut self, opscode: u8) -> Option<OpCode> {
+    fn find_ops(&self, opscode: u8) -> Option<OpCode> {
         for op in CPU_OPS_CODES.iter() {
             if op.code == opscode {
                 return Some(op.clone());
@@ -335,20 +441,58 @@ impl<'a> CPU<'a> {
     }
 
     pub fn anc(&mut self, _mode: &AddressingMode) {
-        // todo!("anc")
+        // A&#{imm} into A, N => C
+        self.and(_mode);
+        self.status = if self.status & FLAG_NEGATIVE != 0 {
+            self.status | FLAG_CARRY
+        } else {
+            self.status & !FLAG_CARRY
+        };
     }
+
     pub fn arr(&mut self, _mode: &AddressingMode) {
-        // todo!("arr")
+        // A&#{imm} の後 ROR A
+        // C = bit6, V = bit6 ^ bit5
+        let addr = self.get_operand_address(_mode);
+        let value = self.mem_read(addr);
+        let value = self.register_a & value;
+        self.register_a = (value >> 1) | ((self.status & FLAG_CARRY) << 7);
+        self.update_zero_and_negative_flags(self.register_a);
+
+        let bit6 = (self.register_a >> 6) & 1;
+        let bit5 = (self.register_a >> 5) & 1;
+        self.status = if bit6 == 1 {
+            self.status | FLAG_CARRY
+        } else {
+            self.status & !FLAG_CARRY
+        };
+        self.status = if bit6 ^ bit5 == 1 {
+            self.status | FLAG_OVERFLOW
+        } else {
+            self.status & !FLAG_OVERFLOW
+        };
     }
+
     pub fn asr(&mut self, _mode: &AddressingMode) {
-        // todo!("asr")
+        // = ALR, A&#{imm} の後 LSR A
+        self.and(_mode);
+        self.lsr(&AddressingMode::Accumulator);
     }
+
     pub fn lxa(&mut self, _mode: &AddressingMode) {
-        // todo!("lxa")
+        // (A | CONST) & #{imm} into A, X
+        let addr = self.get_operand_address(_mode);
+        let value = self.mem_read(addr);
+        self.register_a = (self.register_a | self.magic_constant) & value;
+        self.register_x = self.register_a;
+        self.update_zero_and_negative_flags(self.register_a);
     }
+
     pub fn sha(&mut self, _mode: &AddressingMode) {
-        // todo!("sha")
+        // = AHX, A&X&(H+1) into {adr}
+        self._sh_store(_mode, self.register_a & self.register_x);
     }
+
     pub fn sbx(&mut self, _mode: &AddressingMode) {
         //  A&X minus #{imm} into X
         // AND X register with accumulator and store result in X regis-ter, then
@@ -356,74 +500,86 @@ impl<'a> CPU<'a> {
         // Status flags: N,Z,C
 
         // AND X をアキュムレータに登録し、結果を X レジスタに格納します。 X レジスタからバイトを減算します (ボローなし)。 ステータスフラグ：N、Z、C
+        // キャリーはCMPと同じ判定 (A&X >= imm)
         let addr = self.get_operand_address(_mode);
         let value = self.mem_read(addr);
-        let (v, overflow) = (self.register_a & self.register_x).overflowing_sub(value);
+        let (v, borrow) = (self.register_a & self.register_x).overflowing_sub(value);
         self.register_x = v;
         self.update_zero_and_negative_flags(self.register_x);
-        self.status = if overflow {
-            self.status & FLAG_OVERFLOW
+        self.status = if borrow {
+            self.status & !FLAG_CARRY
         } else {
-            self.status | FLAG_OVERFLOW
+            self.status | FLAG_CARRY
         };
-        // todo!("sbx")
     }
 
     pub fn jam(&mut self, _mode: &AddressingMode) {
         // Stop program counter (processor lock up).
+        // PCはJAM命令を指したまま停止させる。リセットでのみ復帰。
         self.program_counter -= 1;
-        // panic!("CALL JAM operation.");
+        self.jammed = true;
+        error!("CPU JAM: ${:04X}", self.program_counter);
     }
 
     pub fn lae(&mut self, _mode: &AddressingMode) {
-        // stores {adr}&S into A, X and S
+        // = LAS, stores {adr}&S into A, X and S
 
         // AND memory with stack pointer, transfer result to accu-mulator, X
         // register and stack pointer.
         // Status flags: N,Z
         let addr = self.get_operand_address(_mode);
-        let value = self.mem_read(addr);
-        let s = self._pop();
-        self.register_a = value & s;
-        self.register_x = self.register_a;
-        self._push(self.register_a);
+        let value = self.mem_read(addr) & self.stack_pointer;
+        self.register_a = value;
+        self.register_x = value;
+        self.stack_pointer = value;
         self.update_zero_and_negative_flags(self.register_a);
-        // todo!("lae")
     }
 
     pub fn shx(&mut self, _mode: &AddressingMode) {
-        // M =3D X AND HIGH(arg) + 1
-        let addr = self.get_operand_address(_mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, (self.register_x & h).wrapping_add(1));
-        // todo!("shx")
+        // X&(H+1) into {adr}
+        self._sh_store(_mode, self.register_x);
     }
 
     pub fn shy(&mut self, _mode: &AddressingMode) {
-        // Y&H into {adr}
+        // Y&(H+1) into {adr}
         // AND Y register with the high byte of the target address of the argument
         // + 1. Store the result in memory.
-        let addr = self.get_operand_address(_mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, (self.register_y & h).wrapping_add(1));
-        // todo!("shy")
+        self._sh_store(_mode, self.register_y);
     }
 
     pub fn ane(&mut self, _mode: &AddressingMode) {
-        // TXA + AND #{imm}
-        self.txa(_mode);
-        self.and(_mode);
-        // todo!("ane")
+        // = XAA, (A | CONST) & X & #{imm} into A
+        let addr = self.get_operand_address(_mode);
+        let value = self.mem_read(addr);
+        self.register_a = (self.register_a | self.magic_constant) & self.register_x & value;
+        self.update_zero_and_negative_flags(self.register_a);
     }
 
     pub fn shs(&mut self, _mode: &AddressingMode) {
-        // stores A&X into S and A&X&H into {adr}
+        // = TAS, stores A&X into S and A&X&(H+1) into {adr}
         // アキュムレータと X レジスタを AND 演算し、結果をスタック ポインタに格納します。次に、スタック ポインタと引数 1 のターゲット アドレスの上位バイトを AND 演算します。結果をメモリに格納します。
-        self._push(self.register_a & self.register_x);
+        self.stack_pointer = self.register_a & self.register_x;
+        self._sh_store(_mode, self.stack_pointer);
+    }
+
+    fn _sh_store(&mut self, _mode: &AddressingMode, value: u8) {
+        // SHA/SHX/SHY/SHS共通
+        // 書き込む値 = value & (ベースアドレスの上位バイト + 1)
+        // ページを跨いだ場合、アドレスの上位バイトも書き込む値に化ける
         let addr = self.get_operand_address(_mode);
-        let h = ((addr & 0xFF00) >> 8) as u8;
-        self.mem_write(addr, self.register_a & self.register_x & h);
-        // todo!("shs")
+        let index = match _mode {
+            AddressingMode::Absolute_X => self.register_x,
+            _ => self.register_y,
+        };
+        let base = addr.wrapping_sub(index as u16);
+        let h = ((base >> 8) as u8).wrapping_add(1);
+        let value = value & h;
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(addr, value);
     }
 
     pub fn rra(&mut self, _mode: &AddressingMode) {
@@ -965,22 +1121,27 @@ impl<'a> CPU<'a> {
     }
 }
 
-pub fn trace(cpu: &mut CPU) -> String {
+// step_with_callback()のコールバック内 (オペコードをフェッチした後) で呼ぶ
+pub fn trace<M: Mem>(cpu: &CPU<M>) -> String {
+    let log = trace_at(cpu, cpu.program_counter - 1);
+    trace!(target: TRACE_LOG_TARGET, "{}", log);
+    log
+}
+
+// program_counterの命令を現在のレジスタでトレース形式にする (ログは出さない)
+pub fn trace_at<M: Mem>(cpu: &CPU<M>, program_counter: u16) -> String {
     // 0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD
     // OK 0064 => program_counter
     // OK A2 01 => binary code
     // OK LDX #$01 => asm code
     // "0400 @ 0400 = AA" => memory access
     // OK A:01 X:02 Y:03 P:24 SP:FD => register, status, stack_pointer
-    unsafe { IN_TRACE = true };
-
-    let program_counter = cpu.program_counter - 1;
     let pc = format!("{:<04X}", program_counter);
-    let op = cpu.mem_read(program_counter);
+    let op = cpu.peek(program_counter);
     let ops = cpu.find_ops(op).unwrap();
     let mut args: Vec<u8> = vec![];
     for n in 1..ops.bytes {
-        let arg = cpu.mem_read(program_counter + n);
+        let arg = cpu.peek(program_counter.wrapping_add(n));
         args.push(arg);
     }
     let bin = binary(op, &args);
@@ -988,117 +1149,36 @@ pub fn trace(cpu: &mut CPU) -> String {
     let memacc = memory_access(cpu, &ops, &args);
     let status = cpu2str(cpu);
 
-    let log = format!(
+    format!(
         "{:<6}{:<9}{:<33}{}",
         pc,
         bin,
         vec![asm, memacc].join(" "),
         status
-    );
-
-    trace!("{}", log);
-
-    unsafe { IN_TRACE = false };
-
-    log
-}
-
-fn binary(op: u8, args: &Vec<u8>) -> String {
-    let mut list: Vec<String> = vec![];
-    list.push(format!("{:<02X}", op));
-    for v in args {
-        list.push(format!("{:<02X}", v));
-    }
-    list.join(" ")
+    )
 }
 
-fn disasm(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
-    let prefix = if ops.name.starts_with("*") { "" } else { " " };
+// nestest.log形式 (trace() + PPU:scanline,dot + CPUサイクル)
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub fn trace_with_timing(cpu: &CPU<Bus>) -> String {
+    let log = trace(cpu);
+    let ppu = cpu.bus.ppu();
     format!(
-        "{}{} {}",
-        prefix,
-        ops.name,
-        address(program_counter, &ops, args)
+        "{} PPU:{:>3},{:>3} CYC:{}",
+        log,
+        ppu.scanline(),
+        ppu.dot(),
+        cpu.bus.cycles()
     )
 }
 
-fn address(program_counter: u16, ops: &OpCode, args: &Vec<u8>) -> String {
-    match ops.addressing_mode {
-        AddressingMode::Implied => {
-            format!("")
-        }
-        AddressingMode::Accumulator => {
-            format!("A")
-        }
-        // LDA #$44 => a9 44
-        AddressingMode::Immediate => {
-            format!("#${:<02X}", args[0])
-        }
-
-        // LDA $44 => a5 44
-        AddressingMode::ZeroPage => {
-            format!("${:<02X}", args[0])
-        }
-
-        // LDA $4400 => ad 00 44
-        AddressingMode::Absolute => {
-            format!("${:<02X}{:<02X}", args[1], args[0])
-        }
-        // LDA $44,X => b5 44
-        AddressingMode::ZeroPage_X => {
-            format!("${:<02X},X", args[0])
-        }
-
-        // LDX $44,Y => b6 44
-        AddressingMode::ZeroPage_Y => {
-            format!("${:<02X},Y", args[0])
-        }
-
-        // LDA $4400,X => bd 00 44
-        AddressingMode::Absolute_X => {
-            format!("${:<02X}{:<02X},X", args[1], args[0])
-        }
-
-        // LDA $4400,Y => b9 00 44
-        AddressingMode::Absolute_Y => {
-            format!("${:<02X}{:<02X},Y", args[1], args[0])
-        }
-        // JMP
-        AddressingMode::Indirect => {
-            format!("(${:<02X}{:<02X})", args[1], args[0])
-        }
-
-        // LDA ($44,X) => a1 44
-        AddressingMode::Indirect_X => {
-            format!("(${:<02X},X)", args[0])
-        }
-
-        // LDA ($44),Y => b1 44
-        AddressingMode::Indirect_Y => {
-            format!("(${:<02X}),Y", args[0])
-        }
-
-        // BCC *+4 => 90 04
-        AddressingMode::Relative => {
-            format!(
-                "${:<04X}",
-                (program_counter as i32 + (args[0] as i8) as i32) as u16 + 2
-            )
-        }
-
-        AddressingMode::NoneAddressing => {
-            panic!("_mode {:?} is not supported", ops.addressing_mode);
-        }
-    }
-}
-
-fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
+fn memory_access<M: Mem>(cpu: &CPU<M>, ops: &OpCode, args: &Vec<u8>) -> String {
     if ops.name.starts_with("J") {
         if ops.addressing_mode == AddressingMode::Indirect {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read_u16(addr);
+            let value = cpu.peek_u16(addr);
             return format!("= {:<04X}", value);
         }
         return format!("");
@@ -1106,24 +1186,24 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
 
     match ops.addressing_mode {
         AddressingMode::ZeroPage => {
-            let value = cpu.mem_read(args[0] as u16);
+            let value = cpu.peek(args[0] as u16);
             format!("= {:<02X}", value)
         }
         AddressingMode::ZeroPage_X => {
             let addr = args[0].wrapping_add(cpu.register_x) as u16;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
         AddressingMode::ZeroPage_Y => {
             let addr = args[0].wrapping_add(cpu.register_y) as u16;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<02X}", addr, value)
         }
         AddressingMode::Absolute => {
             let hi = args[1] as u16;
             let lo = args[0] as u16;
             let addr = hi << 8 | lo;
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("= {:<02X}", value)
         }
         AddressingMode::Absolute_X => {
@@ -1131,7 +1211,7 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_x as u16);
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<04X} = {:<02X}", addr, value)
         }
         AddressingMode::Absolute_Y => {
@@ -1139,21 +1219,21 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
             let lo = args[0] as u16;
             let base = hi << 8 | lo;
             let addr = base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(addr);
+            let value = cpu.peek(addr);
             format!("@ {:<04X} = {:<02X}", addr, value)
         }
         AddressingMode::Indirect_X => {
             let base = args[0];
             let ptr: u8 = (base as u8).wrapping_add(cpu.register_x);
-            let addr = cpu.mem_read_u16(ptr as u16);
-            let value = cpu.mem_read(addr);
+            let addr = cpu.peek_u16(ptr as u16);
+            let value = cpu.peek(addr);
             format!("@ {:<02X} = {:<04X} = {:<02X}", ptr, addr, value)
         }
         AddressingMode::Indirect_Y => {
             let base = args[0];
-            let deref_base = cpu.mem_read_u16(base as u16);
+            let deref_base = cpu.peek_u16(base as u16);
             let deref = deref_base.wrapping_add(cpu.register_y as u16);
-            let value = cpu.mem_read(deref);
+            let value = cpu.peek(deref);
             format!("= {:<04X} @ {:<04X} = {:<02X}", deref_base, deref, value)
         }
         _ => {
@@ -1162,9 +1242,284 @@ fn memory_access(cpu: &mut CPU, ops: &OpCode, args: &Vec<u8>) -> String {
     }
 }
 
-fn cpu2str(cpu: &CPU) -> String {
+fn cpu2str<M: Mem>(cpu: &CPU<M>) -> String {
     format!(
         "A:{:<02X} X:{:<02X} Y:{:<02X} P:{:<02X} SP:{:<02X}",
         cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
     )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestBus {
+        ram: Vec<u8>,
+        cycles: usize,
+        nmi: Option<i32>,
+        irq: bool,
+    }
+
+    impl Mem for TestBus {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.ram[addr as usize] = data;
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
+
+        fn poke(&mut self, addr: u16, data: u8) {
+            self.ram[addr as usize] = data;
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as usize;
+        }
+
+        fn poll_nmi_status(&mut self) -> Option<i32> {
+            self.nmi.take()
+        }
+
+        fn poll_apu_irq(&mut self) -> bool {
+            self.irq
+        }
+    }
+
+    // $0600 に置いた1命令を実行する
+    fn run(program: &[u8], setup: impl FnOnce(&mut CPU<TestBus>)) -> CPU<TestBus> {
+        let mut cpu = CPU::new(TestBus {
+            ram: vec![0; 0x10000],
+            cycles: 0,
+            nmi: None,
+            irq: false,
+        });
+        cpu.bus.ram[0x0600..0x0600 + program.len()].copy_from_slice(program);
+        cpu.program_counter = 0x0600;
+        cpu.status = FLAG_BREAK2;
+        setup(&mut cpu);
+        cpu.step();
+        cpu
+    }
+
+    fn flags(cpu: &CPU<TestBus>) -> u8 {
+        cpu.status & (FLAG_NEGATIVE | FLAG_OVERFLOW | FLAG_ZERO | FLAG_CARRY)
+    }
+
+    #[test]
+    fn test_arr_carry_and_overflow() {
+        // C = bit6, V = bit6 ^ bit5 (Cは回転で最上位に入る)
+        let cpu = run(&[0x6B, 0xFF], |cpu| {
+            cpu.register_a = 0xFF;
+            cpu.status |= FLAG_CARRY;
+        });
+        assert_eq!(cpu.register_a, 0xFF);
+        assert_eq!(flags(&cpu), FLAG_NEGATIVE | FLAG_CARRY);
+
+        let cpu = run(&[0x6B, 0x40], |cpu| cpu.register_a = 0xFF);
+        assert_eq!(cpu.register_a, 0x20);
+        assert_eq!(flags(&cpu), FLAG_OVERFLOW);
+
+        let cpu = run(&[0x6B, 0x80], |cpu| cpu.register_a = 0xFF);
+        assert_eq!(cpu.register_a, 0x40);
+        assert_eq!(flags(&cpu), FLAG_OVERFLOW | FLAG_CARRY);
+
+        let cpu = run(&[0x6B, 0x01], |cpu| cpu.register_a = 0xFF);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(flags(&cpu), FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_asr() {
+        let cpu = run(&[0x4B, 0x03], |cpu| cpu.register_a = 0xFF);
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(flags(&cpu), FLAG_CARRY);
+
+        let cpu = run(&[0x4B, 0xFE], |cpu| cpu.register_a = 0x01);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(flags(&cpu), FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_lxa_and_ane_magic_constant() {
+        let cpu = run(&[0xAB, 0xFF], |cpu| cpu.register_a = 0x00);
+        assert_eq!(cpu.register_a, DEFAULT_MAGIC_CONSTANT);
+        assert_eq!(cpu.register_x, DEFAULT_MAGIC_CONSTANT);
+        assert_eq!(flags(&cpu), FLAG_NEGATIVE);
+
+        let cpu = run(&[0xAB, 0x0F], |cpu| {
+            cpu.magic_constant = 0xFF;
+            cpu.register_a = 0x00;
+        });
+        assert_eq!((cpu.register_a, cpu.register_x), (0x0F, 0x0F));
+
+        let cpu = run(&[0x8B, 0xFF], |cpu| {
+            cpu.register_a = 0x00;
+            cpu.register_x = 0x0F;
+        });
+        assert_eq!(cpu.register_a, DEFAULT_MAGIC_CONSTANT & 0x0F);
+        assert_eq!(cpu.register_x, 0x0F);
+
+        let cpu = run(&[0x8B, 0xF0], |cpu| {
+            cpu.register_a = 0xFF;
+            cpu.register_x = 0x0F;
+        });
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(flags(&cpu), FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_parse_magic_constant() {
+        assert_eq!(parse_magic_constant("FF"), Ok(0xFF));
+        assert_eq!(parse_magic_constant(" $00 "), Ok(0x00));
+        assert_eq!(parse_magic_constant("0xee"), Ok(0xEE));
+        assert!(parse_magic_constant("100").is_err());
+        assert!(parse_magic_constant("").is_err());
+    }
+
+    #[test]
+    fn test_sbx_carry() {
+        // (A & X) - imm、キャリーはCMPと同じ
+        let cpu = run(&[0xCB, 0x10], |cpu| {
+            cpu.register_a = 0xF0;
+            cpu.register_x = 0x3C;
+        });
+        assert_eq!(cpu.register_x, 0x20);
+        assert_eq!(cpu.register_a, 0xF0);
+        assert_eq!(flags(&cpu), FLAG_CARRY);
+
+        let cpu = run(&[0xCB, 0x30], |cpu| {
+            cpu.register_a = 0xF0;
+            cpu.register_x = 0x3C;
+        });
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(flags(&cpu), FLAG_ZERO | FLAG_CARRY);
+
+        // 入力のキャリーは使わない
+        let cpu = run(&[0xCB, 0x31], |cpu| {
+            cpu.register_a = 0xF0;
+            cpu.register_x = 0x3C;
+            cpu.status |= FLAG_CARRY;
+        });
+        assert_eq!(cpu.register_x, 0xFF);
+        assert_eq!(flags(&cpu), FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn test_tas_and_las() {
+        // TAS $1200,Y : S = A & X, [$1205] = S & ($12 + 1)
+        let cpu = run(&[0x9B, 0x00, 0x12], |cpu| {
+            cpu.register_a = 0xF3;
+            cpu.register_x = 0x3F;
+            cpu.register_y = 0x05;
+        });
+        assert_eq!(cpu.stack_pointer, 0x33);
+        assert_eq!(cpu.bus.ram[0x1205], 0x13);
+
+        // ページを跨ぐと上位バイトも書き込む値になる
+        let cpu = run(&[0x9B, 0xFF, 0x12], |cpu| {
+            cpu.register_a = 0xFF;
+            cpu.register_x = 0x11;
+            cpu.register_y = 0x01;
+        });
+        assert_eq!(cpu.stack_pointer, 0x11);
+        assert_eq!(cpu.bus.ram[0x1100], 0x11);
+
+        // LAS $1200,Y : A, X, S = [$1205] & S
+        let cpu = run(&[0xBB, 0x00, 0x12], |cpu| {
+            cpu.register_y = 0x05;
+            cpu.stack_pointer = 0x3F;
+            cpu.bus.ram[0x1205] = 0xF5;
+        });
+        assert_eq!(cpu.register_a, 0x35);
+        assert_eq!(cpu.register_x, 0x35);
+        assert_eq!(cpu.stack_pointer, 0x35);
+        assert_eq!(flags(&cpu), 0);
+    }
+
+    #[test]
+    fn test_jam_halts() {
+        let mut cpu = run(&[0x02, 0xEA], |_| {});
+        assert!(cpu.is_jammed());
+        assert_eq!(cpu.program_counter, 0x0600);
+
+        // 止まったままPCは進まない
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0600);
+        cpu.run();
+        assert!(cpu.is_jammed());
+
+        cpu.bus.ram[0xFFFC] = 0x00;
+        cpu.bus.ram[0xFFFD] = 0x07;
+        cpu.reset();
+        assert!(!cpu.is_jammed());
+        assert_eq!(cpu.program_counter, 0x0700);
+    }
+
+    #[test]
+    fn test_reset_takes_7_cycles() {
+        // リセットでバスが7サイクル進み、最初の命令はその後から数える
+        let mut cpu = run(&[], |_| {});
+        cpu.bus.cycles = 0;
+        cpu.bus.ram[0xFFFC] = 0x00;
+        cpu.bus.ram[0xFFFD] = 0x06;
+        cpu.bus.ram[0x0600] = 0xEA; // NOP
+        cpu.reset();
+        assert_eq!(cpu.bus.cycles, 7);
+        cpu.step();
+        assert_eq!(cpu.bus.cycles, 9);
+
+        cpu.soft_reset();
+        assert_eq!(cpu.bus.cycles, 16);
+        assert_eq!(cpu.program_counter, 0x0600);
+    }
+
+    // NMIハンドラ ($0700: INX) とIRQハンドラ ($0800: INY) を置く
+    fn with_handlers(cpu: &mut CPU<TestBus>) {
+        cpu.bus.ram[0xFFFA] = 0x00;
+        cpu.bus.ram[0xFFFB] = 0x07;
+        cpu.bus.ram[0xFFFE] = 0x00;
+        cpu.bus.ram[0xFFFF] = 0x08;
+        cpu.bus.ram[0x0700] = 0xE8;
+        cpu.bus.ram[0x0800] = 0xC8;
+    }
+
+    #[test]
+    fn test_interrupt_before_instruction() {
+        // 保留中のNMIは命令の前に入り、同じstep()でハンドラの最初の命令まで実行する
+        let cpu = run(&[0xEA], |cpu| {
+            with_handlers(cpu);
+            cpu.bus.nmi = Some(1);
+        });
+        assert_eq!(cpu.last_interrupt, Some(Interrupt::NMI));
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x0701);
+        // 戻り先は割り込まれた命令 (まだ実行していない)
+        assert_eq!(cpu.bus.ram[0x01FD], 0x06);
+        assert_eq!(cpu.bus.ram[0x01FC], 0x00);
+        assert_ne!(cpu.status & FLAG_INTERRRUPT, 0);
+
+        let cpu = run(&[0xEA], |cpu| {
+            with_handlers(cpu);
+            cpu.bus.irq = true;
+        });
+        assert_eq!(cpu.last_interrupt, Some(Interrupt::IRQ));
+        assert_eq!(cpu.register_y, 1);
+        assert_eq!(cpu.program_counter, 0x0801);
+    }
+
+    #[test]
+    fn test_irq_masked() {
+        let cpu = run(&[0xEA], |cpu| {
+            with_handlers(cpu);
+            cpu.bus.irq = true;
+            cpu.status |= FLAG_INTERRRUPT;
+        });
+        assert_eq!(cpu.last_interrupt, None);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.register_y, 0);
+    }
+}
@@ -0,0 +1,734 @@
+// 対話型デバッガ
+//
+// メインループで命令毎に hook() を呼ぶと、ブレーク条件に当たった所で止まって
+// 標準入力からコマンドを受け付ける (プロンプト中はエミュレーションも止まる)
+//
+// R/Wウォッチポイントは命令の実行後に判定するので、止まるのはアクセスした命令の次。
+// PPUのウォッチポイントは$2007経由のアクセスのみ (レンダリングのフェッチは対象外)
+use crate::bus::{Access, Bus, Mem};
+use crate::cpu::{trace_at, Interrupt, CPU};
+use std::cell::Cell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+const OP_JSR: u8 = 0x20;
+const OP_RTI: u8 = 0x40;
+const OP_RTS: u8 = 0x60;
+
+const HELP: &str = "\
+  c                      continue
+  s [N]                  step into (N instructions)
+  n                      step over (JSR)
+  f                      step out (until RTS/RTI)
+  sl N                   run to scanline N
+  b ADDR                 add breakpoint
+  bd ADDR                delete breakpoint
+  w r|w|x|rw.. [cpu|ppu] ADDR[-END]
+                         add watchpoint
+  wd N                   delete watchpoint #N
+  l                      list breakpoints / watchpoints
+  nmi on|off             break on NMI
+  irq on|off             break on IRQ
+  r                      show registers
+  r a|x|y|p|sp|pc VAL    set register
+  m ADDR [LEN]           dump CPU memory
+  pm ADDR [LEN]          dump PPU memory
+  q                      quit
+  (empty line repeats the last command)";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Watchpoint {
+    fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+
+    fn matches(&self, space: Space, addr: u16, access: Access) -> bool {
+        self.space == space
+            && self.contains(addr)
+            && match access {
+                Access::Read => self.read,
+                Access::Write => self.write,
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Continue,
+    Step(u32),
+    Next,
+    Finish,
+    ToScanline(usize),
+    Break(u16),
+    DeleteBreak(u16),
+    Watch(Watchpoint),
+    DeleteWatch(usize),
+    List,
+    BreakOnNmi(bool),
+    BreakOnIrq(bool),
+    Regs,
+    SetReg(String, u16),
+    Dump(Space, u16, u16),
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RunMode {
+    Run,
+    Pause,
+    StepInto(u32),
+    StepOver { ret: u16, sp: u8 },
+    StepOut { sp: u8 },
+    ToScanline(usize),
+    Quit,
+}
+
+fn parse_num(s: &str) -> Result<u16, String> {
+    let s = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(s, 16).map_err(|_| format!("invalid number: {}", s))
+}
+
+fn parse_dec(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("invalid number: {}", s))
+}
+
+fn parse_on_off(s: Option<&&str>) -> Result<bool, String> {
+    match s {
+        Some(&"on") => Ok(true),
+        Some(&"off") => Ok(false),
+        _ => Err("expected on|off".to_string()),
+    }
+}
+
+fn parse_watch(args: &[&str]) -> Result<Watchpoint, String> {
+    let kind = args.first().ok_or("missing r|w|x")?;
+    let (space, range) = match args.get(1) {
+        Some(&"cpu") => (Space::Cpu, args.get(2)),
+        Some(&"ppu") => (Space::Ppu, args.get(2)),
+        _ => (Space::Cpu, args.get(1)),
+    };
+    let range = range.ok_or("missing address")?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_num(start)?, parse_num(end)?),
+        None => (parse_num(range)?, parse_num(range)?),
+    };
+    if start > end {
+        return Err("invalid range".to_string());
+    }
+    let watch = Watchpoint {
+        space,
+        start,
+        end,
+        read: kind.contains('r'),
+        write: kind.contains('w'),
+        execute: kind.contains('x'),
+    };
+    if !(watch.read || watch.write || watch.execute) {
+        return Err("expected r|w|x".to_string());
+    }
+    if watch.execute && space == Space::Ppu {
+        return Err("execute watchpoint is CPU only".to_string());
+    }
+    Ok(watch)
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (cmd, args) = match words.split_first() {
+        Some((cmd, args)) => (*cmd, args),
+        None => return Err("empty command".to_string()),
+    };
+    match cmd {
+        "c" | "continue" => Ok(Command::Continue),
+        "s" | "step" => match args.first() {
+            Some(n) => Ok(Command::Step(parse_dec(n)?.max(1) as u32)),
+            None => Ok(Command::Step(1)),
+        },
+        "n" | "next" => Ok(Command::Next),
+        "f" | "finish" => Ok(Command::Finish),
+        "sl" => Ok(Command::ToScanline(parse_dec(args.first().ok_or("missing scanline")?)?)),
+        "b" | "break" => Ok(Command::Break(parse_num(args.first().ok_or("missing address")?)?)),
+        "bd" => Ok(Command::DeleteBreak(parse_num(args.first().ok_or("missing address")?)?)),
+        "w" | "watch" => Ok(Command::Watch(parse_watch(args)?)),
+        "wd" => Ok(Command::DeleteWatch(parse_dec(args.first().ok_or("missing number")?)?)),
+        "l" | "list" => Ok(Command::List),
+        "nmi" => Ok(Command::BreakOnNmi(parse_on_off(args.first())?)),
+        "irq" => Ok(Command::BreakOnIrq(parse_on_off(args.first())?)),
+        "r" | "reg" => match args {
+            [] => Ok(Command::Regs),
+            [reg, value] => match *reg {
+                "a" | "x" | "y" | "p" | "sp" | "pc" => {
+                    Ok(Command::SetReg(reg.to_string(), parse_num(value)?))
+                }
+                _ => Err(format!("unknown register: {}", reg)),
+            },
+            _ => Err("usage: r [REG VALUE]".to_string()),
+        },
+        "m" | "pm" => {
+            let space = if cmd == "m" { Space::Cpu } else { Space::Ppu };
+            let addr = parse_num(args.first().ok_or("missing address")?)?;
+            let len = match args.get(1) {
+                Some(len) => parse_num(len)?,
+                None => 0x40,
+            };
+            Ok(Command::Dump(space, addr, len))
+        }
+        "h" | "help" | "?" => Ok(Command::Help),
+        "q" | "quit" => Ok(Command::Quit),
+        _ => Err(format!("unknown command: {} (h for help)", cmd)),
+    }
+}
+
+pub type BreakHook = Box<dyn FnMut(&CPU<Bus>)>;
+
+pub struct Debugger {
+    pub breakpoints: Vec<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub break_on_nmi: bool,
+    pub break_on_irq: bool,
+    // SDLのホットキー等から立てると次の命令で止まる
+    pub break_request: Rc<Cell<bool>>,
+    // 止まった時、プロンプトを出す前に呼ぶ (トレースのリングバッファの書き出し等)
+    pub on_break: Option<BreakHook>,
+
+    // コマンドの入力 (テストでは差し替える)
+    input: Box<dyn BufRead>,
+    mode: RunMode,
+    last_op: u8,
+    last_scanline: usize,
+    last_command: Option<Command>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: vec![],
+            watchpoints: vec![],
+            break_on_nmi: false,
+            break_on_irq: false,
+            break_request: Rc::new(Cell::new(false)),
+            on_break: None,
+            input: Box::new(io::BufReader::new(io::stdin())),
+            mode: RunMode::Run,
+            last_op: 0,
+            last_scanline: 0,
+            last_command: None,
+        }
+    }
+
+    // 次のhook()で止める
+    pub fn pause(&mut self) {
+        self.mode = RunMode::Pause;
+    }
+
+    // q コマンドでエミュレータの終了を要求された (終了処理は呼び出し側で行う)
+    pub fn is_quit(&self) -> bool {
+        self.mode == RunMode::Quit
+    }
+
+    // 命令の実行前に毎回呼ぶ (cpu.program_counter が次に実行する命令)
+    pub fn hook(&mut self, cpu: &mut CPU<Bus>) {
+        if self.mode == RunMode::Run
+            && self.breakpoints.is_empty()
+            && self.watchpoints.is_empty()
+            && !self.break_on_nmi
+            && !self.break_on_irq
+            && !self.break_request.get()
+        {
+            return;
+        }
+        if self.is_quit() {
+            return;
+        }
+
+        // 保留中の割り込みは命令の前に入るので、先に処理してハンドラの先頭で止まれるようにする
+        cpu.service_interrupts();
+        if self.should_break(cpu) {
+            self.mode = RunMode::Pause;
+            if let Some(on_break) = &mut self.on_break {
+                on_break(cpu);
+            }
+            self.prompt(cpu);
+        }
+
+        self.last_op = cpu.peek(cpu.program_counter);
+        self.last_scanline = cpu.bus.ppu().scanline();
+    }
+
+    fn should_break(&mut self, cpu: &mut CPU<Bus>) -> bool {
+        let mut reasons: Vec<String> = vec![];
+        let pc = cpu.program_counter;
+
+        if self.break_request.replace(false) {
+            reasons.push("user break".to_string());
+        }
+
+        match cpu.last_interrupt {
+            Some(Interrupt::NMI) if self.break_on_nmi => reasons.push("NMI".to_string()),
+            Some(Interrupt::IRQ) if self.break_on_irq => reasons.push("IRQ".to_string()),
+            _ => {}
+        }
+
+        let cpu_log = cpu.bus.access_log.as_mut().map(std::mem::take).unwrap_or_default();
+        let ppu_log = cpu.bus.ppu_mut().access_log.as_mut().map(std::mem::take).unwrap_or_default();
+        let accesses = cpu_log
+            .iter()
+            .map(|a| (Space::Cpu, a))
+            .chain(ppu_log.iter().map(|a| (Space::Ppu, a)));
+        for (space, (addr, data, access)) in accesses {
+            for (i, watch) in self.watchpoints.iter().enumerate() {
+                if watch.matches(space, *addr, *access) {
+                    reasons.push(format!(
+                        "watchpoint #{}: {:?} {:?} ${:04X} = ${:02X}",
+                        i, space, access, addr, data
+                    ));
+                }
+            }
+        }
+
+        if self.breakpoints.contains(&pc) {
+            reasons.push(format!("breakpoint ${:04X}", pc));
+        }
+        for (i, watch) in self.watchpoints.iter().enumerate() {
+            if watch.execute && watch.space == Space::Cpu && watch.contains(pc) {
+                reasons.push(format!("watchpoint #{}: Cpu Execute ${:04X}", i, pc));
+            }
+        }
+
+        let stop = match self.mode {
+            RunMode::Run => false,
+            RunMode::Pause => true,
+            RunMode::StepInto(n) => {
+                if n > 1 {
+                    self.mode = RunMode::StepInto(n - 1);
+                }
+                n <= 1
+            }
+            RunMode::StepOver { ret, sp } => pc == ret && cpu.stack_pointer >= sp,
+            RunMode::StepOut { sp } => {
+                (self.last_op == OP_RTS || self.last_op == OP_RTI) && cpu.stack_pointer > sp
+            }
+            RunMode::ToScanline(line) => {
+                let scanline = cpu.bus.ppu().scanline();
+                scanline == line && self.last_scanline != line
+            }
+            RunMode::Quit => false,
+        };
+
+        for reason in reasons.iter() {
+            println!("** {}", reason);
+        }
+        stop || !reasons.is_empty()
+    }
+
+    fn print_location(&self, cpu: &CPU<Bus>) {
+        let ppu = cpu.bus.ppu();
+        println!(
+            "{} PPU:{:>3},{:>3} CYC:{}",
+            trace_at(cpu, cpu.program_counter),
+            ppu.scanline(),
+            ppu.dot(),
+            cpu.bus.cycles()
+        );
+    }
+
+    fn prompt(&mut self, cpu: &mut CPU<Bus>) {
+        self.print_location(cpu);
+        loop {
+            print!("(rscom) ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line).unwrap_or(0) == 0 {
+                // 標準入力が閉じられたらデバッガを外して続行
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                self.break_on_nmi = false;
+                self.break_on_irq = false;
+                self.update_access_logs(cpu);
+                self.mode = RunMode::Run;
+                return;
+            }
+
+            let command = if line.trim().is_empty() {
+                match &self.last_command {
+                    Some(command) => command.clone(),
+                    None => continue,
+                }
+            } else {
+                match parse_command(&line) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            };
+            self.last_command = Some(command.clone());
+
+            if self.execute(cpu, command) {
+                return;
+            }
+        }
+    }
+
+    // コマンドを実行する (trueならエミュレーションを再開)
+    pub fn execute(&mut self, cpu: &mut CPU<Bus>, command: Command) -> bool {
+        match command {
+            Command::Continue => {
+                self.mode = RunMode::Run;
+                return true;
+            }
+            Command::Step(n) => {
+                self.mode = RunMode::StepInto(n);
+                return true;
+            }
+            Command::Next => {
+                let pc = cpu.program_counter;
+                self.mode = if cpu.peek(pc) == OP_JSR {
+                    RunMode::StepOver {
+                        ret: pc.wrapping_add(3),
+                        sp: cpu.stack_pointer,
+                    }
+                } else {
+                    RunMode::StepInto(1)
+                };
+                return true;
+            }
+            Command::Finish => {
+                self.mode = RunMode::StepOut {
+                    sp: cpu.stack_pointer,
+                };
+                return true;
+            }
+            Command::ToScanline(line) => {
+                self.mode = RunMode::ToScanline(line);
+                return true;
+            }
+            Command::Break(addr) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+            }
+            Command::DeleteBreak(addr) => self.breakpoints.retain(|b| *b != addr),
+            Command::Watch(watch) => {
+                self.watchpoints.push(watch);
+                self.update_access_logs(cpu);
+            }
+            Command::DeleteWatch(i) => {
+                if i < self.watchpoints.len() {
+                    self.watchpoints.remove(i);
+                    self.update_access_logs(cpu);
+                } else {
+                    println!("no watchpoint #{}", i);
+                }
+            }
+            Command::List => {
+                for b in self.breakpoints.iter() {
+                    println!("  break ${:04X}", b);
+                }
+                for (i, w) in self.watchpoints.iter().enumerate() {
+                    let kind: String = [(w.read, 'r'), (w.write, 'w'), (w.execute, 'x')]
+                        .iter()
+                        .filter(|(on, _)| *on)
+                        .map(|(_, c)| *c)
+                        .collect();
+                    println!("  #{} watch {:<3} {:?} ${:04X}-${:04X}", i, kind, w.space, w.start, w.end);
+                }
+                println!("  nmi {} / irq {}", self.break_on_nmi, self.break_on_irq);
+            }
+            Command::BreakOnNmi(on) => self.break_on_nmi = on,
+            Command::BreakOnIrq(on) => self.break_on_irq = on,
+            Command::Regs => self.print_location(cpu),
+            Command::SetReg(reg, value) => {
+                match reg.as_str() {
+                    "a" => cpu.register_a = value as u8,
+                    "x" => cpu.register_x = value as u8,
+                    "y" => cpu.register_y = value as u8,
+                    "p" => cpu.status = value as u8,
+                    "sp" => cpu.stack_pointer = value as u8,
+                    "pc" => cpu.program_counter = value,
+                    _ => {}
+                }
+                self.print_location(cpu);
+            }
+            Command::Dump(space, addr, len) => {
+                for row in (0..len).step_by(16) {
+                    let base = addr.wrapping_add(row);
+                    let bytes: Vec<String> = (0..16.min(len - row))
+                        .map(|i| {
+                            let a = base.wrapping_add(i);
+                            let v = match space {
+                                Space::Cpu => cpu.peek(a),
+                                Space::Ppu => cpu.bus.ppu().peek(a),
+                            };
+                            format!("{:02X}", v)
+                        })
+                        .collect();
+                    println!("  {:04X}: {}", base, bytes.join(" "));
+                }
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Quit => {
+                self.mode = RunMode::Quit;
+                return true;
+            }
+        }
+        false
+    }
+
+    // R/Wウォッチポイントがある時だけバス側でアクセスを記録する
+    fn update_access_logs(&self, cpu: &mut CPU<Bus>) {
+        let need = |space: Space| {
+            self.watchpoints
+                .iter()
+                .any(|w| w.space == space && (w.read || w.write))
+        };
+        cpu.bus.access_log = if need(Space::Cpu) { Some(vec![]) } else { None };
+        cpu.bus.ppu_mut().access_log = if need(Space::Ppu) { Some(vec![]) } else { None };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::APU;
+    use crate::mapper::MapperMMC;
+    use crate::rom::Rom;
+    use crate::{lock_mapper_for_test, MAPPER};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    #[rustfmt::skip]
+    const PROGRAM: &[(u16, &[u8])] = &[
+        (0x8000, &[
+            0xA9, 0x00,       // $8000 LDA #$00
+            0x8D, 0x17, 0x40, // $8002 STA $4017 (フレームIRQ有効)
+            0x20, 0x20, 0x80, // $8005 JSR $8020
+            0x8D, 0x00, 0x03, // $8008 STA $0300
+            0xAD, 0x00, 0x03, // $800B LDA $0300
+            0xA9, 0x3F,       // $800E LDA #$3F
+            0x8D, 0x06, 0x20, // $8010 STA $2006
+            0xA9, 0x00,       // $8013 LDA #$00
+            0x8D, 0x06, 0x20, // $8015 STA $2006
+            0x8D, 0x07, 0x20, // $8018 STA $2007 (PPU $3F00)
+            0x58,             // $801B CLI
+            0x4C, 0x1C, 0x80, // $801C JMP $801C
+        ]),
+        (0x8020, &[
+            0xE8,             // $8020 INX
+            0x20, 0x30, 0x80, // $8021 JSR $8030
+            0xC8,             // $8024 INY
+            0x60,             // $8025 RTS
+        ]),
+        (0x8030, &[
+            0xA9, 0x05,       // $8030 LDA #$05
+            0x60,             // $8032 RTS
+        ]),
+        (0x9000, &[
+            0xAD, 0x15, 0x40, // $9000 LDA $4015 (IRQ)
+            0x40,             // $9003 RTI
+        ]),
+        (0x9100, &[
+            0x40,             // $9100 RTI (NMI)
+        ]),
+    ];
+
+    // PROGRAM を置いたNROMでリセットしたCPU (lock_mapper_for_test を取ってから呼ぶ)
+    fn test_cpu() -> CPU<Bus<'static>> {
+        let mut prg = vec![0; 0x8000];
+        for (addr, code) in PROGRAM {
+            let offset = (*addr - 0x8000) as usize;
+            prg[offset..offset + code.len()].copy_from_slice(code);
+        }
+        // NMI $9100, RESET $8000, IRQ $9000
+        prg[0x7FFA..].copy_from_slice(&[0x00, 0x91, 0x00, 0x80, 0x00, 0x90]);
+        let mut rom = Rom::mem_blank();
+        rom.prg_rom = prg;
+        {
+            let mut mapper = MAPPER.lock().unwrap();
+            **mapper = MapperMMC::new();
+            mapper.set_rom(&rom);
+        }
+        let mut cpu = CPU::new(Bus::new(rom, APU::new_headless(), |_, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    // input をコマンドとして入力しながら steps 命令実行して、止まる度に record の値を集める
+    // (入力が尽きるとデバッガは外れる)
+    fn run_with<T: 'static>(
+        debugger: &mut Debugger,
+        cpu: &mut CPU<Bus>,
+        input: &str,
+        steps: usize,
+        record: fn(&CPU<Bus>) -> T,
+    ) -> Vec<T> {
+        let stops = Rc::new(RefCell::new(vec![]));
+        let on_break = stops.clone();
+        debugger.on_break = Some(Box::new(move |cpu| on_break.borrow_mut().push(record(cpu))));
+        debugger.input = Box::new(Cursor::new(input.to_string()));
+        for _ in 0..steps {
+            debugger.hook(cpu);
+            cpu.step();
+        }
+        debugger.on_break = None;
+        Rc::try_unwrap(stops).ok().unwrap().into_inner()
+    }
+
+    // 止まった所のPC
+    fn run(debugger: &mut Debugger, cpu: &mut CPU<Bus>, input: &str, steps: usize) -> Vec<u16> {
+        run_with(debugger, cpu, input, steps, |cpu| cpu.program_counter)
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, Command::Break(0x8008));
+        assert_eq!(run(&mut debugger, &mut cpu, "c\n", 100), vec![0x8008]);
+    }
+
+    #[test]
+    fn test_set_register() {
+        // サブルーチンから戻った所で A を書き換えると、その値が $0300 に書かれる
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, Command::Break(0x8008));
+        let stops = run(&mut debugger, &mut cpu, "r a 7\nr x 10\nc\n", 100);
+        assert_eq!(stops, vec![0x8008]);
+        assert_eq!(cpu.peek(0x0300), 0x07);
+        assert_eq!(cpu.register_x, 0x10);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // R/Wはアクセスした命令の次、実行はその命令の前で止まる
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        for command in ["w x 8020", "w w 0300", "w r 0300", "w w ppu 3F00-3F1F"] {
+            debugger.execute(&mut cpu, parse_command(command).unwrap());
+        }
+        let stops = run(&mut debugger, &mut cpu, "c\nc\nc\nc\n", 100);
+        assert_eq!(stops, vec![0x8020, 0x800B, 0x800E, 0x801B]);
+
+        // 消すとアクセスの記録も止まる
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, parse_command("w rw 0300").unwrap());
+        debugger.execute(&mut cpu, Command::DeleteWatch(0));
+        assert!(cpu.bus.access_log.is_none());
+        assert_eq!(run(&mut debugger, &mut cpu, "c\n", 100), vec![]);
+    }
+
+    #[test]
+    fn test_step() {
+        // 空行は直前のコマンドを繰り返す
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        let stops = run(&mut debugger, &mut cpu, "s 2\ns\n\n\nf\n\nc\n", 100);
+        assert_eq!(
+            stops,
+            vec![0x8000, 0x8005, 0x8020, 0x8021, 0x8030, 0x8024, 0x8008]
+        );
+        assert_eq!((cpu.register_x, cpu.register_y), (1, 1));
+    }
+
+    #[test]
+    fn test_step_over() {
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        let stops = run(&mut debugger, &mut cpu, "s 2\nn\nn\nc\n", 100);
+        assert_eq!(stops, vec![0x8000, 0x8005, 0x8008, 0x800B]);
+        assert_eq!((cpu.register_x, cpu.register_y), (1, 1));
+    }
+
+    #[test]
+    fn test_break_on_nmi() {
+        // ハンドラの最初の命令の前で止まる
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, Command::BreakOnNmi(true));
+        assert_eq!(run(&mut debugger, &mut cpu, "c\n", 20), vec![]);
+        cpu.bus.ppu_mut().nmi_interrupt = Some(1);
+        assert_eq!(run(&mut debugger, &mut cpu, "c\n", 20), vec![0x9100]);
+    }
+
+    #[test]
+    fn test_break_on_irq() {
+        // CLIの後、APUのフレームIRQ (約29830サイクル毎) で止まる
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, Command::BreakOnIrq(true));
+        let stops = run(&mut debugger, &mut cpu, "c\nc\n", 20000);
+        assert_eq!(stops, vec![0x9000, 0x9000]);
+
+        // off にすると止まらない
+        debugger.execute(&mut cpu, Command::BreakOnIrq(false));
+        assert_eq!(run(&mut debugger, &mut cpu, "c\n", 20000), vec![]);
+    }
+
+    #[test]
+    fn test_run_to_scanline() {
+        let _lock = lock_mapper_for_test();
+        let mut cpu = test_cpu();
+        let mut debugger = Debugger::new();
+        debugger.pause();
+        let scanlines = run_with(&mut debugger, &mut cpu, "sl 100\nc\n", 20000, |cpu| {
+            cpu.bus.ppu().scanline()
+        });
+        assert_eq!(scanlines, vec![0, 100]);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("b C000"), Ok(Command::Break(0xC000)));
+        assert_eq!(parse_command("s"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("s 10"), Ok(Command::Step(10)));
+        assert_eq!(parse_command("r pc $8000"), Ok(Command::SetReg("pc".to_string(), 0x8000)));
+        assert_eq!(
+            parse_command("w rw ppu 3F00-3F1F"),
+            Ok(Command::Watch(Watchpoint {
+                space: Space::Ppu,
+                start: 0x3F00,
+                end: 0x3F1F,
+                read: true,
+                write: true,
+                execute: false,
+            }))
+        );
+        assert!(parse_command("w x ppu 2000").is_err());
+        assert!(parse_command("foo").is_err());
+    }
+}
@@ -0,0 +1,450 @@
+// 6502 逆アセンブラ
+//
+// CPUのメモリ空間 (peek経由) かPRG-ROMのバンクを逆アセンブルする。
+// ラベルは ca65 の .dbg か FCEUX の .nl ファイルから読み込める。
+// 先頭から順に命令として解釈するだけなので、データ領域もそのまま命令として出る
+use crate::bus::Mem;
+use crate::cpu::{AddressingMode, OpCode};
+use crate::opcode::CPU_OPS_CODES;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// iNESヘッダのサイズ (.dbg の ooffs はファイル先頭からのオフセット)
+const INES_HEADER_SIZE: usize = 16;
+// FCEUX の .nl は16KB単位のバンク番号
+const NL_BANK_SIZE: usize = 0x4000;
+
+pub fn find_op(code: u8) -> &'static OpCode {
+    CPU_OPS_CODES
+        .iter()
+        .find(|op| op.code == code)
+        .expect("opcode table must have all 256 entries")
+}
+
+pub fn binary(op: u8, args: &[u8]) -> String {
+    let mut list: Vec<String> = vec![];
+    list.push(format!("{:<02X}", op));
+    for v in args {
+        list.push(format!("{:<02X}", v));
+    }
+    list.join(" ")
+}
+
+pub fn disasm(program_counter: u16, ops: &OpCode, args: &[u8]) -> String {
+    let prefix = if ops.name.starts_with('*') { "" } else { " " };
+    format!(
+        "{}{} {}",
+        prefix,
+        ops.name,
+        address(program_counter, ops, args)
+    )
+}
+
+pub fn address(program_counter: u16, ops: &OpCode, args: &[u8]) -> String {
+    match ops.addressing_mode {
+        AddressingMode::Implied => {
+            String::new()
+        }
+        AddressingMode::Accumulator => {
+            "A".to_string()
+        }
+        // LDA #$44 => a9 44
+        AddressingMode::Immediate => {
+            format!("#${:<02X}", args[0])
+        }
+
+        // LDA $44 => a5 44
+        AddressingMode::ZeroPage => {
+            format!("${:<02X}", args[0])
+        }
+
+        // LDA $4400 => ad 00 44
+        AddressingMode::Absolute => {
+            format!("${:<02X}{:<02X}", args[1], args[0])
+        }
+        // LDA $44,X => b5 44
+        AddressingMode::ZeroPage_X => {
+            format!("${:<02X},X", args[0])
+        }
+
+        // LDX $44,Y => b6 44
+        AddressingMode::ZeroPage_Y => {
+            format!("${:<02X},Y", args[0])
+        }
+
+        // LDA $4400,X => bd 00 44
+        AddressingMode::Absolute_X => {
+            format!("${:<02X}{:<02X},X", args[1], args[0])
+        }
+
+        // LDA $4400,Y => b9 00 44
+        AddressingMode::Absolute_Y => {
+            format!("${:<02X}{:<02X},Y", args[1], args[0])
+        }
+        // JMP
+        AddressingMode::Indirect => {
+            format!("(${:<02X}{:<02X})", args[1], args[0])
+        }
+
+        // LDA ($44,X) => a1 44
+        AddressingMode::Indirect_X => {
+            format!("(${:<02X},X)", args[0])
+        }
+
+        // LDA ($44),Y => b1 44
+        AddressingMode::Indirect_Y => {
+            format!("(${:<02X}),Y", args[0])
+        }
+
+        // BCC *+4 => 90 04
+        AddressingMode::Relative => {
+            format!("${:<04X}", branch_target(program_counter, args[0]))
+        }
+
+        AddressingMode::NoneAddressing => {
+            panic!("_mode {:?} is not supported", ops.addressing_mode);
+        }
+    }
+}
+
+fn branch_target(program_counter: u16, offset: u8) -> u16 {
+    program_counter
+        .wrapping_add(2)
+        .wrapping_add(offset as i8 as u16)
+}
+
+// 1命令分
+pub struct Instruction {
+    pub addr: u16,
+    pub op: &'static OpCode,
+    pub args: Vec<u8>,
+}
+
+impl Instruction {
+    pub fn size(&self) -> u16 {
+        self.op.bytes
+    }
+
+    // オペランドが指すアドレス (即値やインデックス前のベースアドレス)
+    pub fn target(&self) -> Option<u16> {
+        match self.op.addressing_mode {
+            AddressingMode::ZeroPage
+            | AddressingMode::ZeroPage_X
+            | AddressingMode::ZeroPage_Y
+            | AddressingMode::Indirect_X
+            | AddressingMode::Indirect_Y => Some(self.args[0] as u16),
+            AddressingMode::Absolute
+            | AddressingMode::Absolute_X
+            | AddressingMode::Absolute_Y
+            | AddressingMode::Indirect => Some(u16::from_le_bytes([self.args[0], self.args[1]])),
+            AddressingMode::Relative => Some(branch_target(self.addr, self.args[0])),
+            _ => None,
+        }
+    }
+
+    // "LDA $0200,X" の形式 (labelがあればアドレスをラベルに置き換える)
+    pub fn format<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let text = disasm(self.addr, self.op, &self.args);
+        let target = match self.target() {
+            Some(target) => target,
+            None => return text.trim_end().to_string(),
+        };
+        let text = match label(target) {
+            Some(name) => {
+                let hex = match self.op.addressing_mode {
+                    AddressingMode::Absolute
+                    | AddressingMode::Absolute_X
+                    | AddressingMode::Absolute_Y
+                    | AddressingMode::Indirect
+                    | AddressingMode::Relative => format!("${:04X}", target),
+                    _ => format!("${:02X}", target),
+                };
+                text.replacen(&hex, &name, 1)
+            }
+            None => text,
+        };
+        text.trim_end().to_string()
+    }
+}
+
+pub fn decode<F: Fn(u16) -> u8>(read: F, addr: u16) -> Instruction {
+    let op = find_op(read(addr));
+    let args = (1..op.bytes).map(|n| read(addr.wrapping_add(n))).collect();
+    Instruction { addr, op, args }
+}
+
+// ラベル (シンボル)
+// PRG-ROM上のラベルはバンク違いで同じアドレスになるので、PRG-ROM内のオフセットで持つ
+pub struct Symbols {
+    ram: HashMap<u16, String>,
+    prg: HashMap<usize, (u16, String)>,
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            ram: HashMap::new(),
+            prg: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ram.len() + self.prg.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn add_label(&mut self, addr: u16, name: &str) {
+        self.ram.insert(addr, name.to_string());
+    }
+
+    pub fn add_prg_label(&mut self, prg_offset: usize, addr: u16, name: &str) {
+        self.prg.insert(prg_offset, (addr, name.to_string()));
+    }
+
+    // prg_offsetが分かっていればバンクも一致するラベルを優先する
+    pub fn label(&self, addr: u16, prg_offset: Option<usize>) -> Option<&str> {
+        if let Some(offset) = prg_offset {
+            if let Some((_, name)) = self.prg.get(&offset) {
+                return Some(name);
+            }
+        }
+        if let Some(name) = self.ram.get(&addr) {
+            return Some(name);
+        }
+        if prg_offset.is_none() {
+            // バンクが分からない時は、アドレスが一致するもののうちPRG-ROMで先頭に近いものを使う
+            return self
+                .prg
+                .iter()
+                .filter(|(_, (a, _))| *a == addr)
+                .min_by_key(|(offset, _)| **offset)
+                .map(|(_, (_, name))| name.as_str());
+        }
+        None
+    }
+
+    // FCEUX の .nl ("$C000#Label#Comment")
+    // bankがNoneならRAM (<rom>.ram.nl)、Someなら16KBバンク番号 (<rom>.<bank>.nl)
+    pub fn load_nl<P: AsRef<Path>>(&mut self, path: P, bank: Option<usize>) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        for line in text.lines() {
+            let mut cols = line.trim().splitn(3, '#');
+            let addr = cols.next().unwrap_or("").trim_start_matches('$');
+            let name = cols.next().unwrap_or("").trim();
+            // "$0300/10" のように配列のサイズが付くこともある
+            let addr = addr.split('/').next().unwrap_or("");
+            let addr = match u16::from_str_radix(addr, 16) {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            if name.is_empty() {
+                continue;
+            }
+            match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    let offset = bank * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1));
+                    self.add_prg_label(offset, addr, name);
+                }
+                _ => self.add_label(addr, name),
+            }
+        }
+        Ok(())
+    }
+
+    // ROMと同じ場所にある <rom>.ram.nl と <rom>.0.nl, <rom>.1.nl ... を読み込む
+    pub fn load_nl_for_rom<P: AsRef<Path>>(&mut self, rom_path: P, prg_size: usize) {
+        let rom_path = rom_path.as_ref().to_string_lossy().to_string();
+        self.load_nl(format!("{}.ram.nl", rom_path), None).ok();
+        for bank in 0..(prg_size / NL_BANK_SIZE).max(1) {
+            self.load_nl(format!("{}.{:X}.nl", rom_path, bank), Some(bank)).ok();
+        }
+    }
+
+    // ca65/ld65 の --dbgfile で出力されるデバッグ情報
+    //   seg  id=0,name="CODE",start=0x008000,size=0x0123,...,ooffs=16
+    //   sym  id=0,name="reset",...,val=0x8000,seg=0,type=lab
+    pub fn load_dbg<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        // seg id => (開始アドレス, ファイル内オフセット)
+        let mut segs: HashMap<String, (usize, Option<usize>)> = HashMap::new();
+        let mut syms: Vec<HashMap<String, String>> = vec![];
+
+        for line in text.lines() {
+            let (kind, fields) = match line.split_once(char::is_whitespace) {
+                Some(kv) => kv,
+                None => continue,
+            };
+            let fields = parse_dbg_fields(fields);
+            match kind {
+                "seg" => {
+                    let start = fields.get("start").and_then(|v| parse_dbg_num(v));
+                    let ooffs = fields.get("ooffs").and_then(|v| parse_dbg_num(v));
+                    if let (Some(id), Some(start)) = (fields.get("id"), start) {
+                        segs.insert(id.clone(), (start, ooffs));
+                    }
+                }
+                "sym" => syms.push(fields),
+                _ => {}
+            }
+        }
+
+        for sym in syms {
+            if sym.get("type").map(|t| t.as_str()) != Some("lab") {
+                continue;
+            }
+            let name = match sym.get("name") {
+                Some(name) => name,
+                None => continue,
+            };
+            let val = match sym.get("val").and_then(|v| parse_dbg_num(v)) {
+                Some(val) if val <= 0xFFFF => val,
+                _ => continue,
+            };
+            let seg = sym.get("seg").and_then(|id| segs.get(id));
+            match seg {
+                Some((start, Some(ooffs))) if val >= 0x8000 && *ooffs >= INES_HEADER_SIZE => {
+                    let offset = ooffs - INES_HEADER_SIZE + (val - start);
+                    self.add_prg_label(offset, val as u16, name);
+                }
+                _ => self.add_label(val as u16, name),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_dbg_fields(fields: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut in_quote = false;
+    let mut field = String::new();
+    for c in fields.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => in_quote = !in_quote,
+            ',' if !in_quote => {
+                if let Some((key, value)) = field.split_once('=') {
+                    map.insert(key.trim().to_string(), value.to_string());
+                }
+                field.clear();
+            }
+            _ => field.push(c),
+        }
+    }
+    map
+}
+
+fn parse_dbg_num(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn listing_line(inst: &Instruction, label: Option<&str>, operand: String) -> String {
+    let mut line = String::new();
+    if let Some(label) = label {
+        line.push_str(&format!("{}:\n", label));
+    }
+    line.push_str(&format!(
+        "  {:04X}  {:<9}{}",
+        inst.addr,
+        binary(inst.op.code, &inst.args),
+        operand
+    ));
+    line
+}
+
+// CPUのメモリ空間 start..=end を逆アセンブルする (peekなので副作用なし)
+pub fn disassemble<M: Mem>(mem: &M, start: u16, end: u16, symbols: &Symbols) -> Vec<String> {
+    let mut lines = vec![];
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+        let inst = decode(|a| mem.peek(a), addr as u16);
+        let label = symbols.label(inst.addr, None);
+        let operand = inst.format(|target| symbols.label(target, None).map(|s| s.to_string()));
+        lines.push(listing_line(&inst, label, operand));
+        addr += inst.size() as u32;
+    }
+    lines
+}
+
+// PRG-ROMのバンクを、baseのアドレスに配置されているものとして逆アセンブルする
+pub fn disassemble_bank(
+    prg_rom: &[u8],
+    bank: usize,
+    bank_size: usize,
+    base: u16,
+    symbols: &Symbols,
+) -> Vec<String> {
+    let bank_start = bank * bank_size;
+    let data = &prg_rom[bank_start..(bank_start + bank_size).min(prg_rom.len())];
+    let end = base as usize + data.len();
+    // バンク内のアドレスならPRG-ROMのオフセットも分かる
+    let offset_of = |addr: u16| {
+        let addr = addr as usize;
+        if base as usize <= addr && addr < end {
+            Some(bank_start + addr - base as usize)
+        } else {
+            None
+        }
+    };
+    let read = |addr: u16| match offset_of(addr) {
+        Some(offset) => prg_rom[offset],
+        None => 0,
+    };
+
+    let mut lines = vec![];
+    let mut addr = base as usize;
+    while addr < end {
+        let inst = decode(read, addr as u16);
+        let label = symbols.label(inst.addr, offset_of(inst.addr));
+        let operand = inst.format(|target| {
+            symbols
+                .label(target, offset_of(target))
+                .map(|s| s.to_string())
+        });
+        lines.push(listing_line(&inst, label, operand));
+        addr += inst.size() as usize;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dbg_labels() {
+        let path = std::env::temp_dir().join("rscom_disasm_test.dbg");
+        fs::write(
+            &path,
+            "version\tmajor=2,minor=0\n\
+             seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw\n\
+             seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"a.nes\",ooffs=16\n\
+             sym\tid=0,name=\"ptr\",addrsize=zeropage,size=2,scope=0,def=1,val=0x2,seg=0,type=lab\n\
+             sym\tid=1,name=\"loop\",addrsize=absolute,scope=0,def=2,val=0xC002,seg=1,type=lab\n\
+             sym\tid=2,name=\"COUNT\",addrsize=zeropage,scope=0,def=3,val=0x10,type=equ\n",
+        )
+        .unwrap();
+        let mut symbols = Symbols::new();
+        symbols.load_dbg(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(symbols.len(), 2);
+
+        // LDA ($02),Y / loop: BNE loop
+        let prg = [0xB1, 0x02, 0xD0, 0xFE];
+        let lines = disassemble_bank(&prg, 0, prg.len(), 0xC000, &symbols);
+        assert_eq!(lines[0], "  C000  B1 02     LDA (ptr),Y");
+        assert_eq!(lines[1], "loop:\n  C002  D0 FE     BNE loop");
+    }
+}
@@ -1,16 +1,27 @@
-use crate::palette;
+use crate::palette::{pixel_index, Palette};
+use crate::ppu::MaskRegister;
+use crate::region::Region;
 
 pub struct Frame {
     pub data: Vec<u8>,
+    // エンファシス込みの9ビットのパレット値 (NTSCフィルタ用)
+    pub indices: Vec<u16>,
+    // render() が色を決める時に使うパレット
+    pub palette: Palette,
+    // PPUMASKのエンファシスのビットの並びが地域で違う
+    pub region: Region,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HEIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HEIGHT) * 3],
+            indices: vec![0; (Frame::WIDTH) * (Frame::HEIGHT)],
+            palette: Palette::default(),
+            region: Region::NTSC,
         }
     }
 
@@ -22,4 +33,14 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+
+    // パレットの値で描き、RGBとパレット値の両方を更新する
+    pub fn set_color(&mut self, x: usize, y: usize, index: u8, mask: &MaskRegister) {
+        let pixel = pixel_index(index, mask, self.region);
+        if x < Frame::WIDTH && y < Frame::HEIGHT {
+            self.indices[y * Frame::WIDTH + x] = pixel;
+        }
+        let rgb = self.palette.colors[pixel as usize];
+        self.set_pixel(x, y, rgb);
+    }
+}
@@ -1,7 +1,5 @@
 use bitflags::bitflags;
 
-use crate::cpu::IN_TRACE;
-
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq)]
     pub struct Button: u8 {
@@ -45,13 +43,19 @@ impl GamePad {
 
         let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
-            if !unsafe { IN_TRACE } {
-                self.button_index += 1;
-            }
+            self.button_index += 1;
         }
         response
     }
 
+    // $4016を読んだ時に返る値 (ボタンのインデックスは進めない)
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits() & (1 << self.button_index)) >> self.button_index
+    }
+
     pub fn set_button_pressed_status(&mut self, button: Button, value: bool) {
         self.button_status.set(button, value)
     }
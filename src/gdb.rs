@@ -0,0 +1,398 @@
+// GDB リモートシリアルプロトコル (RSP) のスタブ
+//
+// GDBやGDB互換のフロントエンドから TCP で接続してデバッグできるようにする。
+// Debugger と同じくメインループで命令毎に hook() を呼ぶ。
+//
+// 対応しているパケット
+//   ?              停止理由
+//   g / G          全レジスタ読み書き (a, x, y, p, sp, pc(リトルエンディアン))
+//   p n / P n=v    レジスタ1つ読み書き
+//   m addr,len     メモリ読み込み (peek)
+//   M addr,len:xx  メモリ書き込み (poke なのでRAM/WRAMのみ)
+//   c / s          続行 / 1命令実行 (アドレス指定も可)
+//   Z0,Z1 / z0,z1  ブレークポイント (メモリは書き換えずにPCで判定する)
+//   qSupported, qXfer:features:read:target.xml, D(デタッチ), k(終了)
+//   実行中の 0x03 (Ctrl-C) で停止
+use crate::bus::Mem;
+use crate::cpu::CPU;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+// 実行中に 0x03 (割り込み) が来ていないか見る間隔 (命令数)
+const POLL_INTERVAL: u32 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rscom.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Halted,
+    Running,
+    Stepping,
+    Detached,
+    Killed,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    pub breakpoints: Vec<u16>,
+    state: State,
+    poll_count: u32,
+    // ackを待っている時に届いた ack 以外のバイト (0x03 や次のパケット) は取っておく
+    pending: VecDeque<u8>,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| match c {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+impl GdbStub {
+    // addrで待ち受けて、GDBが接続してくるまでブロックする
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(addr)?;
+        info!("waiting for GDB on {}", listener.local_addr()?);
+        GdbStub::accept(&listener)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+        let (stream, peer) = listener.accept()?;
+        info!("GDB connected from {}", peer);
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream,
+            breakpoints: vec![],
+            state: State::Halted,
+            poll_count: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.state != State::Detached && self.state != State::Killed
+    }
+
+    // k パケットでエミュレータの終了を要求された
+    pub fn is_killed(&self) -> bool {
+        self.state == State::Killed
+    }
+
+    // 命令の実行前に毎回呼ぶ (cpu.program_counter が次に実行する命令)
+    // 停止中はGDBからのパケットを処理して、c/s/D/k が来るまで戻らない
+    pub fn hook<M: Mem>(&mut self, cpu: &mut CPU<M>) {
+        match self.state {
+            State::Detached | State::Killed => return,
+            State::Halted => {}
+            State::Stepping => self.stop(SIGTRAP),
+            State::Running => {
+                if cpu.is_jammed() {
+                    self.stop(SIGILL);
+                } else if self.breakpoints.contains(&cpu.program_counter) {
+                    self.stop(SIGTRAP);
+                } else {
+                    self.poll_count += 1;
+                    if self.poll_count < POLL_INTERVAL {
+                        return;
+                    }
+                    self.poll_count = 0;
+                    if !self.poll_interrupt() {
+                        return;
+                    }
+                    self.stop(SIGINT);
+                }
+            }
+        }
+        self.serve(cpu);
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.state = State::Halted;
+        self.send(&format!("S{:02x}", signal));
+    }
+
+    // 実行中に 0x03 が届いていたら true
+    fn poll_interrupt(&mut self) -> bool {
+        if let Some(pos) = self.pending.iter().position(|b| *b == 0x03) {
+            self.pending.remove(pos);
+            return true;
+        }
+        let mut buf = [0u8; 1];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false).ok();
+        match result {
+            Ok(0) => {
+                self.detach();
+                false
+            }
+            Ok(_) => buf[0] == 0x03,
+            Err(_) => false,
+        }
+    }
+
+    fn detach(&mut self) {
+        info!("GDB detached");
+        self.breakpoints.clear();
+        self.state = State::Detached;
+    }
+
+    fn serve<M: Mem>(&mut self, cpu: &mut CPU<M>) {
+        while self.state == State::Halted {
+            let packet = match self.recv() {
+                Some(packet) => packet,
+                None => {
+                    self.detach();
+                    return;
+                }
+            };
+            let reply = self.handle(cpu, &packet);
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if let Some(b) = self.pending.pop_front() {
+            return Some(b);
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    // $<data>#<checksum> を1つ受け取って ack を返す
+    fn recv(&mut self) -> Option<String> {
+        loop {
+            // '$' まで読み飛ばす ('+' '-' や停止中の 0x03 は無視)
+            while self.read_byte()? != b'$' {}
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let sum = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if sum == Some(checksum(&data)) {
+                self.stream.write_all(b"+").ok()?;
+                return Some(String::from_utf8_lossy(&data).to_string());
+            }
+            warn!("GDB: bad checksum");
+            self.stream.write_all(b"-").ok()?;
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        // ackの '-' (再送要求) が来たら送り直す
+        for _ in 0..3 {
+            if self.stream.write_all(packet.as_bytes()).is_err() {
+                break;
+            }
+            match self.read_byte() {
+                Some(b'-') => continue,
+                Some(b'+') | None => return,
+                // ackを省略して次のパケットや 0x03 を送ってきた
+                Some(b) => {
+                    self.pending.push_back(b);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn read_register<M: Mem>(cpu: &CPU<M>, n: usize) -> Option<Vec<u8>> {
+        match n {
+            0 => Some(vec![cpu.register_a]),
+            1 => Some(vec![cpu.register_x]),
+            2 => Some(vec![cpu.register_y]),
+            3 => Some(vec![cpu.status]),
+            4 => Some(vec![cpu.stack_pointer]),
+            5 => Some(cpu.program_counter.to_le_bytes().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn write_register<M: Mem>(cpu: &mut CPU<M>, n: usize, value: &[u8]) -> bool {
+        match (n, value) {
+            (0, [v]) => cpu.register_a = *v,
+            (1, [v]) => cpu.register_x = *v,
+            (2, [v]) => cpu.register_y = *v,
+            (3, [v]) => cpu.status = *v,
+            (4, [v]) => cpu.stack_pointer = *v,
+            (5, [lo, hi]) => cpu.program_counter = u16::from_le_bytes([*lo, *hi]),
+            _ => return false,
+        }
+        true
+    }
+
+    // P n=v
+    fn write_register_packet<M: Mem>(cpu: &mut CPU<M>, args: &str) -> Option<String> {
+        let (n, value) = args.split_once('=')?;
+        if GdbStub::write_register(cpu, parse_hex(n)?, &unhex(value)?) {
+            Some("OK".to_string())
+        } else {
+            None
+        }
+    }
+
+    // m addr,len
+    fn read_memory_packet<M: Mem>(cpu: &CPU<M>, args: &str) -> Option<String> {
+        let (addr, len) = args.split_once(',')?;
+        let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+        let data: Vec<u8> = (0..len).map(|i| cpu.peek((addr + i) as u16)).collect();
+        Some(hex(&data))
+    }
+
+    // M addr,len:xx..
+    fn write_memory_packet<M: Mem>(cpu: &mut CPU<M>, args: &str) -> Option<String> {
+        let (header, data) = args.split_once(':')?;
+        let (addr, len) = header.split_once(',')?;
+        let (addr, len, data) = (parse_hex(addr)?, parse_hex(len)?, unhex(data)?);
+        if data.len() != len {
+            return None;
+        }
+        for (i, b) in data.iter().enumerate() {
+            cpu.poke((addr + i) as u16, *b);
+        }
+        Some("OK".to_string())
+    }
+
+    // パケットを処理して返信を返す (c/s は停止するまで返信しないので None)
+    fn handle<M: Mem>(&mut self, cpu: &mut CPU<M>, packet: &str) -> Option<String> {
+        let ok = || Some("OK".to_string());
+        let err = || Some("E01".to_string());
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+
+        match cmd {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => {
+                let regs: Vec<u8> = (0..6).flat_map(|n| GdbStub::read_register(cpu, n).unwrap()).collect();
+                Some(hex(&regs))
+            }
+            "G" => match unhex(args) {
+                Some(regs) if regs.len() == 7 => {
+                    GdbStub::write_register(cpu, 0, &regs[0..1]);
+                    GdbStub::write_register(cpu, 1, &regs[1..2]);
+                    GdbStub::write_register(cpu, 2, &regs[2..3]);
+                    GdbStub::write_register(cpu, 3, &regs[3..4]);
+                    GdbStub::write_register(cpu, 4, &regs[4..5]);
+                    GdbStub::write_register(cpu, 5, &regs[5..7]);
+                    ok()
+                }
+                _ => err(),
+            },
+            "p" => match parse_hex(args).and_then(|n| GdbStub::read_register(cpu, n)) {
+                Some(value) => Some(hex(&value)),
+                None => err(),
+            },
+            "P" => GdbStub::write_register_packet(cpu, args).or_else(err),
+            "m" => GdbStub::read_memory_packet(cpu, args).or_else(err),
+            "M" => GdbStub::write_memory_packet(cpu, args).or_else(err),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    cpu.program_counter = addr as u16;
+                }
+                self.state = if cmd == "c" { State::Running } else { State::Stepping };
+                None
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(parse_hex);
+                match (kind, addr) {
+                    (Some("0") | Some("1"), Some(addr)) => {
+                        let addr = addr as u16;
+                        if cmd == "Z" {
+                            if !self.breakpoints.contains(&addr) {
+                                self.breakpoints.push(addr);
+                            }
+                        } else {
+                            self.breakpoints.retain(|b| *b != addr);
+                        }
+                        ok()
+                    }
+                    // ウォッチポイントは未対応
+                    _ => Some(String::new()),
+                }
+            }
+            "H" => ok(),
+            "D" => {
+                self.send("OK");
+                self.detach();
+                None
+            }
+            "k" => {
+                info!("GDB: kill");
+                self.state = State::Killed;
+                None
+            }
+            "q" => self.query(packet).or_else(err),
+            // 未対応のパケットには空で返す
+            _ => Some(String::new()),
+        }
+    }
+
+    fn query(&self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some("PacketSize=1000;qXfer:features:read+".to_string());
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = args.split_once(',')?;
+            let offset = parse_hex(offset)?.min(TARGET_XML.len());
+            let len = parse_hex(len)?;
+            let end = (offset + len).min(TARGET_XML.len());
+            let prefix = if end < TARGET_XML.len() { "m" } else { "l" };
+            return Some(format!("{}{}", prefix, &TARGET_XML[offset..end]));
+        }
+        match packet {
+            "qAttached" => Some("1".to_string()),
+            "qC" => Some("QC1".to_string()),
+            "qfThreadInfo" => Some("m1".to_string()),
+            "qsThreadInfo" => Some("l".to_string()),
+            _ => Some(String::new()),
+        }
+    }
+}
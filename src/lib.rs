@@ -0,0 +1,42 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod frame;
+pub mod gamepad;
+pub mod gdb;
+pub mod mapper;
+pub mod ntsc;
+pub mod opcode;
+pub mod palette;
+pub mod ppu;
+pub mod region;
+pub mod render;
+pub mod rom;
+pub mod scaler;
+pub mod screenshot;
+pub mod tracer;
+pub mod viewer;
+pub mod common;
+
+use mapper::MapperMMC;
+use std::sync::Mutex;
+
+lazy_static! {
+    pub static ref MAPPER: Mutex<Box<MapperMMC>> = Mutex::new(Box::new(MapperMMC::new()));
+}
+
+// MAPPERはグローバルなので、書き換えて使うテストはこのロックを取って直列に動かす
+#[cfg(test)]
+static TEST_MAPPER_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+pub(crate) fn lock_mapper_for_test() -> std::sync::MutexGuard<'static, ()> {
+    // 他のテストがpanicしても続ける
+    TEST_MAPPER_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -1,50 +1,42 @@
-#[macro_use]
-extern crate lazy_static;
-
-mod apu;
-mod bus;
-mod cartridge;
-mod cpu;
-mod frame;
-mod gamepad;
-mod mapper;
-mod opcode;
-mod palette;
-mod ppu;
-mod render;
-mod rom;
-mod common;
-use common::*;
-use crate::cpu::{trace, IN_TRACE};
-
-use self::bus::{Bus, Mem};
-use self::cpu::CPU;
-
-use apu::APU;
-use cartridge::load_rom;
-use frame::Frame;
-use gamepad::GamePad;
-use log::{debug, info, log_enabled, trace, Level};
-use mapper::MapperMMC;
-use ppu::PPU;
-use sdl2::event::Event;
+use rscom::common::*;
+use rscom::cpu::{magic_constant_from_env, trace, TRACE_LOG_TARGET};
+
+use rscom::bus::{Bus, Mem};
+use rscom::cpu::CPU;
+use rscom::debugger::Debugger;
+use rscom::gdb::GdbStub;
+use rscom::tracer::TraceLogger;
+
+use rscom::apu::APU;
+use rscom::cartridge::load_rom;
+use rscom::frame::Frame;
+use rscom::gamepad::{self, GamePad};
+use rscom::ntsc::{NtscFilter, NTSC_WIDTH};
+use rscom::palette::Palette;
+use rscom::ppu::PPU;
+use rscom::region::Region;
+use rscom::scaler::{Overscan, Scaler};
+use rscom::screenshot::Screenshot;
+use rscom::viewer::{ViewerKind, ViewerTextures, Viewers};
+use rscom::{render, MAPPER};
+use log::{error, info, log_enabled, Level};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::EventPump;
+use sdl2::rect::Rect;
+use sdl2::render::Texture;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io::Write;
-use std::sync::Mutex;
-
-lazy_static! {
-    pub static ref MAPPER: Mutex<Box<MapperMMC>> = Mutex::new(Box::new(MapperMMC::new()));
-}
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 fn main() {
     env_logger::builder()
         .format(|buf, record| {
             let style = buf.style();
-            if unsafe { IN_TRACE } {
+            if record.target() == TRACE_LOG_TARGET {
                 writeln!(buf, "[TRACE] {}", style.value(record.args()))
             } else {
                 writeln!(buf, "        {}", style.value(record.args()))
@@ -53,21 +45,74 @@ fn main() {
         .format_timestamp(None)
         .init();
 
+    let mut rom = load_rom(_NES_ROM_PATH);
+    MAPPER.lock().unwrap().set_rom(&rom);
+
+    // RSCOM_REGION で地域 (NTSC/PAL/Dendy) を強制する
+    if let Some(region) = Region::from_env().expect("can't set region") {
+        rom.region = region;
+    }
+    let region = rom.region;
+
+    info!(
+        "ROM: mapper={}, mirroring={:?} chr_ram={} region={:?}",
+        rom.mapper, rom.mirroring, rom.is_chr_ram, rom.region
+    );
+
+    // RSCOM_FILTER, RSCOM_ASPECT で拡大フィルタと縦横比を決める (F6, F7 で切り替え)
+    let mut scaler = Scaler::from_env().expect("can't set up scaling filter");
+    // RSCOM_OVERSCAN か <rom>.overscan で画面の端を切り取る
+    let overscan = Overscan::for_rom(_NES_ROM_PATH).expect("can't set up overscan");
+    let (visible_width, visible_height) = overscan.visible_size();
+    let window_height = visible_height as u32 * 2;
+    let aspect = scaler.display_aspect(visible_width, visible_height);
+    let window_width = (window_height as f32 * aspect).round() as u32;
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("rscom -Rust NES Emulator-", (256.0 * 2.0) as u32, (240.0 * 2.0) as u32)
+        .window("rscom -Rust NES Emulator-", window_width, window_height)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // 50HzのPAL/Dendyは垂直同期に任せず、自前でフレームの間隔を合わせる
+    let mut canvas = if region == Region::NTSC {
+        window.into_canvas().present_vsync().build().unwrap()
+    } else {
+        window.into_canvas().build().unwrap()
+    };
+    let frame_duration = Duration::from_secs_f64(1.0 / region.frame_rate());
+    let mut next_frame = Instant::now();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(2.0, 2.0).unwrap();
 
-    let creator = canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
-        .unwrap();
+    // F1～F4 でPPUのビューアを開く (RSCOM_VIEWER_SCANLINE の時点の内容を表示する)
+    let mut viewers = Viewers::new(&video_subsystem).expect("can't create viewer windows");
+    let viewer_scanline: usize = match std::env::var("RSCOM_VIEWER_SCANLINE") {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .ok()
+            .filter(|line| *line < region.scanlines())
+            .unwrap_or_else(|| {
+                panic!("RSCOM_VIEWER_SCANLINE must be 0-{}", region.scanlines() - 1)
+            }),
+        Err(_) => 241,
+    };
+    let viewer_creators = viewers.texture_creators();
+    let mut viewer_textures = ViewerTextures::new(&viewer_creators);
+    // ビューアを表示している間だけPPUにスナップショットを取らせる
+    let snapshot_scanline: Rc<Cell<Option<usize>>> = Rc::new(Cell::new(None));
+    let viewer_snapshot_scanline = snapshot_scanline.clone();
+
+    // RSCOM_NTSC でNTSCフィルタを通す (横幅が広くなる)
+    let mut ntsc = NtscFilter::from_env().expect("can't set up NTSC filter");
+
+    // フィルタを切り替えると大きさが変わるので、その時に作り直す
+    let texture_creator = canvas.texture_creator();
+    let creator = &texture_creator;
+    let mut texture: Option<(Texture, usize, usize)> = None;
+    let mut cropped = vec![];
 
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, gamepad::Button::DOWN);
@@ -79,36 +124,155 @@ fn main() {
     key_map.insert(Keycode::A, gamepad::Button::BUTTON_A);
     key_map.insert(Keycode::S, gamepad::Button::BUTTON_B);
 
-    let rom = load_rom(_NES_ROM_PATH);
-    MAPPER.lock().unwrap().prg_rom = rom.prg_rom.clone();
-    MAPPER.lock().unwrap().chr_rom = rom.chr_rom.clone();
-    MAPPER.lock().unwrap().is_chr_ram = rom.is_chr_ram.clone();
-    MAPPER.lock().unwrap().is_prg_ram = rom.is_prg_ram.clone();
-    MAPPER.lock().unwrap().mapper = rom.mapper.clone();
-    MAPPER.lock().unwrap().rom_type = rom.rom_type.clone();
-    MAPPER.lock().unwrap().mmc_1.rom_type = rom.rom_type.clone();
+    // F12 でデバッガに入る (RSCOM_DEBUG を指定するとリセット直後から止まる)
+    let mut debugger = Debugger::new();
+    if std::env::var("RSCOM_DEBUG").is_ok() {
+        debugger.pause();
+    }
+    let break_request = debugger.break_request.clone();
 
-    info!(
-        "ROM: mapper={}, mirroring={:?} chr_ram={}",
-        rom.mapper, rom.mirroring, rom.is_chr_ram
-    );
+    // RSCOM_TRACE でファイルにトレースを出力する (F9 で開始/停止)
+    let tracer = Rc::new(RefCell::new(
+        TraceLogger::from_env().expect("can't start trace logger"),
+    ));
+    let trace_toggle = tracer.borrow().toggle_request.clone();
+    let break_tracer = tracer.clone();
+    debugger.on_break = Some(Box::new(move |_| break_tracer.borrow_mut().dump("break")));
+    let quit_tracer = tracer.clone();
 
+    // RSCOM_PALETTE でパレットを差し替える (.pal ファイル or ntsc)
     let mut frame = Frame::new();
+    frame.palette = Palette::from_env().expect("can't load palette");
+    frame.region = region;
+
+    // F8 でスクリーンショットをPNGで保存する (次のフレームを描画した時に書き出す)
+    let screenshot = Screenshot::from_env(_NES_ROM_PATH);
+    let mut screenshot_request = false;
+    let mut frame_number: u64 = 0;
     let apu = APU::new(&sdl_context);
     let bus = Bus::new(rom, apu, move |ppu: &PPU, gamepad_1: &mut GamePad| {
         render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+        frame_number += 1;
+        let (pixels, width) = match &mut ntsc {
+            Some(ntsc) => (ntsc.apply(&frame), NTSC_WIDTH),
+            None => (&frame.data[..], 256),
+        };
+        let (width, height) = overscan.crop(pixels, width, 240, &mut cropped);
+        let (pixels, width, height) = scaler.apply(&cropped, width, height);
+        if std::mem::take(&mut screenshot_request) {
+            match screenshot.save_frame(&frame, frame_number) {
+                Ok(path) => info!("screenshot: {}", path.display()),
+                Err(e) => error!("can't save screenshot: {}", e),
+            }
+            if screenshot.also_save_filtered {
+                match screenshot.save_filtered(pixels, width, height, frame_number) {
+                    Ok(path) => info!("screenshot: {}", path.display()),
+                    Err(e) => error!("can't save screenshot: {}", e),
+                }
+            }
+        }
+        if texture.as_ref().map(|(_, w, h)| (*w, *h)) != Some((width, height)) {
+            let t = creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+                .unwrap();
+            texture = Some((t, width, height));
+        }
+        let (t, _, _) = texture.as_mut().unwrap();
+        t.update(None, pixels, width * 3).unwrap();
 
-        canvas.copy(&texture, None, None).unwrap();
+        // NTSCフィルタで横に伸びていても、切り取った後の元の画面の縦横比で表示する
+        let (out_width, out_height) = canvas.output_size().unwrap();
+        let aspect = scaler.display_aspect(visible_width, visible_height);
+        let (x, y, w, h) = scaler.fit(aspect, out_width, out_height);
+        canvas.clear();
+        canvas.copy(t, None, Some(Rect::new(x, y, w, h))).unwrap();
 
         canvas.present();
+        if viewers.is_active() {
+            if let Some(snapshot) = &ppu.snapshot {
+                viewers.update(snapshot, &frame.palette, &mut viewer_textures);
+            }
+        }
+        if region != Region::NTSC {
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                std::thread::sleep(next_frame - now);
+            } else {
+                // 追いつけない時は遅れを持ち越さない
+                next_frame = now;
+            }
+        }
         for event in event_pump.poll_iter() {
             match event {
+                Event::Window {
+                    win_event: WindowEvent::Close,
+                    window_id,
+                    ..
+                } if viewers.close(window_id) => {}
                 Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => {
+                    // process::exit()ではDropが走らないので先に書き出しておく
+                    quit_tracer.borrow_mut().flush();
+                    std::process::exit(0)
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => break_request.set(true),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } => trace_toggle.set(true),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => screenshot_request = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => {
+                    scaler.filter = scaler.filter.next();
+                    info!("filter: {}", scaler.filter.name());
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => {
+                    scaler.aspect_8_7 = !scaler.aspect_8_7;
+                    let aspect = if scaler.aspect_8_7 { "8:7" } else { "1:1" };
+                    info!("aspect ratio: {}", aspect);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => viewers.toggle(ViewerKind::Nametable),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => viewers.toggle(ViewerKind::PatternTable),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => viewers.toggle(ViewerKind::Oam),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => viewers.toggle(ViewerKind::Palette),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    viewers.pattern_palette = (viewers.pattern_palette + 1) % 8;
+                    info!("pattern table palette: {}", viewers.pattern_palette);
+                }
                 Event::KeyDown { keycode, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
                         gamepad_1.set_button_pressed_status(*key, true);
@@ -122,16 +286,68 @@ fn main() {
                 _ => { /* do nothing */ }
             }
         }
+        viewer_snapshot_scanline.set(viewers.is_active().then_some(viewer_scanline));
     });
 
     let mut cpu = CPU::new(bus);
+    // RSCOM_MAGIC_CONSTANT でANE/LXAのマジック定数を変える
+    cpu.magic_constant = magic_constant_from_env().expect("can't set magic constant");
+    // RSCOM_NO_SPRITE_LIMIT でスプライトの8個制限を外す (ちらつき防止)
+    cpu.bus.ppu_mut().remove_sprite_limit = std::env::var("RSCOM_NO_SPRITE_LIMIT").is_ok();
 
     cpu.reset();
-    cpu.run_with_callback(move |cpu| {
+
+    // RSCOM_GDB=127.0.0.1:9000 等を指定するとGDBの接続を待ってから始める
+    let mut gdb = std::env::var("RSCOM_GDB")
+        .ok()
+        .map(|addr| GdbStub::listen(addr.as_str()).expect("can't start GDB stub"));
+
+    let mut callback = |cpu: &mut CPU<Bus>| {
         if log_enabled!(Level::Trace) {
             trace(cpu);
         }
-    });
+        tracer.borrow_mut().log(cpu);
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| loop {
+        cpu.bus.ppu_mut().snapshot_scanline = snapshot_scanline.get();
+        debugger.hook(&mut cpu);
+        if debugger.is_quit() {
+            tracer.borrow_mut().flush();
+            std::process::exit(0);
+        }
+        if let Some(gdb) = &mut gdb {
+            gdb.hook(&mut cpu);
+            if gdb.is_killed() {
+                tracer.borrow_mut().flush();
+                std::process::exit(0);
+            }
+        }
+        cpu.step_with_callback(&mut callback);
+        if cpu.is_jammed() {
+            break;
+        }
+    }));
+    if let Err(e) = result {
+        tracer.borrow_mut().dump("panic");
+        panic::resume_unwind(e);
+    }
+
+    // ループを抜けるのはJAM命令でCPUが停止した時のみ
+    if cpu.is_jammed() {
+        tracer.borrow_mut().dump("CPU halted");
+        let opcode = cpu.peek(cpu.program_counter);
+        error!(
+            "CPU halted (JAM ${:02X} at ${:04X}). A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            opcode,
+            cpu.program_counter,
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.status,
+            cpu.stack_pointer
+        );
+        std::process::exit(1);
+    }
 }
 
 #[cfg(test)]
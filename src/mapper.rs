@@ -1,6 +1,6 @@
 use log::{info, warn};
 // use log::{debug, error, info, log_enabled, trace, warn, Level};
-use crate::{common, rom::RomType};
+use crate::{common, rom::Rom, rom::RomType};
 use common::*;
 use crate::rom::Mirroring;
 
@@ -275,9 +275,9 @@ impl Mapper4 {
         //                                  four 1 KB banks at $1000-$1FFF;
         //                               1: two 2 KB banks at $1000-$1FFF,
         //                                  four 1 KB banks at $0000-$0FFF)
+        // bit6, 7 (PRG/CHRのモード) も読み出し側で見るので、そのまま残す
         self.bank_sel_reg = val;
         let reg = self.bank_sel_reg;
-        self.bank_sel_reg = reg & 0x07;
 
         self.chr_a12_inv_mode = match (reg & _BIT_7) >> 7 {
             1     => (FOUR_1KB_BANK, TWO_2KB_BANK),
@@ -412,6 +412,19 @@ impl MapperMMC {
         }
     }
 
+    pub fn set_rom(&mut self, rom: &Rom) {
+        self.prg_rom = rom.prg_rom.clone();
+        self.chr_rom = rom.chr_rom.clone();
+        self.is_chr_ram = rom.is_chr_ram;
+        self.is_prg_ram = rom.is_prg_ram;
+        if rom.is_chr_ram {
+            self.chr_ram = vec![0; rom.chr_rom.len().max(_MEM_SIZE_8K as usize)];
+        }
+        self.mapper = rom.mapper;
+        self.rom_type = rom.rom_type.clone();
+        self.mmc_1.rom_type = rom.rom_type.clone();
+    }
+
     fn mapper_1_write(&mut self, addr: u16, data: u8)
     {
         match addr {
@@ -434,7 +447,7 @@ impl MapperMMC {
             // [For PPU]
             // CHR-RAM
             0x0000..=0x1FFF => {
-                self.chr_ram[addr as usize] = data;
+                self.write_chr(addr, data);
             },
 
             // [For CPU]
@@ -497,7 +510,11 @@ impl MapperMMC {
         match self.mapper {
             _MAPPER_1 => self.mmc_1_write(addr, data),
             _MAPPER_4 => self.mmc_3_write(addr, data),
-            _ => self.bank_select = data,
+            _ => match addr {
+                // 拡張RAM(WRAM)
+                0x6000..=0x7FFF => self.ext_ram[(addr - 0x6000) as usize] = data,
+                _ => self.bank_select = data,
+            },
         }
     }
 
@@ -553,7 +570,7 @@ impl MapperMMC {
     }
 
     fn mapper_4_chr_rom_addr(&self, addr: usize) -> usize {
-        let d7 = self.bank_select & 0x80;
+        let d7 = self.mmc_3.mapper_4.bank_sel_reg & 0x80;
         let bank_len: usize = 1 * 1024;
         let r0: usize = (self.mmc_3.mapper_4.bank_data_reg[0] & 0xFE) as usize;
         let r1: usize = (self.mmc_3.mapper_4.bank_data_reg[1] & 0xFE) as usize;
@@ -658,6 +675,10 @@ impl MapperMMC {
                 self.chr_rom[(addr as usize + bank_len * bank as usize) as usize]
             },
             // [For CPU]
+            // 拡張RAM(WRAM)
+            0x6000..=0x7FFF => {
+                self.ext_ram[(addr - 0x6000) as usize]
+            },
             0x8000..=0xFFFF => {
                 self.prg_rom[(addr - 0x8000)as usize]
             },
@@ -690,11 +711,137 @@ impl MapperMMC {
     }
 
     pub fn read_chr_rom(&mut self, addr: u16) -> u8 {
+        self.read_chr(addr)
+    }
+
+    // PPUの$0000-$1FFFが今指しているCHR-ROM/CHR-RAMのオフセット
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
         match self.mapper {
-            _MAPPER_1 | _MAPPER_105 | _MAPPER_115 => self.mmc_1_read(addr),
-            _MAPPER_3 => self.mapper_3_read(addr),
-            _MAPPER_4 | _MAPPER_118 | _MAPPER_119 => self.mmc_3_read(addr),
-            _ => panic!("[ERR] Not Emu Support MapperMMC {}", self.mapper),
+            _MAPPER_1 | _MAPPER_105 | _MAPPER_115 => {
+                let mapper_1 = &self.mmc_1.mapper_1;
+                if mapper_1.chr_bank_mode == _MEM_SIZE_4K {
+                    // 4KBモード: R1が$0000、R2が$1000
+                    let bank = if addr < 0x1000 { mapper_1.ctrl_reg_r1 } else { mapper_1.ctrl_reg_r2 };
+                    bank as usize * 0x1000 + (addr & 0x0FFF)
+                } else {
+                    // 8KBモード: R1の下位ビットは無視
+                    (mapper_1.ctrl_reg_r1 & 0x1E) as usize * 0x1000 + addr
+                }
+            }
+            _MAPPER_3 => (self.bank_select & 0x0F) as usize * _MEM_SIZE_8K as usize + addr,
+            _MAPPER_4 | _MAPPER_118 | _MAPPER_119 => self.mapper_4_chr_rom_addr(addr),
+            _ => addr,
+        }
+    }
+
+    // パターンテーブルの読み込み (PPUの$2007、描画とも全部ここを通す)
+    // CHRのサイズを超えるバンク番号はミラーする
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        let chr = if self.is_chr_ram { &self.chr_ram } else { &self.chr_rom };
+        if chr.is_empty() {
+            return 0;
+        }
+        chr[self.chr_offset(addr) % chr.len()]
+    }
+
+    // パターンテーブルへの書き込み (CHR-RAMの時のみ。CHR-ROMへの書き込みは無視)
+    pub fn write_chr(&mut self, addr: u16, data: u8) {
+        if !self.is_chr_ram || self.chr_ram.is_empty() {
+            return;
+        }
+        let offset = self.chr_offset(addr) % self.chr_ram.len();
+        self.chr_ram[offset] = data;
+    }
+
+    // 副作用なしで読む (トレース、デバッガ、メモリビューア用)
+    // $0000-$1FFF: CHR (PPU側), $6000-$FFFF: WRAM/PRG-ROM (CPU側)
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.read_chr(addr),
+            0x6000..=0xFFFF => match self.mapper {
+                _MAPPER_0 | _MAPPER_2 => self.mmc_2_read(addr),
+                _MAPPER_1 | _MAPPER_105 | _MAPPER_115 => self.mmc_1_read(addr),
+                _MAPPER_3 => self.mapper_3_read(addr),
+                _MAPPER_4 | _MAPPER_118 | _MAPPER_119 => self.mmc_3_read(addr),
+                _ => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    // CPUアドレスが現在指しているPRG-ROMのオフセット (トレース、逆アセンブラ用)
+    // 各マッパーの read と同じ計算をしている
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg_rom.is_empty() {
+            return None;
+        }
+        let addr = addr as usize;
+        let bank_16k = _MEM_SIZE_16K as usize;
+        let offset = match self.mapper {
+            _MAPPER_0 | _MAPPER_2 => {
+                let bank_max = self.prg_rom.len() / bank_16k;
+                match addr {
+                    0x8000..=0xBFFF => addr - 0x8000 + bank_16k * (self.bank_select & 0x0F) as usize,
+                    _ => addr - 0xC000 + bank_16k * (bank_max - 1),
+                }
+            }
+            _MAPPER_1 | _MAPPER_105 | _MAPPER_115 => {
+                let (bank_len, _bank_addr, bank_ops) = self.mmc_1.mapper_1.prg_bank_mode;
+                let (first_bank_len, last_bank_len) = match bank_ops {
+                    FIX_LAST_BANK => (bank_len, _MEM_SIZE_16K),
+                    FIX_FIRST_BANK => (_MEM_SIZE_16K, bank_len),
+                    _ => (bank_len, bank_len),
+                };
+                let bank_max = self.prg_rom.len() / (bank_len as usize);
+                match addr {
+                    0x8000..=0xBFFF => {
+                        addr - 0x8000 + first_bank_len as usize * self.mmc_1.mapper_1.prg_bank as usize
+                    }
+                    _ => addr - 0xC000 + last_bank_len as usize * (bank_max - 1),
+                }
+            }
+            _MAPPER_3 => addr - 0x8000,
+            _MAPPER_4 | _MAPPER_118 | _MAPPER_119 => {
+                let bank_len = _MEM_SIZE_8K as usize;
+                let bank_max = self.prg_rom.len() / bank_len;
+                let mode = self.mmc_3.mapper_4.bank_sel_reg & _BIT_6 != 0;
+                let r6 = self.mmc_3.mapper_4.bank_data_reg[6] as usize;
+                let r7 = self.mmc_3.mapper_4.bank_data_reg[7] as usize;
+                let bank = match (addr, mode) {
+                    (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => r6,
+                    (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => bank_max - 2,
+                    (0xA000..=0xBFFF, _) => r7,
+                    _ => bank_max - 1,
+                };
+                (addr & (bank_len - 1)) + bank * bank_len
+            }
+            _ => return None,
+        };
+        if offset < self.prg_rom.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // PRG-ROMのバンク切り替えの単位
+    pub fn prg_bank_size(&self) -> usize {
+        match self.mapper {
+            _MAPPER_4 | _MAPPER_118 | _MAPPER_119 => _MEM_SIZE_8K as usize,
+            _ => _MEM_SIZE_16K as usize,
+        }
+    }
+
+    // CPUアドレスに今割り当てられているPRG-ROMのバンク番号
+    pub fn prg_bank(&self, addr: u16) -> Option<usize> {
+        self.prg_rom_offset(addr).map(|offset| offset / self.prg_bank_size())
+    }
+
+    // 副作用なしで書き込む (WRAMのみ、バンク切り替え等のレジスタやROMは無視)
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.ext_ram[(addr - 0x6000) as usize] = data;
         }
     }
 
@@ -721,6 +868,89 @@ impl MapperMMC {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    // 各バイトにそのバイトが属する1KBバンクの番号が入ったCHR
+    fn numbered_chr(banks: usize) -> Vec<u8> {
+        (0..banks * 0x400).map(|i| (i / 0x400) as u8).collect()
+    }
+
+    #[test]
+    fn test_cnrom_chr_bank() {
+        let mut mapper = MapperMMC::new();
+        mapper.mapper = _MAPPER_3;
+        mapper.chr_rom = numbered_chr(32);
+        assert_eq!(mapper.read_chr(0x0000), 0);
+        mapper.write(0x8000, 2);
+        assert_eq!(mapper.read_chr(0x0000), 16);
+        assert_eq!(mapper.read_chr(0x1FFF), 23);
+        // CHRのサイズを超えるバンクはミラー
+        mapper.write(0x8000, 5);
+        assert_eq!(mapper.read_chr(0x0000), 8);
+    }
+
+    #[test]
+    fn test_mmc3_chr_bank() {
+        let mut mapper = MapperMMC::new();
+        mapper.mapper = _MAPPER_4;
+        mapper.chr_rom = numbered_chr(64);
+        // R0 = 9 (2KBバンクなので下位ビットは無視), R2 = 20
+        mapper.write(0x8000, 0);
+        mapper.write(0x8001, 9);
+        mapper.write(0x8000, 2);
+        mapper.write(0x8001, 20);
+        assert_eq!(mapper.read_chr(0x0000), 8);
+        assert_eq!(mapper.read_chr(0x0400), 9);
+        assert_eq!(mapper.read_chr(0x1000), 20);
+        // CHR A12反転で入れ替わる
+        mapper.write(0x8000, 0x80);
+        assert_eq!(mapper.read_chr(0x0000), 20);
+        assert_eq!(mapper.read_chr(0x1000), 8);
+    }
+
+    #[test]
+    fn test_mmc3_prg_bank_mode() {
+        // 8KBバンク x 8、各バイトにバンク番号
+        let mut mapper = MapperMMC::new();
+        mapper.mapper = _MAPPER_4;
+        mapper.prg_rom = (0..8 * 0x2000).map(|i| (i / 0x2000) as u8).collect();
+        // R6 = 1, R7 = 2
+        mapper.write(0x8000, 6);
+        mapper.write(0x8001, 1);
+        mapper.write(0x8000, 7);
+        mapper.write(0x8001, 2);
+        let banks = |mapper: &mut MapperMMC| {
+            [0x8000, 0xA000, 0xC000, 0xE000].map(|addr| mapper.read_prg_rom(addr))
+        };
+        assert_eq!(banks(&mut mapper), [1, 2, 6, 7]);
+        assert_eq!(mapper.prg_bank(0x8000), Some(1));
+
+        // モード1: $8000 が最後から2番目に固定され、R6 は $C000 に入る
+        mapper.write(0x8000, 0x40);
+        assert_eq!(banks(&mut mapper), [6, 2, 1, 7]);
+        assert_eq!(mapper.prg_bank(0x8000), Some(6));
+        assert_eq!(mapper.prg_bank(0xC000), Some(1));
+    }
+
+    #[test]
+    fn test_write_chr_ram() {
+        let mut mapper = MapperMMC::new();
+        mapper.mapper = _MAPPER_4;
+        mapper.is_chr_ram = true;
+        // R5 = 3: $1C00 は CHR-RAM の $0C00
+        mapper.write(0x8000, 5);
+        mapper.write(0x8001, 3);
+        mapper.write_chr(0x1C10, 0x5A);
+        assert_eq!(mapper.chr_ram[0x0C10], 0x5A);
+        assert_eq!(mapper.read_chr(0x1C10), 0x5A);
+
+        // CHR-ROMなら書き込まない
+        mapper.is_chr_ram = false;
+        mapper.chr_rom = vec![0; 0x2000];
+        mapper.write_chr(0x0000, 0xFF);
+        assert_eq!(mapper.read_chr(0x0000), 0);
+    }
+
     #[test]
     fn test_ptr_from_vec() {
         let my_vec: Vec<u8> = vec![1, 2, 3, 4, 5];
@@ -0,0 +1,267 @@
+// NTSCコンポジット映像のフィルタ (blargg の nes_ntsc 風)
+//
+// Frame の9ビットのパレット値から1ラインのコンポジット信号を作り直し、
+// それをYIQに復調してRGBにする。輝度と色が混ざるので、縦縞の偽色やドットクロールが出る
+// main.rs では環境変数で有効にする
+//
+//   RSCOM_NTSC=1                 : 標準の設定で有効にする
+//   RSCOM_NTSC=sharpness=0.5,dot_crawl=0,hue=0,saturation=1.0,contrast=1.0,brightness=0.0
+//
+// sharpness は -1.0 (ぼやける) - 1.0 (くっきり、偽色が強い)
+use crate::frame::Frame;
+use crate::palette::{ntsc_phase_angle, ntsc_signal, yiq_to_rgb, NtscPaletteParams, PALETTE_SIZE};
+use std::env;
+use std::io;
+
+// 出力の横幅 (256ドットを横長に引き伸ばす)
+pub const NTSC_WIDTH: usize = 602;
+
+// 1ドットは色信号の 8/12 周期
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = Frame::WIDTH * SAMPLES_PER_PIXEL;
+// 1ラインは 341 * 8 サンプルなので、次のラインは位相が 4 ずれる
+const LINE_PHASE_SHIFT: usize = 4;
+// 左右の端で窓がはみ出す分 (位相がずれないように12の倍数)
+const PADDING: usize = 12;
+
+pub struct NtscFilter {
+    pub params: NtscPaletteParams,
+    pub sharpness: f32,
+    // フレーム毎に位相をずらす (切るとドットクロールが止まる)
+    pub dot_crawl: bool,
+
+    frame_count: usize,
+    // signals[pixel][phase]
+    signals: Vec<[f32; 12]>,
+    output: Vec<u8>,
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        NtscFilter::new(NtscPaletteParams::default())
+    }
+}
+
+impl NtscFilter {
+    pub fn new(params: NtscPaletteParams) -> Self {
+        let signals = (0..PALETTE_SIZE as u16)
+            .map(|pixel| std::array::from_fn(|phase| ntsc_signal(pixel, phase)))
+            .collect();
+        NtscFilter {
+            params,
+            sharpness: 0.0,
+            dot_crawl: true,
+            frame_count: 0,
+            signals,
+            output: vec![0; NTSC_WIDTH * Frame::HEIGHT * 3],
+        }
+    }
+
+    // RSCOM_NTSC が無ければ None
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match env::var("RSCOM_NTSC") {
+            Ok(spec) => NtscFilter::from_spec(&spec)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // "1" なら標準の設定、それ以外は "sharpness=0.5,dot_crawl=0,hue=10" 等
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        if spec.is_empty() || spec == "1" {
+            return Ok(NtscFilter::default());
+        }
+        let mut sharpness = 0.0;
+        let mut dot_crawl = true;
+        let mut rest = vec![];
+        for item in spec.split(',') {
+            match item.split_once('=') {
+                Some(("sharpness", value)) => {
+                    sharpness = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid sharpness '{}'", value))?
+                }
+                Some(("dot_crawl", value)) => dot_crawl = value.trim() != "0",
+                _ => rest.push(item),
+            }
+        }
+        let mut filter = NtscFilter::new(NtscPaletteParams::parse(&rest.join(","))?);
+        filter.sharpness = sharpness;
+        filter.dot_crawl = dot_crawl;
+        Ok(filter)
+    }
+
+    // NTSC_WIDTH x 240 の RGB を返す
+    pub fn apply(&mut self, frame: &Frame) -> &[u8] {
+        // 輝度を平均する窓の幅。色信号の1周期(12)より狭いほど色が輝度に漏れる
+        let luma_width = (8.0 - 4.0 * self.sharpness.clamp(-1.0, 1.0)).round() as usize;
+        let frame_phase = if self.dot_crawl {
+            (self.frame_count % 3) * LINE_PHASE_SHIFT
+        } else {
+            0
+        };
+        self.frame_count = self.frame_count.wrapping_add(1);
+
+        // 信号と、それに cos/sin を掛けたものの累積和 (窓の合計を O(1) で取る)
+        let len = LINE_SAMPLES + PADDING * 2;
+        let mut sum_y = vec![0.0f32; len + 1];
+        let mut sum_i = vec![0.0f32; len + 1];
+        let mut sum_q = vec![0.0f32; len + 1];
+
+        for y in 0..Frame::HEIGHT {
+            let line = &frame.indices[y * Frame::WIDTH..(y + 1) * Frame::WIDTH];
+            let line_phase = frame_phase + y * LINE_PHASE_SHIFT;
+            for n in 0..len {
+                // 端より外は端のドットが続いているものとする
+                let x = (n.saturating_sub(PADDING) / SAMPLES_PER_PIXEL).min(Frame::WIDTH - 1);
+                let phase = (line_phase + n) % 12;
+                let v = self.signals[line[x] as usize][phase];
+                let angle = ntsc_phase_angle(phase as f32, self.params.hue);
+                sum_y[n + 1] = sum_y[n] + v;
+                sum_i[n + 1] = sum_i[n] + v * angle.cos();
+                sum_q[n + 1] = sum_q[n] + v * angle.sin();
+            }
+
+            let window = |sum: &[f32], center: usize, width: usize| {
+                let start = center - width / 2;
+                (sum[start + width] - sum[start]) / width as f32
+            };
+            for out_x in 0..NTSC_WIDTH {
+                let center = PADDING + (out_x * LINE_SAMPLES + LINE_SAMPLES / 2) / NTSC_WIDTH;
+                let luma = window(&sum_y, center, luma_width);
+                let i = window(&sum_i, center, 12) * 2.0;
+                let q = window(&sum_q, center, 12) * 2.0;
+                let (r, g, b) = yiq_to_rgb(luma, i, q, &self.params);
+                let base = (y * NTSC_WIDTH + out_x) * 3;
+                self.output[base] = r;
+                self.output[base + 1] = g;
+                self.output[base + 2] = b;
+            }
+        }
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filled(pixel: u16) -> Frame {
+        let mut frame = Frame::new();
+        frame.indices.iter_mut().for_each(|p| *p = pixel);
+        frame
+    }
+
+    fn output_pixel(output: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * NTSC_WIDTH + x) * 3;
+        (output[base], output[base + 1], output[base + 2])
+    }
+
+    fn luma((r, g, b): (u8, u8, u8)) -> f32 {
+        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+    }
+
+    fn chroma((r, g, b): (u8, u8, u8)) -> u8 {
+        r.max(g).max(b) - r.min(g).min(b)
+    }
+
+    // 白と黒の1ドット幅の縦縞
+    fn stripes() -> Frame {
+        let mut frame = Frame::new();
+        for (i, p) in frame.indices.iter_mut().enumerate() {
+            *p = if i % 2 == 0 { 0x30 } else { 0x0F };
+        }
+        frame
+    }
+
+    // 左半分が黒、右半分が白
+    fn edge() -> Frame {
+        let mut frame = Frame::new();
+        for (i, p) in frame.indices.iter_mut().enumerate() {
+            *p = if i % Frame::WIDTH < Frame::WIDTH / 2 {
+                0x0F
+            } else {
+                0x30
+            };
+        }
+        frame
+    }
+
+    #[test]
+    fn test_flat_colors() {
+        let mut filter = NtscFilter::default();
+        // 灰色は色が付かない
+        let output = filter.apply(&filled(0x10)).to_vec();
+        let (r, g, b) = output_pixel(&output, 300, 100);
+        assert!(r.abs_diff(g) < 4 && g.abs_diff(b) < 4);
+
+        // 一色で塗った所は偽色が出ない
+        let output = filter.apply(&filled(0x16)).to_vec();
+        let (r, g, b) = output_pixel(&output, 300, 100);
+        assert!(r > g && r > b);
+    }
+
+    #[test]
+    fn test_artifact_colors() {
+        // 白黒だけの細かい縦縞に、色信号と誤って復調された偽色が付く
+        let mut filter = NtscFilter::default();
+        let output = filter.apply(&stripes()).to_vec();
+        let max_chroma = (100..500)
+            .map(|x| chroma(output_pixel(&output, x, 100)))
+            .max()
+            .unwrap();
+        assert!(max_chroma > 100, "chroma {}", max_chroma);
+
+        let output = filter.apply(&filled(0x30)).to_vec();
+        assert!(chroma(output_pixel(&output, 300, 100)) < 4);
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+        // 色信号の位相がラインとフレーム毎にずれるので、偽色が変わる (3フレームで1周)
+        let mut filter = NtscFilter::default();
+        let frames: Vec<Vec<u8>> = (0..4).map(|_| filter.apply(&stripes()).to_vec()).collect();
+        assert_ne!(frames[0], frames[1]);
+        assert_ne!(frames[1], frames[2]);
+        assert_eq!(frames[0], frames[3]);
+        let line = |y: usize| &frames[0][y * NTSC_WIDTH * 3..(y + 1) * NTSC_WIDTH * 3];
+        assert_ne!(line(100), line(101));
+        assert_eq!(line(100), line(103));
+
+        // dot_crawl=0 ならフレーム毎には変わらない
+        let mut filter = NtscFilter::from_spec("dot_crawl=0").unwrap();
+        let first = filter.apply(&stripes()).to_vec();
+        assert_eq!(filter.apply(&stripes()), &first[..]);
+    }
+
+    #[test]
+    fn test_sharpness() {
+        // ぼやけるほど境界の暗い側に明るさが漏れる
+        let leak = |sharpness: f32| {
+            let mut filter = NtscFilter::from_spec("dot_crawl=0").unwrap();
+            filter.sharpness = sharpness;
+            let output = filter.apply(&edge()).to_vec();
+            // 境界は出力の x = 301 の中ほど
+            (
+                luma(output_pixel(&output, 299, 100)),
+                luma(output_pixel(&output, 300, 100)),
+            )
+        };
+        let (blur_far, blur) = leak(-1.0);
+        let (_, normal) = leak(0.0);
+        let (sharp_far, sharp) = leak(1.0);
+        assert!(
+            blur > normal && normal > sharp,
+            "{} {} {}",
+            blur,
+            normal,
+            sharp
+        );
+        assert!(blur - sharp > 20.0);
+        // 境界から離れた所は変わらない
+        assert_eq!((blur_far, sharp_far), (0.0, 0.0));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bus::Mem;
 use crate::cpu::{AddressingMode, CycleCalcMode, OpCode, CPU};
 
 lazy_static! {
@@ -263,7 +264,7 @@ lazy_static! {
 }
 
 
-pub fn call(cpu: &mut CPU, op: &OpCode) {
+pub fn call<M: Mem>(cpu: &mut CPU<M>, op: &OpCode) {
   match op.name.replace("*", "").as_str() {
 
     "ADC" => {
@@ -1,3 +1,21 @@
+// パレット
+//
+// 標準では SYSTEM_PALLETE を使う。main.rs では環境変数で差し替えられる
+//
+//   RSCOM_PALETTE=foo.pal        : .pal ファイル (192バイト or エンファシス込みの1536バイト)
+//
+// エンファシスはNTSCのPPUMASKの並び (bit0 = 赤, bit1 = 緑, bit2 = 青) で持つ。
+// PAL/Dendy のPPUは赤と緑のビットが逆なので、pixel_index() で並べ替える
+//   RSCOM_PALETTE=ntsc           : NTSC信号から色を計算する
+//   RSCOM_PALETTE=ntsc:hue=0,saturation=1.0,contrast=1.0,brightness=0.0
+use crate::ppu::MaskRegister;
+use crate::region::Region;
+use std::env;
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+use std::path::Path;
+
 #[rustfmt::skip]
 
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
@@ -15,3 +33,275 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
 ];
+
+// エンファシスを掛けた時に、強調されない色の成分を弱める割合
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// emphasis の各ビット (bit0 = 赤, bit1 = 緑, bit2 = 青) が、それ以外の2色を弱める
+pub fn emphasize(rgb: (u8, u8, u8), emphasis: u8) -> (u8, u8, u8) {
+    if emphasis == 0 {
+        return rgb;
+    }
+    let mut factor = [1.0f32; 3];
+    for bit in 0..3 {
+        if emphasis & (1 << bit) != 0 {
+            for (channel, f) in factor.iter_mut().enumerate() {
+                if channel != bit {
+                    *f *= EMPHASIS_ATTENUATION;
+                }
+            }
+        }
+    }
+    (
+        (rgb.0 as f32 * factor[0]) as u8,
+        (rgb.1 as f32 * factor[1]) as u8,
+        (rgb.2 as f32 * factor[2]) as u8,
+    )
+}
+
+// エンファシス8通り x 64色
+pub const PALETTE_SIZE: usize = 64 * 8;
+
+#[derive(Clone)]
+pub struct Palette {
+    // colors[emphasis * 64 + index]
+    pub colors: Vec<(u8, u8, u8)>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::from_base(&SYSTEM_PALLETE)
+    }
+}
+
+impl Palette {
+    // 64色からエンファシスの分を計算して作る
+    pub fn from_base(base: &[(u8, u8, u8); 64]) -> Self {
+        let mut colors = Vec::with_capacity(PALETTE_SIZE);
+        for emphasis in 0..8 {
+            colors.extend(base.iter().map(|rgb| emphasize(*rgb, emphasis)));
+        }
+        Palette { colors }
+    }
+
+    // 192バイト (64色) か 1536バイト (エンファシス込みの512色) の .pal ファイル
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let rgb = |chunk: &[u8]| (chunk[0], chunk[1], chunk[2]);
+        match bytes.len() {
+            192 => {
+                let mut base = [(0, 0, 0); 64];
+                for (c, chunk) in base.iter_mut().zip(bytes.chunks(3)) {
+                    *c = rgb(chunk);
+                }
+                Ok(Palette::from_base(&base))
+            }
+            1536 => Ok(Palette {
+                colors: bytes.chunks(3).map(rgb).collect(),
+            }),
+            n => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid .pal size {} (expected 192 or 1536 bytes)", n),
+            )),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Palette::from_bytes(&fs::read(path)?)
+    }
+
+    // RSCOM_PALETTE から作る (未指定なら標準のパレット)
+    pub fn from_env() -> io::Result<Self> {
+        match env::var("RSCOM_PALETTE") {
+            Ok(spec) => Palette::from_spec(&spec),
+            Err(_) => Ok(Palette::default()),
+        }
+    }
+
+    // "ntsc", "ntsc:hue=10,saturation=1.2" ならNTSCの計算、それ以外は .pal ファイルのパス
+    pub fn from_spec(spec: &str) -> io::Result<Self> {
+        let params = match spec.strip_prefix("ntsc") {
+            Some("") => "",
+            Some(params) if params.starts_with(':') => &params[1..],
+            _ => return Palette::load(spec),
+        };
+        let params = NtscPaletteParams::parse(params)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Palette::generate_ntsc(&params))
+    }
+
+    // NTSCのコンポジット信号を1周期 (12サンプル) 作り、YIQに復調してRGBにする
+    pub fn generate_ntsc(params: &NtscPaletteParams) -> Self {
+        let colors = (0..PALETTE_SIZE as u16)
+            .map(|pixel| {
+                let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+                for phase in 0..12 {
+                    let v = ntsc_signal(pixel, phase);
+                    let angle = ntsc_phase_angle(phase as f32, params.hue);
+                    y += v;
+                    i += v * angle.cos();
+                    q += v * angle.sin();
+                }
+                yiq_to_rgb(y / 12.0, i / 12.0 * 2.0, q / 12.0 * 2.0, params)
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    // パレットの値をPPUMASKのグレースケールとカラーエンファシスを反映したRGBにする
+    pub fn color(&self, index: u8, mask: &MaskRegister, region: Region) -> (u8, u8, u8) {
+        self.colors[pixel_index(index, mask, region) as usize]
+    }
+}
+
+// PPUMASKのエンファシスのビットをNTSCの並び (bit0 = 赤, bit1 = 緑) にする
+pub fn ntsc_emphasis(emphasis: u8, region: Region) -> u8 {
+    match region {
+        Region::NTSC => emphasis,
+        // bit0 = 緑, bit1 = 赤
+        Region::PAL | Region::DENDY => emphasis & 0b100 | (emphasis & 1) << 1 | (emphasis >> 1) & 1,
+    }
+}
+
+// グレースケールとエンファシスを反映した9ビットの値 (emphasis << 6 | index)
+pub fn pixel_index(index: u8, mask: &MaskRegister, region: Region) -> u16 {
+    let index = if mask.is_greyscale() { index & 0x30 } else { index & 0x3F };
+    (ntsc_emphasis(mask.emphasis(), region) as u16) << 6 | index as u16
+}
+
+// 9ビットのピクセル値が、色信号の位相 phase (0-11) で出す電圧 (黒=0.0, 白=1.0)
+pub fn ntsc_signal(pixel: u16, phase: usize) -> f32 {
+    // 信号の電圧 (輝度0-3の低い側, 高い側)。黒は0x0Dではなく0x1Dの低い側
+    const LEVELS: [[f32; 4]; 2] = [[0.350, 0.518, 0.962, 1.550], [1.094, 1.506, 1.962, 1.962]];
+    const BLACK: f32 = 0.518;
+    const WHITE: f32 = 1.962;
+    const ATTENUATION: f32 = 0.746;
+    // 色相cの信号が高い位相か
+    let in_phase = |color: usize| (color + phase) % 12 < 6;
+
+    let color = (pixel & 0x0F) as usize;
+    let emphasis = pixel >> 6;
+    // 0xE, 0xF は輝度によらず黒
+    let level = if color < 0x0E { (pixel >> 4) as usize & 3 } else { 1 };
+    let mut signal = match (color, in_phase(color)) {
+        (0x00, _) => LEVELS[1][level],
+        (0x0D..=0x0F, _) => LEVELS[0][level],
+        (_, true) => LEVELS[1][level],
+        (_, false) => LEVELS[0][level],
+    };
+    // エンファシスはそれぞれの色の位相で信号を弱める (赤=0xC, 緑=0x4, 青=0x8)
+    if (emphasis & 1 != 0 && in_phase(0x0C))
+        || (emphasis & 2 != 0 && in_phase(0x04))
+        || (emphasis & 4 != 0 && in_phase(0x08))
+    {
+        signal *= ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+// 位相 (サンプル単位) を復調に使う角度にする
+pub fn ntsc_phase_angle(phase: f32, hue: f32) -> f32 {
+    PI * (phase + 3.0) / 6.0 + hue.to_radians()
+}
+
+// 復調したYIQに調整を掛けてRGBにする
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, params: &NtscPaletteParams) -> (u8, u8, u8) {
+    let y = (y - 0.5) * params.contrast + 0.5 + params.brightness;
+    let i = i * params.saturation;
+    let q = q * params.saturation;
+    let to_u8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (
+        to_u8(y + 0.956 * i + 0.621 * q),
+        to_u8(y - 0.272 * i - 0.647 * q),
+        to_u8(y - 1.106 * i + 1.703 * q),
+    )
+}
+
+pub struct NtscPaletteParams {
+    // 色相のずれ (度)
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    // 輝度に足す値 (-1.0 - 1.0)
+    pub brightness: f32,
+}
+
+impl Default for NtscPaletteParams {
+    fn default() -> Self {
+        NtscPaletteParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+        }
+    }
+}
+
+impl NtscPaletteParams {
+    // "hue=10,saturation=1.2" (省略した値は標準のまま)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut params = NtscPaletteParams::default();
+        for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("invalid palette parameter '{}'", item))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid value '{}'", value))?;
+            match key.trim() {
+                "hue" => params.hue = value,
+                "saturation" | "sat" => params.saturation = value,
+                "contrast" => params.contrast = value,
+                "brightness" => params.brightness = value,
+                k => return Err(format!("unknown palette parameter '{}'", k)),
+            }
+        }
+        Ok(params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_file_size() {
+        let base: Vec<u8> = (0..192).map(|n| n as u8).collect();
+        let palette = Palette::from_bytes(&base).unwrap();
+        assert_eq!(palette.colors.len(), PALETTE_SIZE);
+        assert_eq!(palette.colors[1], (3, 4, 5));
+        // 赤のエンファシスは緑と青を弱める
+        assert_eq!(palette.colors[64 + 1], emphasize((3, 4, 5), 1));
+
+        let full: Vec<u8> = (0..1536).map(|n| (n / 3) as u8).collect();
+        let palette = Palette::from_bytes(&full).unwrap();
+        assert_eq!(palette.colors[64 * 7 + 2], ((64 * 7 + 2) as u8, (64 * 7 + 2) as u8, (64 * 7 + 2) as u8));
+
+        assert!(Palette::from_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_ntsc_emphasis() {
+        assert_eq!(ntsc_emphasis(0b001, Region::NTSC), 0b001);
+        assert_eq!(ntsc_emphasis(0b001, Region::PAL), 0b010);
+        assert_eq!(ntsc_emphasis(0b010, Region::DENDY), 0b001);
+        assert_eq!(ntsc_emphasis(0b101, Region::PAL), 0b110);
+        assert_eq!(ntsc_emphasis(0b111, Region::PAL), 0b111);
+    }
+
+    #[test]
+    fn test_generate_ntsc() {
+        let palette = Palette::generate_ntsc(&NtscPaletteParams::default());
+        let (r, g, b) = palette.colors[0x0F];
+        assert!(r < 0x10 && g < 0x10 && b < 0x10);
+        let (r, g, b) = palette.colors[0x30];
+        assert!(r > 0xF0 && g > 0xF0 && b > 0xF0);
+        // 0x16 は赤、0x12 は青、0x1A は緑
+        let (r, g, b) = palette.colors[0x16];
+        assert!(r > g && r > b);
+        let (r, g, b) = palette.colors[0x12];
+        assert!(b > r && b > g);
+        let (r, g, b) = palette.colors[0x1A];
+        assert!(g > r && g > b);
+    }
+}
@@ -1,12 +1,17 @@
 use bitflags::bitflags;
 use log::{debug, info, trace};
+use crate::bus::Access;
+use crate::mapper::MapperMMC;
+use crate::region::Region;
 use crate::MAPPER;
-use crate::{cpu::IN_TRACE, rom::Mirroring};
+use crate::rom::Mirroring;
 
+// I/Oラッチの値が消えるまでの時間 (実機では600ms前後。NTSCの約36フレーム)
+const IO_LATCH_DECAY_DOTS: u64 = 341 * 262 * 36;
+
+// パターンテーブル ($0000-$1FFF) は全てマッパー (MAPPER) を通して読み書きする
 pub struct PPU {
-    pub chr_rom: Vec<u8>,
     pub mirroring: Mirroring,
-    pub is_chr_ram: bool,
 
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
@@ -29,6 +34,8 @@ pub struct PPU {
 
     cycles: usize,
     scanline: usize,
+    // 1フレームのスキャンライン数とVBlankの開始位置が変わる
+    region: Region,
     pub nmi_interrupt: Option<i32>,
     pub clear_nmi_interrupt: bool,
 
@@ -37,14 +44,39 @@ pub struct PPU {
     // レンダリング時に、その履歴を参照して描画することで実現。
     pub scanline_palette_indexes: Vec<usize>,
     pub scanline_palette_tables: Vec<[u8; 32]>,
+    // PPUMASKも描画中に書き換えられるので同様に (スキャンライン, 値) の履歴を持つ
+    pub scanline_masks: Vec<(usize, u8)>,
+
+    // スプライトの8個制限を外す (ちらつき防止。SPRITE_OVERFLOWは実機通りに立てる)
+    pub remove_sprite_limit: bool,
+    // I/Oラッチと、各ビットが最後に更新された時のドット数
+    io_latch: u8,
+    io_latch_refreshed: [u64; 8],
+    // 電源投入からのドット数
+    total_dots: u64,
+    // 奇数フレームか (NTSCで描画中ならプリレンダーラインが1ドット短い)
+    odd_frame: bool,
+    // ドット0で$2002が読まれたので、このフレームはVBlankフラグを立てない
+    suppress_vblank: bool,
+    // VBlankに入った (take_frame_ready()でクリア)
+    frame_ready: bool,
+    // このスキャンラインのスプライト評価 (SPRITE_OVERFLOWの判定) が済んだか
+    sprite_evaluated: bool,
+    // このスキャンラインでスプライト0ヒットが起きるドット (ラインの最初のtickで求める)
+    sprite_zero_hit_dot: Option<Option<usize>>,
+
+    // Some の間は$2007経由のアクセスを (アドレス, 値, 種類) で記録する (デバッガ用)
+    pub access_log: Option<Vec<(u16, u8, Access)>>,
+
+    // Some の間は、そのスキャンラインの始めで snapshot を取る (ビューア用)
+    pub snapshot_scanline: Option<usize>,
+    pub snapshot: Option<PpuSnapshot>,
 }
 
 impl PPU {
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring, is_chr_ram: bool) -> Self {
+    pub fn new(mirroring: Mirroring) -> Self {
         PPU {
-            chr_rom: chr_rom,
             mirroring: mirroring,
-            is_chr_ram: is_chr_ram,
             vram: [0; 2048],
             oam_data: [0; 64 * 4],
             oam_addr: 0,
@@ -57,63 +89,104 @@ impl PPU {
             internal_data_buf: 0,
             cycles: 0,
             scanline: 0,
+            region: Region::NTSC,
             nmi_interrupt: None,
             clear_nmi_interrupt: false,
             scanline_palette_indexes: vec![],
             scanline_palette_tables: vec![],
+            scanline_masks: vec![],
+            remove_sprite_limit: false,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            total_dots: 0,
+            odd_frame: false,
+            suppress_vblank: false,
+            frame_ready: false,
+            sprite_evaluated: false,
+            sprite_zero_hit_dot: None,
+            access_log: None,
+            snapshot_scanline: None,
+            snapshot: None,
         }
     }
 
 
     pub fn read_data(&mut self) -> u8 {
         let addr = self.addr.get();
-        if !unsafe { IN_TRACE } {
-            self.increment_vram_addr();
-        }
+        self.increment_vram_addr();
         debug!("READ PPU: {:04X}", addr);
+        if let Some(mut log) = self.access_log.take() {
+            log.push((addr, self.peek(addr), Access::Read));
+            self.access_log = Some(log);
+        }
 
-        match addr {
+        let value = match addr {
             0..=0x1FFF => {
-                if unsafe { IN_TRACE } {
-                    self.internal_data_buf
-                } else {
-                    let result = self.internal_data_buf;
-                    let mapper = MAPPER.lock().unwrap().mapper;
-                    match mapper {
-                        3 | 4 => self.internal_data_buf = MAPPER.lock().unwrap().read_chr_rom(addr),
-                        _ => self.internal_data_buf = self.chr_rom[addr as usize],
-                    }
-                    result
-                }
+                let result = self.internal_data_buf;
+                self.internal_data_buf = MAPPER.lock().unwrap().read_chr(addr);
+                result
             }
             0x2000..=0x2FFF => {
-                if unsafe { IN_TRACE } {
-                    self.internal_data_buf
-                } else {
-                    let result = self.internal_data_buf;
-                    self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
-                    result
-                }
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                result
             }
             0x3000..=0x3EFF => {
-                if unsafe { IN_TRACE } {
-                    self.internal_data_buf
-                } else {
-                    let result = self.internal_data_buf;
-                    self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
-                    result
-                }
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                result
             }
             0x3F00..=0x3FFF => {
-                if unsafe { IN_TRACE } {
-                    self.internal_data_buf
-                } else {
-                    self.internal_data_buf =
-                        self.palette_table[self.mirror_palette_addr(addr) as usize];
-                    self.internal_data_buf
-                }
+                // パレットはバッファを通さずに返り、バッファには裏にあるネームテーブルが入る
+                // パレットは6ビットなので、上位2ビットはI/Oラッチの値になる
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                let value = self.palette_table[self.mirror_palette_addr(addr) as usize] & 0x3F;
+                self.refresh_io_latch(value, 0x3F);
+                return self.io_latch();
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
+        };
+        self.refresh_io_latch(value, 0xFF);
+        value
+    }
+
+    // $2007を読んだ時に返る値 (アドレスのインクリメントやバッファ更新はしない)
+    pub fn peek_data(&self) -> u8 {
+        let addr = self.addr.get();
+        match addr {
+            0x3F00..=0x3FFF => {
+                let value = self.palette_table[self.mirror_palette_addr(addr) as usize] & 0x3F;
+                self.io_latch() & 0xC0 | value
+            }
+            _ => self.internal_data_buf,
+        }
+    }
+
+    // レジスタに書き込んだ値や読み出した値が残るI/Oラッチ ($2000等の書き込み専用レジスタを読むとこれが返る)
+    // 更新されなかったビットは時間が経つと0に戻る
+    pub fn io_latch(&self) -> u8 {
+        (0..8)
+            .filter(|bit| self.total_dots - self.io_latch_refreshed[*bit] < IO_LATCH_DECAY_DOTS)
+            .fold(0, |value, bit| value | (self.io_latch & (1 << bit)))
+    }
+
+    // I/Oラッチの mask のビットを value で更新する
+    pub fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = self.io_latch & !mask | value & mask;
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refreshed[bit] = self.total_dots;
+            }
+        }
+    }
+
+    // PPUアドレス空間($0000-$3FFF)の内容を副作用なしで読む (トレース、デバッガ、メモリビューア用)
+    pub fn peek(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0..=0x1FFF => MAPPER.lock().unwrap().read_chr(addr),
+            0x2000..=0x3EFF => self.vram[self.mirror_vram_addr(addr) as usize],
+            _ => self.palette_table[self.mirror_palette_addr(addr) as usize],
         }
     }
 
@@ -123,22 +196,14 @@ impl PPU {
 
     pub fn write_to_data(&mut self, value: u8) {
         let addr = self.addr.get();
-        if !unsafe { IN_TRACE } {
-            self.increment_vram_addr();
-        }
+        self.increment_vram_addr();
         debug!("WRITE PPU: {:04X} => {:02X}", addr, value);
+        if let Some(log) = &mut self.access_log {
+            log.push((addr, value, Access::Write));
+        }
 
         match addr {
-            0x0000..=0x1FFF => {
-                let mapper = MAPPER.lock().unwrap().mapper;
-                match mapper {
-                    3 | 4 => { MAPPER.lock().unwrap().write(addr, value); },
-                    _ => { if self.is_chr_ram {
-                            self.chr_rom[addr as usize] = value;
-                        }
-                    },
-                }
-            }
+            0x0000..=0x1FFF => MAPPER.lock().unwrap().write_chr(addr, value),
             0x2000..=0x2FFF => {
                 trace!(
                     "WRITE PPU_VRAM {:04X} {:02X} => ({:02X})",
@@ -203,6 +268,8 @@ impl PPU {
     fn clear_palette_table_histories(&mut self) {
         self.scanline_palette_indexes = vec![];
         self.scanline_palette_tables = vec![];
+        // フレームの最初の値として今の値を残しておく
+        self.scanline_masks = vec![(0, self.mask.bits())];
     }
 
     pub fn read_palette_table(&self, scanline: usize) -> &[u8; 32] {
@@ -236,9 +303,14 @@ impl PPU {
     pub fn write_to_ctrl(&mut self, value: u8) {
         let before_nmi_status = self.ctrl.generate_vblank_nmi();
         self.ctrl.update(value);
+        // VBlank中にNMIを有効にすると、その場でNMIが起きる
         if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
+        // VBlankフラグが立った直後に無効にすると、そのNMIは起きない
+        if before_nmi_status && !self.ctrl.generate_vblank_nmi() {
+            self.nmi_interrupt = None;
+        }
     }
 
     pub fn read_ctrl(&self) -> u8 {
@@ -251,15 +323,26 @@ impl PPU {
 
     pub fn read_status(&mut self) -> u8 {
         // スクロール ($2005)  PPUSTATUSを読み取ってアドレス ラッチをリセットした後
-        if unsafe { IN_TRACE } {
-            self.status.bits()
-        } else {
-            self.scroll.reset();
-            let bits = self.status.bits();
-            self.status.reset_vblank_status();
-            self.clear_nmi_interrupt = true;
-            bits
+        self.scroll.reset();
+        // VBlankフラグが立つ直前 (ドット0) に読むと、このフレームはフラグもNMIも起きない
+        // 立った直後 (ドット1, 2) に読むとフラグは見えるがNMIは起きない
+        if self.scanline == self.region.vblank_scanline() {
+            match self.cycles {
+                0 => self.suppress_vblank = true,
+                1 | 2 => self.clear_nmi_interrupt = true,
+                _ => {}
+            }
         }
+        let bits = self.peek_status();
+        self.refresh_io_latch(bits, 0xE0);
+        self.status.reset_vblank_status();
+        bits
+    }
+
+    // $2002を読んだ時に返る値 (VBlankフラグのクリア等はしない)
+    // 下位5ビット (PPU_OPEN_BUS1～5) はI/Oラッチの値
+    pub fn peek_status(&self) -> u8 {
+        self.status.bits() & 0xE0 | self.io_latch() & 0x1F
     }
 
     pub fn write_to_status(&mut self, value: u8) {
@@ -268,6 +351,24 @@ impl PPU {
 
     pub fn write_to_mask(&mut self, value: u8) {
         self.mask.update(value);
+
+        let scanline = self.scanline;
+        match self.scanline_masks.last_mut() {
+            Some((last, mask)) if *last == scanline => *mask = value,
+            _ => self.scanline_masks.push((scanline, value)),
+        }
+    }
+
+    // scanline を描画していた時のPPUMASK
+    pub fn read_mask_at(&self, scanline: usize) -> MaskRegister {
+        let bits = self
+            .scanline_masks
+            .iter()
+            .take_while(|(s, _)| *s <= scanline)
+            .last()
+            .map(|(_, mask)| *mask)
+            .unwrap_or(self.mask.bits());
+        MaskRegister::from_bits_truncate(bits)
     }
 
     pub fn write_to_oam_addr(&mut self, value: u8) {
@@ -280,8 +381,20 @@ impl PPU {
         self.oam_addr = self.oam_addr.wrapping_add(1)
     }
 
-    pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+    pub fn read_oam_data(&mut self) -> u8 {
+        let value = self.peek_oam_data();
+        self.refresh_io_latch(value, 0xFF);
+        value
+    }
+
+    // 属性バイト (4バイト目毎の3番目) のビット2～4は存在しないので0で読める
+    pub fn peek_oam_data(&self) -> u8 {
+        let value = self.oam_data[self.oam_addr as usize];
+        if self.oam_addr % 4 == 2 {
+            value & 0xE3
+        } else {
+            value
+        }
     }
 
     pub fn write_to_oam_dma(&mut self, values: [u8; 256]) {
@@ -312,46 +425,301 @@ impl PPU {
         }
     }
 
+    pub fn scanline(&self) -> usize {
+        self.scanline
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    // スキャンライン内のドット位置 (0～340)
+    pub fn dot(&self) -> usize {
+        self.cycles
+    }
+
+    // cycles ドット進める。フレームの最後 (スキャンライン0に戻った) ならtrue
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_zero_hit(self.cycles) {
-                self.status.set_sprite_zero_hit(true);
+        let mut new_frame = false;
+        for _ in 0..cycles {
+            new_frame |= self.step_dot();
+        }
+        new_frame
+    }
+
+    fn step_dot(&mut self) -> bool {
+        self.cycles += 1;
+        self.total_dots += 1;
+
+        // スプライト評価はドット65～256 (次のスキャンライン用のセカンダリOAMを作る)
+        if self.scanline < 240 && self.cycles > 256 && !self.sprite_evaluated {
+            self.sprite_evaluated = true;
+            if self.is_rendering_enabled() && self.evaluate_sprites(self.scanline).overflow {
+                self.status.set_sprite_overflow(true);
             }
+        }
 
-            self.cycles = self.cycles - 341;
-            self.scanline += 1;
+        if self.scanline < 240 && !self.status.contains(StatusRegister::SPRITE_ZERO_HIT) {
+            let dot = match self.sprite_zero_hit_dot {
+                Some(dot) => dot,
+                None => {
+                    let dot = self.find_sprite_zero_hit(self.scanline);
+                    self.sprite_zero_hit_dot = Some(dot);
+                    dot
+                }
+            };
+            if dot.map(|dot| self.cycles >= dot).unwrap_or(false) {
+                self.status.set_sprite_zero_hit(true);
+            }
+        }
 
-            if self.scanline == 241 {
+        // VBlankフラグはドット1で立つ (直前に$2002を読まれていたら立たない)
+        if self.scanline == self.region.vblank_scanline() && self.cycles == 1 {
+            if !self.suppress_vblank {
                 self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
                 if self.ctrl.generate_vblank_nmi() {
-                    // self.status.set_vblank_status(true);
                     self.nmi_interrupt = Some(1);
                 }
             }
+            self.suppress_vblank = false;
+            self.frame_ready = true;
+        }
 
-            if self.scanline >= 262 {
+        // プリレンダーラインのドット1でVBlank、スプライト0ヒットとオーバーフローをクリア
+        let pre_render_line = self.region.scanlines() - 1;
+        if self.scanline == pre_render_line && self.cycles == 1 {
+            self.status.reset_vblank_status();
+            self.status.set_sprite_zero_hit(false);
+            self.status.set_sprite_overflow(false);
+        }
+
+        // NTSCでは描画中の奇数フレームはプリレンダーラインの最後のドットを飛ばす
+        let line_length = if self.scanline == pre_render_line
+            && self.odd_frame
+            && self.region == Region::NTSC
+            && self.is_rendering_enabled()
+        {
+            340
+        } else {
+            341
+        };
+
+        if self.cycles >= line_length {
+            self.cycles = 0;
+            self.scanline += 1;
+            self.sprite_evaluated = false;
+            self.sprite_zero_hit_dot = None;
+
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
-                self.status.set_sprite_zero_hit(false);
-                self.status.reset_vblank_status();
+                self.odd_frame = !self.odd_frame;
                 self.nmi_interrupt = None;
                 self.clear_palette_table_histories();
+                self.take_snapshot();
                 return true;
             }
+            self.take_snapshot();
 
             if self.scanline == 257 {
                 // OAMADDR は、プリレンダリングおよび表示可能なスキャンラインのティック 257 ～ 320 (スプライト タイルの読み込み間隔) のそれぞれの間に 0 に設定されます。
                 self.oam_addr = 0;
             }
         }
-        return false;
+        false
+    }
+
+    fn take_snapshot(&mut self) {
+        if self.snapshot_scanline == Some(self.scanline) {
+            let snapshot = PpuSnapshot::capture(self, &MAPPER.lock().unwrap());
+            self.snapshot = Some(snapshot);
+        }
+    }
+
+    // VBlankに入った (フレームを描画するタイミング) かを返してクリアする
+    // NMIが無効でも、$2002の読み込みでVBlankフラグが立たなくても、毎フレーム1回trueになる
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    pub fn sprite_height(&self) -> usize {
+        if self.ctrl.is_sprite_8x16_mode() {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn is_rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    // scanline で評価したスプライト (表示されるのは次のスキャンライン = OAMのY+1から)
+    // indices は範囲内の全スプライトをOAM順に。先頭8個が実機のセカンダリOAMに入る
+    pub fn evaluate_sprites(&self, scanline: usize) -> SpriteEvaluation {
+        let height = self.sprite_height();
+        let in_range = |y: u8| scanline.wrapping_sub(y as usize) < height;
+
+        let indices: Vec<usize> = (0..64)
+            .filter(|n| in_range(self.oam_data[n * 4]))
+            .collect();
+
+        // 8個見つかった後の続きの評価。実機はここでスプライト番号nと一緒に
+        // バイト位置mも進めてしまうので、Y以外のバイトをYとして比較する (オーバーフローのバグ)
+        let mut overflow = false;
+        if indices.len() >= 8 {
+            let mut n = indices[7] + 1;
+            let mut m = 0;
+            while n < 64 {
+                if in_range(self.oam_data[n * 4 + m]) {
+                    overflow = true;
+                    break;
+                }
+                n += 1;
+                m = (m + 1) & 3;
+            }
+        }
+
+        SpriteEvaluation { indices, overflow }
+    }
+
+    // スプライトのrow行目 (8x16なら0～15) のパターン (下位プレーン, 上位プレーン)
+    // 8x16の時はタイル番号のbit0でパターンテーブルを選び、偶数タイルが上半分、次のタイルが下半分
+    pub fn sprite_tile_row(&self, mapper: &MapperMMC, tile_idx: u8, row: usize) -> (u8, u8) {
+        let (bank, tile) = if self.ctrl.is_sprite_8x16_mode() {
+            let bank: u16 = if tile_idx & 1 == 0 { 0x0000 } else { 0x1000 };
+            (bank, (tile_idx & 0xFE) as u16 + (row / 8) as u16)
+        } else {
+            (self.ctrl.sprite_pattern_addr(), tile_idx as u16)
+        };
+        let addr = bank + tile * 16 + (row % 8) as u16;
+        (mapper.read_chr(addr), mapper.read_chr(addr + 8))
     }
 
-    fn is_sprite_zero_hit(&self, cycle: usize) -> bool {
-        let y = self.oam_data[0] as usize;
-        let x = self.oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.mask.show_sprites()
+    // n番目のスプライトの、画面上の(x, y)のピクセルの色番号 (0なら透明か範囲外)
+    fn sprite_pixel(&self, mapper: &MapperMMC, n: usize, x: usize, y: usize) -> u8 {
+        let tile_y = self.oam_data[n * 4] as usize;
+        let tile_idx = self.oam_data[n * 4 + 1];
+        let attr = self.oam_data[n * 4 + 2];
+        let tile_x = self.oam_data[n * 4 + 3] as usize;
+
+        let height = self.sprite_height();
+        let row = y.wrapping_sub(tile_y + 1);
+        let col = x.wrapping_sub(tile_x);
+        if row >= height || col >= 8 {
+            return 0;
+        }
+        let row = if attr & 0x80 != 0 { height - 1 - row } else { row };
+        let col = if attr & 0x40 != 0 { 7 - col } else { col };
+        let (lo, hi) = self.sprite_tile_row(mapper, tile_idx, row);
+        ((hi >> (7 - col)) & 1) << 1 | ((lo >> (7 - col)) & 1)
+    }
+
+    // 画面上の(x, y)の背景のピクセルの色番号 (スクロールとネームテーブルの選択を反映)
+    fn background_pixel(&self, mapper: &MapperMMC, x: usize, y: usize) -> u8 {
+        let base = self.ctrl.nametable_addr() as usize - 0x2000;
+        let world_x = (x + self.scroll.scroll_x as usize + (base & 0x400) / 0x400 * 256) % 512;
+        let world_y = (y + self.scroll.scroll_y as usize + (base & 0x800) / 0x800 * 240) % 480;
+        let name_table = (world_x / 256) + (world_y / 240) * 2;
+        let (x, y) = (world_x % 256, world_y % 240);
+
+        let addr = 0x2000 + name_table * 0x400 + (y / 8) * 32 + x / 8;
+        let tile_idx = self.vram[self.mirror_vram_addr(addr as u16) as usize] as u16;
+        let addr = self.ctrl.background_pattern_addr() + tile_idx * 16 + (y % 8) as u16;
+        let (lo, hi) = (mapper.read_chr(addr), mapper.read_chr(addr + 8));
+        ((hi >> (7 - x % 8)) & 1) << 1 | ((lo >> (7 - x % 8)) & 1)
+    }
+
+    // scanline でスプライト0ヒットが起きるドット
+    // スプライト0と背景の不透明なピクセルが重なった所 (優先順位は関係ない)
+    // 背景・スプライトのどちらかが非表示なら起きない。左端8ピクセルのクリップ中とx=255では起きない
+    fn find_sprite_zero_hit(&self, scanline: usize) -> Option<usize> {
+        if !self.mask.show_background() || !self.mask.show_sprites() {
+            return None;
+        }
+        let left = if self.mask.contains(MaskRegister::SHOW_BACKGROUND_IN_LEFT)
+            && self.mask.contains(MaskRegister::SHOW_SPRITES_IN_LEFT)
+        {
+            0
+        } else {
+            8
+        };
+        let tile_x = self.oam_data[3] as usize;
+        let mapper = MAPPER.lock().unwrap();
+        (tile_x.max(left)..(tile_x + 8).min(255))
+            .find(|x| {
+                self.sprite_pixel(&mapper, 0, *x, scanline) != 0
+                    && self.background_pixel(&mapper, *x, scanline) != 0
+            })
+            // x のピクセルを出力するのはドット x+1
+            .map(|x| x + 1)
+    }
+}
+
+pub struct SpriteEvaluation {
+    pub indices: Vec<usize>,
+    pub overflow: bool,
+}
+
+// あるスキャンラインでのPPUの状態 (ビューア用)
+#[derive(Clone)]
+pub struct PpuSnapshot {
+    pub scanline: usize,
+    // $2000, $2400, $2800, $2C00 (ミラーリング済み)
+    pub nametables: Vec<[u8; 0x400]>,
+    // その時点でマッパーが見せていたパターンテーブル
+    pub chr: Vec<u8>,
+    pub oam: [u8; 256],
+    pub palette: [u8; 32],
+    pub ctrl: u8,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+}
+
+impl PpuSnapshot {
+    pub fn capture(ppu: &PPU, mapper: &MapperMMC) -> Self {
+        let nametables = (0..4)
+            .map(|n| {
+                std::array::from_fn(|i| {
+                    let addr = 0x2000 + n * 0x400 + i as u16;
+                    ppu.vram[ppu.mirror_vram_addr(addr) as usize]
+                })
+            })
+            .collect();
+        PpuSnapshot {
+            scanline: ppu.scanline(),
+            nametables,
+            chr: (0..0x2000).map(|addr| mapper.read_chr(addr)).collect(),
+            oam: ppu.oam_data,
+            palette: ppu.palette_table,
+            ctrl: ppu.read_ctrl(),
+            scroll_x: ppu.scroll.scroll_x,
+            scroll_y: ppu.scroll.scroll_y,
+        }
+    }
+
+    pub fn ctrl(&self) -> ControlRegister {
+        ControlRegister::from_bits_truncate(self.ctrl)
+    }
+
+    // パレットRAMのミラー ($3F10 等は $3F00 等と同じ)
+    pub fn palette_entry(&self, index: usize) -> u8 {
+        let index = match index {
+            0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+            _ => index,
+        };
+        self.palette[index] & 0x3F
+    }
+
+    // パターンテーブルのタイルの (row, col) の色番号 (0～3)
+    pub fn tile_pixel(&self, addr: u16, row: usize, col: usize) -> u8 {
+        let lo = self.chr[(addr as usize + row) % self.chr.len()];
+        let hi = self.chr[(addr as usize + row + 8) % self.chr.len()];
+        let bit = 7 - col;
+        ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1)
     }
 }
 
@@ -509,6 +877,10 @@ impl StatusRegister {
         self.set(StatusRegister::SPRITE_ZERO_HIT, value)
     }
 
+    pub fn set_sprite_overflow(&mut self, value: bool) {
+        self.set(StatusRegister::SPRITE_OVERFLOW, value)
+    }
+
     pub fn update(&mut self, data: u8) {
         *self.0.bits_mut() = data;
     }
@@ -532,10 +904,31 @@ impl MaskRegister {
         MaskRegister::from_bits_truncate(0b0000_0000)
     }
 
+    pub fn is_greyscale(&self) -> bool {
+        self.contains(MaskRegister::GREYSCALE)
+    }
+
+    pub fn show_background_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND_IN_LEFT)
+    }
+
+    pub fn show_sprites_in_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_SPRITES_IN_LEFT)
+    }
+
+    pub fn show_background(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND)
+    }
+
     pub fn show_sprites(&self) -> bool {
         self.contains(MaskRegister::SHOW_SPRITES)
     }
 
+    // カラーエンファシス (bit0 = 赤, bit1 = 緑, bit2 = 青。PAL/Dendyは赤と緑が逆)
+    pub fn emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
+
     pub fn update(&mut self, data: u8) {
         *self.0.bits_mut() = data;
     }
@@ -569,3 +962,250 @@ impl ScrollRegister {
         self.write_x = true;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lock_mapper_for_test;
+
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.oam_data = [0xF0; 256];
+        for n in 0..8 {
+            ppu.oam_data[n * 4] = 10;
+        }
+
+        // 9個目がラインにかかっているのに、Y以外のバイトと比較してしまい見逃す
+        ppu.oam_data[9 * 4] = 10;
+        ppu.oam_data[9 * 4 + 1] = 0;
+        let evaluation = ppu.evaluate_sprites(12);
+        assert_eq!(evaluation.indices.len(), 9);
+        assert!(!evaluation.overflow);
+
+        // 逆にラインにかからないスプライトのタイル番号で誤検出する
+        ppu.oam_data[9 * 4] = 0xF0;
+        ppu.oam_data[9 * 4 + 1] = 10;
+        let evaluation = ppu.evaluate_sprites(12);
+        assert_eq!(evaluation.indices.len(), 8);
+        assert!(evaluation.overflow);
+
+        // 8個続いた直後はYを正しく見る
+        ppu.oam_data[8 * 4] = 5;
+        assert!(ppu.evaluate_sprites(12).overflow);
+    }
+
+    // VBlankに入るスキャンラインと、1フレームのスキャンライン数
+    fn vblank_and_frame_lines(region: Region) -> (usize, usize) {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.set_region(region);
+        let mut vblank = None;
+        let mut last_line = 0;
+        loop {
+            last_line = last_line.max(ppu.scanline());
+            if ppu.tick(1) {
+                return (vblank.unwrap(), last_line + 1);
+            }
+            if vblank.is_none() && ppu.peek_status() & 0x80 != 0 {
+                vblank = Some(ppu.scanline());
+            }
+        }
+    }
+
+    #[test]
+    fn test_region_timing() {
+        assert_eq!(vblank_and_frame_lines(Region::NTSC), (241, 262));
+        assert_eq!(vblank_and_frame_lines(Region::PAL), (241, 312));
+        assert_eq!(vblank_and_frame_lines(Region::DENDY), (291, 312));
+    }
+
+    // 1フレームのドット数
+    fn frame_dots(ppu: &mut PPU) -> usize {
+        let mut dots = 1;
+        while !ppu.tick(1) {
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_odd_frame_dot_skip() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        // 描画していなければ毎フレーム同じ
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+
+        ppu.write_to_mask(0b0000_1000);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262 - 1);
+        assert_eq!(frame_dots(&mut ppu), 341 * 262);
+    }
+
+    // スキャンライン241のドットdotで$2002を読んだ時の (読んだ値のbit7, NMIが起きたか)
+    fn read_status_at_vblank(dot: usize) -> (bool, bool) {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.write_to_ctrl(0x80);
+        while ppu.scanline() != 241 || ppu.dot() != dot {
+            ppu.tick(1);
+        }
+        let status = ppu.read_status();
+        if ppu.clear_nmi_interrupt {
+            ppu.nmi_interrupt = None;
+        }
+        ppu.tick(3);
+        (status & 0x80 != 0, ppu.nmi_interrupt.is_some())
+    }
+
+    #[test]
+    fn test_vblank_suppression() {
+        assert_eq!(read_status_at_vblank(0), (false, false));
+        assert_eq!(read_status_at_vblank(1), (true, false));
+        assert_eq!(read_status_at_vblank(2), (true, false));
+        assert_eq!(read_status_at_vblank(3), (true, true));
+    }
+
+    #[test]
+    fn test_nmi_enabled_in_vblank() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        while ppu.scanline() != 250 {
+            ppu.tick(1);
+        }
+        assert!(ppu.nmi_interrupt.is_none());
+        ppu.write_to_ctrl(0x80);
+        assert!(ppu.nmi_interrupt.is_some());
+    }
+
+    #[test]
+    fn test_io_latch() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.refresh_io_latch(0xFF, 0xFF);
+        assert_eq!(ppu.peek_status() & 0x1F, 0x1F);
+        assert_eq!(ppu.io_latch(), 0xFF);
+
+        // $2002は上位3ビットだけ更新する
+        ppu.tick(200);
+        ppu.read_status();
+        assert_eq!(ppu.io_latch(), 0x1F);
+        // 更新されないビットは時間が経つと消える
+        for _ in 0..IO_LATCH_DECAY_DOTS - 100 {
+            ppu.tick(1);
+        }
+        assert_eq!(ppu.io_latch(), 0x00);
+        ppu.refresh_io_latch(0xA5, 0xFF);
+        assert_eq!(ppu.io_latch(), 0xA5);
+    }
+
+    #[test]
+    fn test_palette_read_buffer() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.palette_table[0] = 0x0F;
+        let nametable = ppu.mirror_vram_addr(0x2F00) as usize;
+        ppu.vram[nametable] = 0x55;
+        ppu.refresh_io_latch(0xC0, 0xFF);
+
+        // パレットはすぐに返り (上位2ビットはI/Oラッチ)、バッファには裏のネームテーブル
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0xCF);
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x00);
+        assert_eq!(ppu.read_data(), 0x55);
+    }
+
+    #[test]
+    fn test_oam_attribute_bits() {
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.oam_data = [0xFF; 256];
+        ppu.write_to_oam_addr(2);
+        assert_eq!(ppu.read_oam_data(), 0xE3);
+        ppu.write_to_oam_addr(1);
+        assert_eq!(ppu.read_oam_data(), 0xFF);
+    }
+
+    // MAPPERをNROMのCHR-ROMにする (lock_mapper_for_test を取ってから呼ぶ)
+    fn set_chr_rom(chr: Vec<u8>) {
+        let mut mapper = MAPPER.lock().unwrap();
+        **mapper = MapperMMC::new();
+        mapper.chr_rom = chr;
+    }
+
+    // タイル1が全面不透明、ネームテーブルも全部タイル1
+    fn sprite_zero_ppu(x: u8, mask: u8) -> PPU {
+        let mut chr = vec![0; 0x2000];
+        for i in 16..24 {
+            chr[i] = 0xFF;
+        }
+        set_chr_rom(chr);
+
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.vram = [1; 2048];
+        ppu.oam_data = [0xF0; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[9, 1, 0, x]);
+        ppu.write_to_mask(mask);
+        ppu
+    }
+
+    // 最初にスプライト0ヒットが立ったドット
+    fn sprite_zero_hit_dot(ppu: &mut PPU) -> Option<(usize, usize)> {
+        for _ in 0..(341 * 262) {
+            ppu.tick(1);
+            if ppu.peek_status() & 0x40 != 0 {
+                return Some((ppu.scanline(), ppu.dot()));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let _lock = lock_mapper_for_test();
+        // Y+1のラインの x+1 ドット
+        let mut ppu = sprite_zero_ppu(20, 0b0001_1110);
+        assert_eq!(sprite_zero_hit_dot(&mut ppu), Some((10, 21)));
+
+        // 左端8ピクセルのクリップ
+        let mut ppu = sprite_zero_ppu(4, 0b0001_1010);
+        assert_eq!(sprite_zero_hit_dot(&mut ppu), Some((10, 9)));
+
+        // x=255 では起きない
+        let mut ppu = sprite_zero_ppu(255, 0b0001_1110);
+        assert_eq!(sprite_zero_hit_dot(&mut ppu), None);
+
+        // 背景が非表示
+        let mut ppu = sprite_zero_ppu(20, 0b0001_0110);
+        assert_eq!(sprite_zero_hit_dot(&mut ppu), None);
+
+        // 背景が透明
+        let mut ppu = sprite_zero_ppu(20, 0b0001_1110);
+        ppu.vram = [0; 2048];
+        assert_eq!(sprite_zero_hit_dot(&mut ppu), None);
+    }
+
+    #[test]
+    fn test_chr_ram_write() {
+        let _lock = lock_mapper_for_test();
+        {
+            let mut mapper = MAPPER.lock().unwrap();
+            **mapper = MapperMMC::new();
+            mapper.is_chr_ram = true;
+        }
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.write_to_ppu_addr(0x12);
+        ppu.write_to_ppu_addr(0x34);
+        ppu.write_to_data(0xA5);
+        assert_eq!(MAPPER.lock().unwrap().chr_ram[0x1234], 0xA5);
+
+        // 読み込みは1回遅れる
+        ppu.write_to_ppu_addr(0x12);
+        ppu.write_to_ppu_addr(0x34);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0xA5);
+
+        // CHR-ROMへの書き込みは無視
+        set_chr_rom(vec![0; 0x2000]);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0xFF);
+        assert_eq!(MAPPER.lock().unwrap().read_chr(0x0010), 0);
+    }
+}
@@ -0,0 +1,195 @@
+// 地域 (テレビ方式) ごとのタイミング
+//
+// ROMのヘッダ (NES 2.0 の CPU/PPU Timing、iNES の TV system) から決め、
+// 分からなければ地域のデータベース、それにも無ければファイル名の "(E)" "(Europe)" 等で判断する
+// main.rs では環境変数で強制できる
+//
+//   RSCOM_REGION=pal       : ntsc, pal, dendy, auto (省略時は auto)
+//   RSCOM_REGION_DB=db.txt : 地域のデータベース
+//
+// データベースは1行に1つ、ヘッダ (16バイト) を除いたROMのCRC32 (No-Intro と同じ) と地域を書く
+//
+//   # Game (Europe)
+//   0123ABCD pal
+use std::env;
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    // ロシア等のファミコン互換機 (PALのフレームにNTSC寄りのCPU/APU)
+    DENDY,
+}
+
+impl Region {
+    // ヘッダに書かれていれば Some
+    pub fn from_header(raw: &[u8]) -> Option<Region> {
+        if raw.len() < 16 {
+            return None;
+        }
+        if raw[7] & 0x0C == 0x08 {
+            // NES 2.0: 0=NTSC, 1=PAL, 2=両対応, 3=Dendy
+            return match raw[12] & 0b11 {
+                1 => Some(Region::PAL),
+                3 => Some(Region::DENDY),
+                _ => Some(Region::NTSC),
+            };
+        }
+        // iNES: バイト11～15にゴミが入っている古いヘッダは信用しない
+        if raw[9] & 1 != 0 && raw[11..16].iter().all(|b| *b == 0) {
+            return Some(Region::PAL);
+        }
+        None
+    }
+
+    // RSCOM_REGION_DB が指定されていて、ROM (ヘッダ込み) が載っていれば Some
+    pub fn from_database(raw: &[u8]) -> Result<Option<Region>, String> {
+        let path = match env::var("RSCOM_REGION_DB") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let db = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let crc = crc32fast::hash(raw.get(16..).unwrap_or(&[]));
+        Region::lookup(&db, crc).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // データベースの中身から CRC32 で引く
+    pub fn lookup(db: &str, crc: u32) -> Result<Option<Region>, String> {
+        for (n, line) in db.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid entry '{}'", n + 1, line);
+            let mut fields = line.split_whitespace();
+            let (key, region) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(key), Some(region), None) => (key, region),
+                _ => return Err(invalid()),
+            };
+            let key = u32::from_str_radix(key, 16).map_err(|_| invalid())?;
+            let region = match Region::parse(region)? {
+                Some(region) => region,
+                None => return Err(invalid()),
+            };
+            if key == crc {
+                return Ok(Some(region));
+            }
+        }
+        Ok(None)
+    }
+
+    // GoodNES / No-Intro のファイル名のタグ
+    pub fn from_file_name(path: &str) -> Option<Region> {
+        const PAL_TAGS: [&str; 4] = ["(E)", "(Europe)", "(PAL)", "(Australia)"];
+        let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+        if name.contains("(Dendy)") {
+            Some(Region::DENDY)
+        } else if PAL_TAGS.iter().any(|tag| name.contains(tag)) {
+            Some(Region::PAL)
+        } else {
+            None
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Option<Region>, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(None),
+            "ntsc" => Ok(Some(Region::NTSC)),
+            "pal" => Ok(Some(Region::PAL)),
+            "dendy" => Ok(Some(Region::DENDY)),
+            r => Err(format!("unknown region '{}'", r)),
+        }
+    }
+
+    // RSCOM_REGION で強制されていれば Some
+    pub fn from_env() -> Result<Option<Region>, String> {
+        match env::var("RSCOM_REGION") {
+            Ok(s) => Region::parse(&s),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // CPUのクロック (Hz)
+    pub fn cpu_clock(&self) -> f32 {
+        match self {
+            Region::NTSC => 1_789_772.5,  // 21.477272 MHz / 12
+            Region::PAL => 1_662_607.0,   // 26.601712 MHz / 16
+            Region::DENDY => 1_773_448.0, // 26.601712 MHz / 15
+        }
+    }
+
+    // CPU 1サイクルあたりのPPUのドット数 (分子, 分母)
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match self {
+            Region::NTSC | Region::DENDY => (3, 1),
+            Region::PAL => (16, 5),
+        }
+    }
+
+    // プリレンダーラインを含む1フレームのスキャンライン数
+    pub fn scanlines(&self) -> usize {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::DENDY => 312,
+        }
+    }
+
+    // VBlankが始まるスキャンライン (Dendy は描画後に50ライン待ってから)
+    pub fn vblank_scanline(&self) -> usize {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::DENDY => 291,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL | Region::DENDY => 50.0070,
+        }
+    }
+
+    // APUのフレームシーケンサの1ステップのCPUサイクル数
+    pub fn apu_frame_interval(&self) -> usize {
+        match self {
+            Region::NTSC | Region::DENDY => 7457,
+            Region::PAL => 8313,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let db = "# コメント\n\n5CB0A9C4 pal\n0a1b2c3d Dendy  # 小文字でもよい\n";
+        assert_eq!(Region::lookup(db, 0x5CB0A9C4), Ok(Some(Region::PAL)));
+        assert_eq!(Region::lookup(db, 0x0A1B2C3D), Ok(Some(Region::DENDY)));
+        assert_eq!(Region::lookup(db, 0x12345678), Ok(None));
+        assert!(Region::lookup("5CB0A9C4", 0).is_err());
+        assert!(Region::lookup("5CB0A9C4 auto", 0).is_err());
+        assert!(Region::lookup("XYZ pal", 0).is_err());
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(
+            Region::from_file_name("roms/Game (Europe).nes"),
+            Some(Region::PAL)
+        );
+        assert_eq!(
+            Region::from_file_name("Game (E) [!].nes"),
+            Some(Region::PAL)
+        );
+        assert_eq!(
+            Region::from_file_name("Game (Dendy).nes"),
+            Some(Region::DENDY)
+        );
+        assert_eq!(Region::from_file_name("(E)/Game (U).nes"), None);
+        // GoodNES の (A) は Australia だけでなく別の意味でも使われる
+        assert_eq!(Region::from_file_name("Game (A).nes"), None);
+    }
+}
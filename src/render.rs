@@ -1,9 +1,10 @@
 use log::{debug, info};
 
 use crate::frame::Frame;
-use crate::palette;
+use crate::mapper::MapperMMC;
 use crate::ppu::PPU;
 use crate::rom::Mirroring;
+use crate::MAPPER;
 
 struct Rect {
     x1: usize,
@@ -23,7 +24,16 @@ impl Rect {
     }
 }
 
+// 背景の色 (パレットの値) と、不透明 (パレット0以外) か。スプライトと合成する時に使う
+struct Background {
+    color: Vec<u8>,
+    opaque: Vec<bool>,
+}
+
 pub fn render(ppu: &PPU, frame: &mut Frame) {
+    // パターンテーブルはマッパーから読む (CHRのバンク切り替え、CHR-RAM)
+    let mapper = MAPPER.lock().unwrap();
+
     // draw background
     let scroll_x = (ppu.scroll.scroll_x) as usize;
     let scroll_y = (ppu.scroll.scroll_y) as usize;
@@ -48,11 +58,16 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
 
     let screen_w = 256;
     let screen_h = 240;
+    let mut bg = Background {
+        color: vec![0u8; screen_w * screen_h],
+        opaque: vec![false; screen_w * screen_h],
+    };
 
     // 左上
     render_name_table(
         ppu,
-        frame,
+        &mapper,
+        &mut bg,
         main_name_table,
         Rect::new(scroll_x, scroll_y, screen_w, screen_h),
         -(scroll_x as isize),
@@ -62,7 +77,8 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
     // 右下
     render_name_table(
         ppu,
-        frame,
+        &mapper,
+        &mut bg,
         second_name_table,
         Rect::new(0, 0, scroll_x, scroll_y),
         (screen_w.wrapping_sub(scroll_x)) as isize,
@@ -72,7 +88,8 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
     // 左下
     render_name_table(
         ppu,
-        frame,
+        &mapper,
+        &mut bg,
         main_name_table,
         Rect::new(scroll_x, 0, screen_w, scroll_y),
         -(scroll_x as isize),
@@ -82,53 +99,97 @@ pub fn render(ppu: &PPU, frame: &mut Frame) {
     // 右上
     render_name_table(
         ppu,
-        frame,
+        &mapper,
+        &mut bg,
         second_name_table,
         Rect::new(0, scroll_y, scroll_x, screen_h),
         (screen_w.wrapping_sub(scroll_x)) as isize,
         -(scroll_y as isize),
     );
 
-    // draw sprites
-    // TODO 8x16 mode
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_y = ppu.oam_data[i] as usize;
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let attr = ppu.oam_data[i + 2];
-        let tile_x = ppu.oam_data[i + 3] as usize;
+    // draw sprites (背景と合成して、PPUMASKを反映しながら1ラインずつ出力する)
+    // スキャンライン毎に前のラインで評価したスプライトを描く (OAMのYの次のラインから表示)
+    // 0ライン目の前はプリレンダーラインで評価しないので、スプライトは出ない
+    for y in 0..screen_h {
+        let mask = ppu.read_mask_at(y);
+        let backdrop = ppu.read_palette_table(y)[0];
 
-        let flip_vertical = (attr >> 7 & 1) == 1;
-        let flip_horizontal = (attr >> 6 & 1) == 1;
-        let palette_idx = attr & 0b11;
-        let sprite_palette = sprite_palette(ppu, tile_y, palette_idx);
+        // ピクセル毎に、不透明なピクセルを持つOAM番号が一番小さいスプライトが選ばれる
+        // 選ばれたスプライトが背景の後ろ (属性bit5) なら、背景が不透明な所は背景が見える
+        // (後ろのスプライトが、後ろにある番号の大きい前面スプライトも隠す。SMB3の土管など)
+        let mut line: [SpritePixel; 256] = [None; 256];
+        if y > 0 && mask.show_sprites() {
+            let evaluation = ppu.evaluate_sprites(y - 1);
+            let count = if ppu.remove_sprite_limit {
+                evaluation.indices.len()
+            } else {
+                evaluation.indices.len().min(8)
+            };
+            for n in evaluation.indices[..count].iter() {
+                render_sprite_line(ppu, &mapper, &mut line, *n, y);
+            }
+        }
 
-        let bank: u16 = ppu.ctrl.sprite_pattern_addr();
+        for (x, sprite) in line.iter().enumerate() {
+            // 非表示の所や左端8ピクセルのクリップ中は、背景はパレット0の透明になる
+            let show_bg = mask.show_background() && (x >= 8 || mask.show_background_in_left());
+            let show_sprite = x >= 8 || mask.show_sprites_in_left();
+            let (bg_color, bg_opaque) = if show_bg {
+                (bg.color[y * screen_w + x], bg.opaque[y * screen_w + x])
+            } else {
+                (backdrop, false)
+            };
+            let color = match *sprite {
+                Some((color, behind_background)) if show_sprite && !(behind_background && bg_opaque) => {
+                    color
+                }
+                _ => bg_color,
+            };
+            frame.set_color(x, y, color, &mask);
+        }
+    }
+}
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+// スプライトのピクセル (パレットの値, 背景の後ろか)
+type SpritePixel = Option<(u8, bool)>;
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
-                }
-            }
+// n番目のスプライトのスキャンラインyにかかる1ライン分を、まだ埋まっていないピクセルに書く
+fn render_sprite_line(
+    ppu: &PPU,
+    mapper: &MapperMMC,
+    line: &mut [SpritePixel; 256],
+    n: usize,
+    y: usize,
+) {
+    let i = n * 4;
+    let tile_y = ppu.oam_data[i] as usize;
+    let tile_idx = ppu.oam_data[i + 1];
+    let attr = ppu.oam_data[i + 2];
+    let tile_x = ppu.oam_data[i + 3] as usize;
+
+    let flip_vertical = (attr >> 7 & 1) == 1;
+    let flip_horizontal = (attr >> 6 & 1) == 1;
+    let behind_background = (attr >> 5 & 1) == 1;
+    let palette_idx = attr & 0b11;
+    let sprite_palette = sprite_palette(ppu, y, palette_idx);
+
+    let sprite_height = ppu.sprite_height();
+    let row = y - 1 - tile_y;
+    // 8x16は上下2タイルをまとめて反転する
+    let row = if flip_vertical { sprite_height - 1 - row } else { row };
+    let (mut upper, mut lower) = ppu.sprite_tile_row(mapper, tile_idx, row);
+    'ololo: for x in (0..=7).rev() {
+        let value = (1 & lower) << 1 | (1 & upper);
+        upper = upper >> 1;
+        lower = lower >> 1;
+        if value == 0 {
+            continue 'ololo; // skip coloring the pixel
+        }
+
+        let pixel_x = if flip_horizontal { tile_x + 7 - x } else { tile_x + x };
+        // 右端ではみ出した分は左に回り込まない
+        if pixel_x < 256 && line[pixel_x].is_none() {
+            line[pixel_x] = Some((sprite_palette[value as usize], behind_background));
         }
     }
 }
@@ -168,7 +229,8 @@ fn sprite_palette(ppu: &PPU, tile_y: usize, palette_idx: u8) -> [u8; 4] {
 
 fn render_name_table(
     ppu: &PPU,
-    frame: &mut Frame,
+    mapper: &MapperMMC,
+    bg: &mut Background,
     name_table: &[u8],
     view_port: Rect,
     shift_x: isize,
@@ -181,8 +243,7 @@ fn render_name_table(
         let tile_column = i % 32;
         let tile_row = i / 32;
         let tile_idx = name_table[i] as u16;
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+        let tile: [u8; 16] = std::array::from_fn(|n| mapper.read_chr(bank + tile_idx * 16 + n as u16));
         let palette = bg_pallette(ppu, attribute_table, tile_column, tile_row);
 
         for y in 0..=7 {
@@ -193,13 +254,6 @@ fn render_name_table(
                 let value = (1 & lower) << 1 | (1 & upper);
                 upper = upper >> 1;
                 lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[palette[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
 
                 let pixel_x = tile_column * 8 + x;
                 let pixel_y = tile_row * 8 + y;
@@ -208,13 +262,136 @@ fn render_name_table(
                     && pixel_y >= view_port.y1
                     && pixel_y < view_port.y2
                 {
-                    frame.set_pixel(
-                        (shift_x + pixel_x as isize) as usize,
-                        (shift_y + pixel_y as isize) as usize,
-                        rgb,
-                    )
+                    let screen_x = (shift_x + pixel_x as isize) as usize;
+                    let screen_y = (shift_y + pixel_y as isize) as usize;
+                    let i = screen_y.wrapping_mul(256).wrapping_add(screen_x);
+                    if i < bg.color.len() {
+                        bg.color[i] = palette[value as usize];
+                        bg.opaque[i] = value != 0;
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lock_mapper_for_test;
+    use crate::region::Region;
+
+    const BACKDROP: u16 = 0x0F;
+    const BG: u16 = 0x16;
+    const SPRITE: u16 = 0x2A;
+
+    // 背景は全面タイル1 (色1)、スプライト0はタイル2 (色2) で (4, 10) から8x8
+    fn render_with_mask(mask: u8) -> Frame {
+        render_in_region(mask, Region::NTSC)
+    }
+
+    fn render_in_region(mask: u8, region: Region) -> Frame {
+        let _lock = lock_mapper_for_test();
+        let mut chr = vec![0; 0x2000];
+        for i in 0..8 {
+            chr[16 + i] = 0xFF;
+            chr[32 + 8 + i] = 0xFF;
+        }
+        {
+            let mut mapper = MAPPER.lock().unwrap();
+            **mapper = MapperMMC::new();
+            mapper.chr_rom = chr;
+        }
+
+        let mut ppu = PPU::new(Mirroring::HORIZONTAL);
+        ppu.vram = [1; 2048];
+        for i in 0..0x40 {
+            ppu.vram[0x3C0 + i] = 0;
+        }
+        ppu.palette_table[0] = BACKDROP as u8;
+        ppu.palette_table[1] = BG as u8;
+        ppu.palette_table[0x12] = SPRITE as u8;
+        ppu.oam_data = [0xF0; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[9, 2, 0, 4]);
+        ppu.write_to_mask(mask);
+
+        let mut frame = Frame::new();
+        frame.region = region;
+        render(&ppu, &mut frame);
+        frame
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> u16 {
+        frame.indices[y * Frame::WIDTH + x]
+    }
+
+    #[test]
+    fn test_show_background_and_sprites() {
+        let frame = render_with_mask(0b0001_1110);
+        assert_eq!(pixel(&frame, 0, 0), BG);
+        assert_eq!(pixel(&frame, 4, 10), SPRITE);
+        assert_eq!(pixel(&frame, 11, 17), SPRITE);
+        assert_eq!(pixel(&frame, 12, 10), BG);
+
+        // 背景非表示ならバックドロップの色
+        let frame = render_with_mask(0b0001_0110);
+        assert_eq!(pixel(&frame, 0, 0), BACKDROP);
+        assert_eq!(pixel(&frame, 4, 10), SPRITE);
+
+        // スプライト非表示
+        let frame = render_with_mask(0b0000_1110);
+        assert_eq!(pixel(&frame, 4, 10), BG);
+
+        let frame = render_with_mask(0b0000_0000);
+        assert_eq!(pixel(&frame, 4, 10), BACKDROP);
+    }
+
+    #[test]
+    fn test_left_clipping() {
+        // 背景だけ左端8ピクセルを隠す
+        let frame = render_with_mask(0b0001_1100);
+        assert_eq!(pixel(&frame, 0, 0), BACKDROP);
+        assert_eq!(pixel(&frame, 7, 0), BACKDROP);
+        assert_eq!(pixel(&frame, 8, 0), BG);
+        assert_eq!(pixel(&frame, 4, 10), SPRITE);
+
+        // スプライトだけ隠す
+        let frame = render_with_mask(0b0001_1010);
+        assert_eq!(pixel(&frame, 0, 0), BG);
+        assert_eq!(pixel(&frame, 7, 10), BG);
+        assert_eq!(pixel(&frame, 8, 10), SPRITE);
+
+        // 両方隠す
+        let frame = render_with_mask(0b0001_1000);
+        assert_eq!(pixel(&frame, 7, 10), BACKDROP);
+        assert_eq!(pixel(&frame, 8, 10), SPRITE);
+    }
+
+    #[test]
+    fn test_greyscale() {
+        let frame = render_with_mask(0b0001_1111);
+        assert_eq!(pixel(&frame, 0, 0), BG & 0x30);
+        assert_eq!(pixel(&frame, 4, 10), SPRITE & 0x30);
+        let rgb = frame.palette.colors[(BG & 0x30) as usize];
+        assert_eq!(frame.data[..3], [rgb.0, rgb.1, rgb.2]);
+    }
+
+    #[test]
+    fn test_emphasis() {
+        // 赤と青
+        let frame = render_with_mask(0b1011_1110);
+        assert_eq!(pixel(&frame, 0, 0), 0b101 << 6 | BG);
+        assert_eq!(pixel(&frame, 4, 10), 0b101 << 6 | SPRITE);
+        let rgb = frame.palette.colors[(0b101 << 6 | BG) as usize];
+        assert_eq!(frame.data[..3], [rgb.0, rgb.1, rgb.2]);
+        assert_ne!(rgb, frame.palette.colors[BG as usize]);
+
+        // PAL/Dendy は bit5 が緑、bit6 が赤
+        for region in [Region::PAL, Region::DENDY] {
+            let frame = render_in_region(0b0011_1110, region);
+            assert_eq!(pixel(&frame, 0, 0), 0b010 << 6 | BG);
+            let frame = render_in_region(0b0101_1110, region);
+            assert_eq!(pixel(&frame, 0, 0), 0b001 << 6 | BG);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{common};
+use crate::region::Region;
 use common::*;
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // NES^Z
@@ -46,6 +47,7 @@ pub struct Rom {
     pub is_chr_ram: bool,
     pub is_prg_ram: bool,
     pub rom_type: RomType,
+    pub region: Region,
 }
 
 impl Rom {
@@ -140,6 +142,7 @@ impl Rom {
             is_chr_ram: is_chr_ram,
             is_prg_ram: is_prg_ram,
             rom_type: rom_type,
+            region: Region::from_header(raw).unwrap_or(Region::NTSC),
         })
     }
 
@@ -153,6 +156,7 @@ impl Rom {
             is_chr_ram: false,
             is_prg_ram: false,
             rom_type: RomType::NROM,
+            region: Region::NTSC,
         };
     }
 }
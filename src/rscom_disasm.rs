@@ -0,0 +1,207 @@
+// PRG-ROMを逆アセンブルしてバンク毎のリストを出力する
+//
+//   cargo run --release --bin rscom-disasm -- <ROM> [--bank N] [--bank-size 8|16|32] [--base ADDR]
+//                                                  [--nl FILE] [--dbg FILE]
+//
+// --bank      : 指定したバンクだけ出力する (省略時は全バンク)
+// --bank-size : バンクの単位 KB (省略時はMMC3系は8、それ以外は16)
+// --base      : バンクを配置するCPUアドレス (省略時はマッパーの配置から推測)
+// --nl, --dbg : ラベルファイル (FCEUX .nl / ca65 .dbg)。.nl は "<rom>.N.nl" ならバンクN、それ以外はRAM
+//
+// ROMと同じ場所にある <rom>.ram.nl, <rom>.N.nl, <rom拡張子なし>.dbg は自動で読み込む
+// 未対応マッパーのROMでも逆アセンブルできるように、ヘッダは自前で読む
+use rscom::disasm::{disassemble_bank, Symbols};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16 * 1024;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: rscom-disasm <ROM> [--bank N] [--bank-size 8|16|32] [--base ADDR] [--nl FILE] [--dbg FILE]"
+    );
+    process::exit(1);
+}
+
+fn parse_num(s: &str) -> Option<usize> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+// (PRG-ROM, マッパー番号)
+fn read_prg_rom(path: &str) -> Result<(Vec<u8>, u8), String> {
+    let raw = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+        return Err(format!("{}: not in iNES file format", path));
+    }
+    let mapper = (raw[7] & 0xF0) | (raw[6] >> 4);
+    let start = HEADER_SIZE + if raw[6] & 0b100 != 0 { TRAINER_SIZE } else { 0 };
+    let end = (start + raw[4] as usize * PRG_ROM_PAGE_SIZE).min(raw.len());
+    Ok((raw[start..end].to_vec(), mapper))
+}
+
+// FCEUXの命名の "<rom>.N.nl" (Nは16進) ならバンクN、それ以外 ("<rom>.ram.nl" 等) はRAM
+fn nl_bank(nl_path: &str, rom_path: &str) -> Option<usize> {
+    let file_name = |p: &str| {
+        Path::new(p)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+    };
+    let nl_name = file_name(nl_path)?;
+    let rom_name = file_name(rom_path)?;
+    let n = nl_name
+        .strip_prefix(rom_name.as_str())?
+        .strip_prefix('.')?
+        .strip_suffix(".nl")?;
+    if n.is_empty() || !n.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(n, 16).ok()
+}
+
+// バンクが普段配置されているアドレス
+// 最後のバンクは固定で上位に、それ以外は切り替え領域の先頭に置かれることが多い
+fn guess_base(bank: usize, banks: usize, bank_size: usize) -> u16 {
+    match bank_size {
+        0x2000 if bank + 1 == banks => 0xE000,
+        0x2000 if bank + 2 == banks => 0xC000,
+        0x4000 if bank + 1 == banks => 0xC000,
+        _ => 0x8000,
+    }
+}
+
+fn main() {
+    let mut rom_path: Option<String> = None;
+    let mut bank: Option<usize> = None;
+    let mut bank_size: Option<usize> = None;
+    let mut base: Option<u16> = None;
+    let mut nl_files: Vec<String> = vec![];
+    let mut dbg_files: Vec<String> = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--bank" => bank = Some(parse_num(&value()).unwrap_or_else(|| usage())),
+            "--bank-size" => {
+                bank_size = match parse_num(&value()) {
+                    Some(kb @ (8 | 16 | 32)) => Some(kb * 1024),
+                    _ => usage(),
+                }
+            }
+            "--base" => {
+                base = match parse_num(&value()) {
+                    Some(addr) if (0x8000..=0xFFFF).contains(&addr) => Some(addr as u16),
+                    _ => usage(),
+                }
+            }
+            "--nl" => nl_files.push(value()),
+            "--dbg" => dbg_files.push(value()),
+            "-h" | "--help" => usage(),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage());
+
+    let (prg_rom, mapper) = match read_prg_rom(&rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut symbols = Symbols::new();
+    symbols.load_nl_for_rom(&rom_path, prg_rom.len());
+    let dbg = Path::new(&rom_path).with_extension("dbg");
+    if dbg.exists() {
+        symbols.load_dbg(&dbg).ok();
+    }
+    for path in &nl_files {
+        let bank = nl_bank(path, &rom_path);
+        if let Err(e) = symbols.load_nl(path, bank) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    for path in &dbg_files {
+        if let Err(e) = symbols.load_dbg(path) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    // MMC3系は8KB単位で切り替わる
+    let bank_size = bank_size.unwrap_or(match mapper {
+        4 | 118 | 119 => 0x2000,
+        _ => 0x4000,
+    });
+    if let Some(base) = base {
+        if base as usize + bank_size > 0x10000 {
+            eprintln!(
+                "bank of {}KB does not fit at ${:04X} (must end at or below $FFFF)",
+                bank_size / 1024,
+                base
+            );
+            process::exit(1);
+        }
+    }
+    let banks = prg_rom.len().div_ceil(bank_size);
+    let targets: Vec<usize> = match bank {
+        Some(n) if n < banks => vec![n],
+        Some(n) => {
+            eprintln!("bank {} is out of range (0-{})", n, banks.saturating_sub(1));
+            process::exit(1);
+        }
+        None => (0..banks).collect(),
+    };
+
+    println!(
+        "; {} mapper:{} PRG-ROM:{}KB banks:{}x{}KB labels:{}",
+        rom_path,
+        mapper,
+        prg_rom.len() / 1024,
+        banks,
+        bank_size / 1024,
+        symbols.len()
+    );
+    for n in targets {
+        let base = base.unwrap_or_else(|| guess_base(n, banks, bank_size));
+        println!();
+        println!(
+            "; ---- bank {} (PRG ${:05X}) @ ${:04X} ----",
+            n,
+            n * bank_size,
+            base
+        );
+        for line in disassemble_bank(&prg_rom, n, bank_size, base, &symbols) {
+            println!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nl_bank() {
+        assert_eq!(nl_bank("game.nes.3.nl", "roms/game.nes"), Some(3));
+        assert_eq!(nl_bank("labels/game.nes.1F.nl", "game.nes"), Some(0x1F));
+        assert_eq!(nl_bank("game.nes.ram.nl", "game.nes"), None);
+        // ROMの名前で始まらない物は16進に見えてもバンクではない
+        assert_eq!(nl_bank("cafe.nl", "game.nes"), None);
+        assert_eq!(nl_bank("labels.dead.nl", "game.nes"), None);
+        assert_eq!(nl_bank("game.nes.nl", "game.nes"), None);
+    }
+}
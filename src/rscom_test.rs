@@ -0,0 +1,415 @@
+// Blargg形式のテストROMをヘッドレスで実行するランナー
+//
+//   cargo run --release --bin rscom-test -- [--timeout SEC] <ROM or DIR>...
+//
+// テストROMは結果を $6000- に書き込む
+//   $6000       : 状態 ($80 = 実行中, $81 = リセット要求, $00-$7F = 結果コード, 0 = 成功)
+//   $6001-$6003 : シグネチャ $DE $B0 $61 (書き込まれるまで$6000は無効)
+//   $6004-      : 0終端のテキスト (テスト名、失敗内容など)
+//
+// 終了コード
+//   0 = 成功, 1 = 失敗, 2 = タイムアウト, 3 = CPU停止(JAM), 4 = ROMエラー(未対応マッパー等)
+//
+// RSCOM_MAGIC_CONSTANT=FF 等でANE/LXAのマジック定数を変えられる (子プロセスにも引き継ぐ)
+//
+// ROMを1つだけ指定した時はそのまま実行してテキストを出力する
+// ディレクトリや複数ROMを指定した時は1ROMずつ子プロセスで実行して結果を表にまとめる
+// (MAPPERがグローバルなのと、未対応マッパーのpanicで全体が止まらないようにするため)
+use rscom::apu::APU;
+use rscom::bus::{Bus, Mem};
+use rscom::cartridge::load_rom;
+use rscom::cpu::{magic_constant_from_env, CPU};
+use rscom::MAPPER;
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const TEXT_ADDR: u16 = 0x6004;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// リセット要求からリセットボタンを押すまでの時間 (100ms以上待つ決まり)
+// 秒数はROMの地域 (NTSC/PAL/Dendy) のCPUクロックでサイクル数にする
+const RESET_DELAY_SEC: f64 = 0.1;
+const DEFAULT_TIMEOUT_SEC: f64 = 60.0;
+
+const EXIT_PASSED: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_TIMEOUT: i32 = 2;
+const EXIT_JAMMED: i32 = 3;
+const EXIT_ERROR: i32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+enum TestResult {
+    Passed,
+    Failed(u8),
+    Timeout,
+    Jammed(u16),
+}
+
+impl TestResult {
+    fn exit_code(&self) -> i32 {
+        match self {
+            TestResult::Passed => EXIT_PASSED,
+            TestResult::Failed(_) => EXIT_FAILED,
+            TestResult::Timeout => EXIT_TIMEOUT,
+            TestResult::Jammed(_) => EXIT_JAMMED,
+        }
+    }
+}
+
+fn has_signature<M: Mem>(mem: &M) -> bool {
+    (0..3).all(|i| mem.peek(SIGNATURE_ADDR + i) == SIGNATURE[i as usize])
+}
+
+fn read_text<M: Mem>(mem: &M) -> String {
+    let mut text = vec![];
+    for addr in TEXT_ADDR..=0x7FFF {
+        let c = mem.peek(addr);
+        if c == 0 {
+            break;
+        }
+        text.push(c);
+    }
+    String::from_utf8_lossy(&text).to_string()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Running,
+    // リセットボタンを押す
+    Reset,
+    Finished(TestResult),
+}
+
+// $6000 の状態を見る (1フレーム毎に呼ぶ)
+struct StatusMonitor {
+    reset_delay: usize,
+    reset_at: Option<usize>,
+}
+
+impl StatusMonitor {
+    fn new(reset_delay: usize) -> Self {
+        StatusMonitor {
+            reset_delay,
+            reset_at: None,
+        }
+    }
+
+    fn check<M: Mem>(&mut self, mem: &M, cycles: usize) -> Status {
+        if !has_signature(mem) {
+            return Status::Running;
+        }
+        match mem.peek(STATUS_ADDR) {
+            STATUS_RUNNING => Status::Running,
+            STATUS_RESET => {
+                let at = *self.reset_at.get_or_insert(cycles + self.reset_delay);
+                if cycles >= at {
+                    self.reset_at = None;
+                    Status::Reset
+                } else {
+                    Status::Running
+                }
+            }
+            0x00 => Status::Finished(TestResult::Passed),
+            code if code < STATUS_RUNNING => Status::Finished(TestResult::Failed(code)),
+            // 未定義の値は実行中とみなす
+            _ => Status::Running,
+        }
+    }
+}
+
+fn run_rom(path: &str, timeout_sec: f64, magic_constant: u8) -> (TestResult, String) {
+    let rom = load_rom(path);
+    MAPPER.lock().unwrap().set_rom(&rom);
+    let cpu_clock = rom.region.cpu_clock() as f64;
+    let cycles_per_frame = (cpu_clock / rom.region.frame_rate()) as usize;
+    let bus = Bus::new(rom, APU::new_headless(), |_, _| {});
+    let mut cpu = CPU::new(bus);
+    cpu.magic_constant = magic_constant;
+    cpu.reset();
+
+    let max_cycles = (timeout_sec * cpu_clock) as usize;
+    let mut monitor = StatusMonitor::new((RESET_DELAY_SEC * cpu_clock) as usize);
+    let mut next_check = cycles_per_frame;
+
+    loop {
+        cpu.step();
+        if cpu.is_jammed() {
+            return (TestResult::Jammed(cpu.program_counter), read_text(&cpu));
+        }
+
+        // $6000のチェックは1フレーム毎で十分
+        let cycles = cpu.bus.cycles();
+        if cycles < next_check {
+            continue;
+        }
+        next_check = cycles + cycles_per_frame;
+
+        if cycles >= max_cycles {
+            return (TestResult::Timeout, read_text(&cpu));
+        }
+        match monitor.check(&cpu, cycles) {
+            Status::Running => {}
+            Status::Reset => cpu.soft_reset(),
+            Status::Finished(result) => return (result, read_text(&cpu)),
+        }
+    }
+}
+
+fn collect_roms(path: &Path, roms: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .expect("can't read directory")
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            collect_roms(&entry, roms);
+        }
+    } else if path
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("nes"))
+        .unwrap_or(false)
+    {
+        roms.push(path.to_path_buf());
+    }
+}
+
+// 表に載せる用にテキストを1行にまとめる
+fn summarize(text: &str) -> String {
+    let line = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" / ");
+    if line.chars().count() > 60 {
+        format!("{}...", line.chars().take(57).collect::<String>())
+    } else {
+        line
+    }
+}
+
+fn run_batch(roms: &[PathBuf], timeout_sec: f64) -> i32 {
+    let exe = env::current_exe().expect("can't find rscom-test executable");
+    let width = roms
+        .iter()
+        .map(|rom| rom.display().to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    let mut passed = 0;
+    for rom in roms {
+        let output = Command::new(&exe)
+            .arg("--timeout")
+            .arg(timeout_sec.to_string())
+            .arg(rom)
+            .output()
+            .expect("can't run rscom-test");
+
+        let label = match output.status.code() {
+            Some(EXIT_PASSED) => "PASS",
+            Some(EXIT_FAILED) => "FAIL",
+            Some(EXIT_TIMEOUT) => "TIMEOUT",
+            Some(EXIT_JAMMED) => "JAM",
+            _ => "ERROR",
+        };
+        if label == "PASS" {
+            passed += 1;
+        }
+        let text = if output.stdout.is_empty() {
+            String::from_utf8_lossy(&output.stderr).to_string()
+        } else {
+            String::from_utf8_lossy(&output.stdout).to_string()
+        };
+        println!(
+            "{:<7} {:<width$}  {}",
+            label,
+            rom.display(),
+            summarize(&text),
+            width = width
+        );
+    }
+
+    println!("----");
+    println!("{}/{} passed", passed, roms.len());
+    if passed == roms.len() {
+        EXIT_PASSED
+    } else {
+        EXIT_FAILED
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: rscom-test [--timeout SEC] <ROM or DIR>...");
+    process::exit(EXIT_ERROR);
+}
+
+fn main() {
+    env_logger::builder().format_timestamp(None).init();
+
+    let mut timeout_sec = DEFAULT_TIMEOUT_SEC;
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                timeout_sec = args
+                    .next()
+                    .and_then(|sec| sec.parse().ok())
+                    .unwrap_or_else(|| usage());
+            }
+            "-h" | "--help" => usage(),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+    let magic_constant = magic_constant_from_env().unwrap_or_else(|e| {
+        eprintln!("RSCOM_MAGIC_CONSTANT: {}", e);
+        process::exit(EXIT_ERROR);
+    });
+
+    let single = paths.len() == 1 && paths[0].is_file();
+    if single {
+        let path = paths[0].to_string_lossy().to_string();
+        // 未対応マッパー等のpanicはROMエラーとして返す
+        let (result, text) =
+            match panic::catch_unwind(|| run_rom(&path, timeout_sec, magic_constant)) {
+                Ok(result) => result,
+                Err(_) => process::exit(EXIT_ERROR),
+            };
+        print!("{}", text);
+        match &result {
+            TestResult::Passed => {}
+            TestResult::Failed(code) => eprintln!("failed (code ${:02X})", code),
+            TestResult::Timeout => eprintln!("timeout ({} sec)", timeout_sec),
+            TestResult::Jammed(pc) => eprintln!("CPU halted at ${:04X}", pc),
+        }
+        process::exit(result.exit_code());
+    }
+
+    let mut roms = vec![];
+    for path in &paths {
+        collect_roms(path, &mut roms);
+    }
+    if roms.is_empty() {
+        eprintln!("no .nes files found");
+        process::exit(EXIT_ERROR);
+    }
+    process::exit(run_batch(&roms, timeout_sec));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // $6000- だけのメモリ
+    struct TestMem {
+        ram: Vec<u8>,
+    }
+
+    impl TestMem {
+        fn new() -> Self {
+            TestMem {
+                ram: vec![0; 0x2000],
+            }
+        }
+
+        // シグネチャ、状態、テキストを書き込む
+        fn write_result(&mut self, status: u8, text: &str) {
+            self.poke(STATUS_ADDR, status);
+            for (i, b) in SIGNATURE.iter().enumerate() {
+                self.poke(SIGNATURE_ADDR + i as u16, *b);
+            }
+            for (i, b) in text.bytes().chain([0]).enumerate() {
+                self.poke(TEXT_ADDR + i as u16, b);
+            }
+        }
+    }
+
+    impl Mem for TestMem {
+        fn mem_read(&mut self, addr: u16) -> u8 {
+            self.peek(addr)
+        }
+
+        fn mem_write(&mut self, addr: u16, data: u8) {
+            self.poke(addr, data)
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.ram[(addr - 0x6000) as usize]
+        }
+
+        fn poke(&mut self, addr: u16, data: u8) {
+            self.ram[(addr - 0x6000) as usize] = data;
+        }
+    }
+
+    #[test]
+    fn test_signature_and_text() {
+        let mut mem = TestMem::new();
+        assert!(!has_signature(&mem));
+        assert_eq!(read_text(&mem), "");
+
+        mem.write_result(STATUS_RUNNING, "01-basics\n\nPassed\n");
+        assert!(has_signature(&mem));
+        assert_eq!(read_text(&mem), "01-basics\n\nPassed\n");
+
+        mem.poke(SIGNATURE_ADDR + 2, 0x00);
+        assert!(!has_signature(&mem));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut mem = TestMem::new();
+        let mut monitor = StatusMonitor::new(100);
+
+        // シグネチャが書かれるまでは$6000を見ない
+        mem.poke(STATUS_ADDR, 0x00);
+        assert_eq!(monitor.check(&mem, 0), Status::Running);
+
+        mem.write_result(STATUS_RUNNING, "");
+        assert_eq!(monitor.check(&mem, 0), Status::Running);
+        mem.write_result(0x00, "");
+        assert_eq!(monitor.check(&mem, 0), Status::Finished(TestResult::Passed));
+        mem.write_result(0x03, "");
+        assert_eq!(
+            monitor.check(&mem, 0),
+            Status::Finished(TestResult::Failed(3))
+        );
+        mem.write_result(0xFF, "");
+        assert_eq!(monitor.check(&mem, 0), Status::Running);
+    }
+
+    #[test]
+    fn test_reset_request() {
+        // $81 になってから reset_delay サイクル経ったらリセットする
+        let mut mem = TestMem::new();
+        let mut monitor = StatusMonitor::new(100);
+        mem.write_result(STATUS_RESET, "");
+        assert_eq!(monitor.check(&mem, 1000), Status::Running);
+        assert_eq!(monitor.check(&mem, 1099), Status::Running);
+        assert_eq!(monitor.check(&mem, 1100), Status::Reset);
+
+        // リセット後もまだ $81 なら、また待ち直す
+        assert_eq!(monitor.check(&mem, 1200), Status::Running);
+        assert_eq!(monitor.check(&mem, 1300), Status::Reset);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(TestResult::Passed.exit_code(), EXIT_PASSED);
+        assert_eq!(TestResult::Failed(1).exit_code(), EXIT_FAILED);
+        assert_eq!(TestResult::Timeout.exit_code(), EXIT_TIMEOUT);
+        assert_eq!(TestResult::Jammed(0x8000).exit_code(), EXIT_JAMMED);
+    }
+}
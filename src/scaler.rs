@@ -0,0 +1,681 @@
+// 画面を拡大するフィルタ
+//
+// Frame (やNTSCフィルタ) のRGBを拡大してからSDLのテクスチャに渡す
+// main.rs では環境変数で初期値を決め、実行中はキーで切り替える
+//
+//   RSCOM_FILTER=scale2x   : nearest (整数倍), scale2x, scale3x, hq2x, hq3x, xbr
+//                            nearest は nearest3 のように倍率を付けられる (省略時は2倍)
+//   RSCOM_ASPECT=8:7       : ドットの縦横比を 8:7 にする (省略時は正方形)
+//
+// F6 でフィルタを順に切り替え、F7 で縦横比を切り替える
+//
+// オーバースキャン (テレビでは見えなかった画面の端) も拡大の前に切り取る
+//
+//   RSCOM_OVERSCAN=8,8,0,0 : 上, 下, 左, 右 の順にドット数 (1つだけなら上下、2つなら上下と左右)
+//
+// ROMと同じ場所に <rom>.overscan があれば、そのゲームでは中身 (書式は同じ) を使う
+use std::env;
+use std::fs;
+use std::path::Path;
+
+type Pixel = (u8, u8, u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleFilter {
+    Nearest(usize),
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Hq3x,
+    Xbr,
+}
+
+impl ScaleFilter {
+    // F6 で切り替える順番
+    const CYCLE: [ScaleFilter; 6] = [
+        ScaleFilter::Nearest(2),
+        ScaleFilter::Scale2x,
+        ScaleFilter::Scale3x,
+        ScaleFilter::Hq2x,
+        ScaleFilter::Hq3x,
+        ScaleFilter::Xbr,
+    ];
+
+    pub fn parse(s: &str) -> Option<ScaleFilter> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "nearest" => Some(ScaleFilter::Nearest(2)),
+            "scale2x" => Some(ScaleFilter::Scale2x),
+            "scale3x" => Some(ScaleFilter::Scale3x),
+            "hq2x" => Some(ScaleFilter::Hq2x),
+            "hq3x" => Some(ScaleFilter::Hq3x),
+            "xbr" | "2xbr" => Some(ScaleFilter::Xbr),
+            s => match s.strip_prefix("nearest")?.parse() {
+                Ok(n @ 1..=8) => Some(ScaleFilter::Nearest(n)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            ScaleFilter::Nearest(n) => format!("nearest{}", n),
+            ScaleFilter::Scale2x => "scale2x".to_string(),
+            ScaleFilter::Scale3x => "scale3x".to_string(),
+            ScaleFilter::Hq2x => "hq2x".to_string(),
+            ScaleFilter::Hq3x => "hq3x".to_string(),
+            ScaleFilter::Xbr => "xbr".to_string(),
+        }
+    }
+
+    pub fn scale(&self) -> usize {
+        match self {
+            ScaleFilter::Nearest(n) => *n,
+            ScaleFilter::Scale2x | ScaleFilter::Hq2x | ScaleFilter::Xbr => 2,
+            ScaleFilter::Scale3x | ScaleFilter::Hq3x => 3,
+        }
+    }
+
+    pub fn next(&self) -> ScaleFilter {
+        let n = ScaleFilter::CYCLE.iter().position(|f| f == self).map(|n| n + 1).unwrap_or(0);
+        ScaleFilter::CYCLE[n % ScaleFilter::CYCLE.len()]
+    }
+}
+
+pub struct Scaler {
+    pub filter: ScaleFilter,
+    // ドットを 8:7 の横長で表示する
+    pub aspect_8_7: bool,
+    output: Vec<u8>,
+}
+
+impl Default for Scaler {
+    fn default() -> Self {
+        Scaler::new(ScaleFilter::Nearest(2))
+    }
+}
+
+impl Scaler {
+    pub fn new(filter: ScaleFilter) -> Self {
+        Scaler {
+            filter,
+            aspect_8_7: false,
+            output: vec![],
+        }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let mut scaler = Scaler::default();
+        if let Ok(name) = env::var("RSCOM_FILTER") {
+            scaler.filter = ScaleFilter::parse(&name).ok_or(format!("unknown filter '{}'", name))?;
+        }
+        if let Ok(aspect) = env::var("RSCOM_ASPECT") {
+            scaler.aspect_8_7 = match aspect.trim() {
+                "8:7" => true,
+                "1:1" => false,
+                a => return Err(format!("unknown aspect ratio '{}'", a)),
+            };
+        }
+        Ok(scaler)
+    }
+
+    // RGBの画像を拡大して (データ, 幅, 高さ) を返す
+    pub fn apply(&mut self, src: &[u8], width: usize, height: usize) -> (&[u8], usize, usize) {
+        let scale = self.filter.scale();
+        let (out_width, out_height) = (width * scale, height * scale);
+        self.output.resize(out_width * out_height * 3, 0);
+
+        let image = Image { data: src, width, height };
+        let output = &mut self.output;
+        let mut put = |x: usize, y: usize, sub: usize, rgb: Pixel| {
+            let (ox, oy) = (x * scale + sub % scale, y * scale + sub / scale);
+            let base = (oy * out_width + ox) * 3;
+            output[base] = rgb.0;
+            output[base + 1] = rgb.1;
+            output[base + 2] = rgb.2;
+        };
+        for y in 0..height {
+            for x in 0..width {
+                let p = |dx: isize, dy: isize| image.get(x as isize + dx, y as isize + dy);
+                match self.filter {
+                    ScaleFilter::Nearest(_) => {
+                        let e = p(0, 0);
+                        (0..scale * scale).for_each(|sub| put(x, y, sub, e));
+                    }
+                    ScaleFilter::Scale2x => {
+                        for (sub, rgb) in scale2x(&p).into_iter().enumerate() {
+                            put(x, y, sub, rgb);
+                        }
+                    }
+                    ScaleFilter::Scale3x => {
+                        for (sub, rgb) in scale3x(&p).into_iter().enumerate() {
+                            put(x, y, sub, rgb);
+                        }
+                    }
+                    ScaleFilter::Hq2x => {
+                        for (sub, rgb) in hq2x(&p).into_iter().enumerate() {
+                            put(x, y, sub, rgb);
+                        }
+                    }
+                    ScaleFilter::Hq3x => {
+                        for (sub, rgb) in hq3x(&p).into_iter().enumerate() {
+                            put(x, y, sub, rgb);
+                        }
+                    }
+                    ScaleFilter::Xbr => {
+                        for (sub, rgb) in xbr2x(&p).into_iter().enumerate() {
+                            put(x, y, sub, rgb);
+                        }
+                    }
+                }
+            }
+        }
+        (&self.output, out_width, out_height)
+    }
+
+    // 元の画面 (width x height ドット) を表示する時の縦横比 (幅 / 高さ)
+    pub fn display_aspect(&self, width: usize, height: usize) -> f32 {
+        let pixel_aspect = if self.aspect_8_7 { 8.0 / 7.0 } else { 1.0 };
+        width as f32 * pixel_aspect / height as f32
+    }
+
+    // ウィンドウ (out_width x out_height) の中に縦横比を保って収める (x, y, 幅, 高さ)
+    pub fn fit(&self, aspect: f32, out_width: u32, out_height: u32) -> (i32, i32, u32, u32) {
+        let (w, h) = if out_width as f32 / out_height as f32 > aspect {
+            ((out_height as f32 * aspect).round() as u32, out_height)
+        } else {
+            (out_width, (out_width as f32 / aspect).round() as u32)
+        };
+        (((out_width - w) / 2) as i32, ((out_height - h) / 2) as i32, w, h)
+    }
+}
+
+// 画面の端を切り取るドット数 (256x240 の画面で数える)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    // "8" => 上下8, "8,4" => 上下8 左右4, "8,8,0,0" => 上, 下, 左, 右
+    pub fn parse(s: &str) -> Result<Overscan, String> {
+        let values = s
+            .split(',')
+            .map(|v| {
+                v.trim()
+                    .parse()
+                    .map_err(|_| format!("invalid overscan '{}'", s))
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        let overscan = match values[..] {
+            [v] => Overscan {
+                top: v,
+                bottom: v,
+                left: 0,
+                right: 0,
+            },
+            [v, h] => Overscan {
+                top: v,
+                bottom: v,
+                left: h,
+                right: h,
+            },
+            [top, bottom, left, right] => Overscan {
+                top,
+                bottom,
+                left,
+                right,
+            },
+            _ => return Err(format!("invalid overscan '{}'", s)),
+        };
+        if overscan.top + overscan.bottom >= 240 || overscan.left + overscan.right >= 256 {
+            return Err(format!("overscan '{}' leaves nothing to show", s));
+        }
+        Ok(overscan)
+    }
+
+    // <rom>.overscan があればそれを、無ければ RSCOM_OVERSCAN を使う
+    pub fn for_rom(rom_path: &str) -> Result<Overscan, String> {
+        let per_game = format!("{}.overscan", rom_path);
+        if Path::new(&per_game).exists() {
+            let s = fs::read_to_string(&per_game).map_err(|e| format!("{}: {}", per_game, e))?;
+            return Overscan::parse(s.trim()).map_err(|e| format!("{}: {}", per_game, e));
+        }
+        match env::var("RSCOM_OVERSCAN") {
+            Ok(s) => Overscan::parse(&s),
+            Err(_) => Ok(Overscan::default()),
+        }
+    }
+
+    // 切り取った後の大きさ (256x240 の画面で数える)
+    pub fn visible_size(&self) -> (usize, usize) {
+        (256 - self.left - self.right, 240 - self.top - self.bottom)
+    }
+
+    // RGBの画像を切り取って out に入れ、(幅, 高さ) を返す
+    // NTSCフィルタの出力のように横に伸びていても、256ドットに対する割合で切る
+    pub fn crop(
+        &self,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        out: &mut Vec<u8>,
+    ) -> (usize, usize) {
+        let left = self.left * width / 256;
+        let right = self.right * width / 256;
+        let (top, bottom) = (
+            self.top.min(height),
+            self.bottom.min(height - self.top.min(height)),
+        );
+        let out_width = width - left - right;
+        let out_height = height - top - bottom;
+        out.clear();
+        for y in top..top + out_height {
+            let start = (y * width + left) * 3;
+            out.extend_from_slice(&src[start..start + out_width * 3]);
+        }
+        (out_width, out_height)
+    }
+}
+
+struct Image<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Image<'_> {
+    // 画面外は端のドットを使う
+    fn get(&self, x: isize, y: isize) -> Pixel {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+}
+
+// AdvMAME2x
+fn scale2x(p: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 4] {
+    let (b, d, e, f, h) = (p(0, -1), p(-1, 0), p(0, 0), p(1, 0), p(0, 1));
+    if b == h || d == f {
+        return [e; 4];
+    }
+    [
+        if d == b { d } else { e },
+        if b == f { f } else { e },
+        if d == h { d } else { e },
+        if h == f { f } else { e },
+    ]
+}
+
+// AdvMAME3x
+fn scale3x(p: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 9] {
+    let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+    let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+    if b == h || d == f {
+        return [e; 9];
+    }
+    [
+        if d == b { d } else { e },
+        if (d == b && e != c) || (b == f && e != a) { b } else { e },
+        if b == f { f } else { e },
+        if (d == b && e != g) || (d == h && e != a) { d } else { e },
+        e,
+        if (b == f && e != i) || (h == f && e != c) { f } else { e },
+        if d == h { d } else { e },
+        if (d == h && e != i) || (h == f && e != g) { h } else { e },
+        if h == f { f } else { e },
+    ]
+}
+
+fn yuv(rgb: Pixel) -> (f32, f32, f32) {
+    let (r, g, b) = (rgb.0 as f32, rgb.1 as f32, rgb.2 as f32);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b,
+        0.5 * r - 0.419 * g - 0.081 * b,
+    )
+}
+
+// hqx と同じしきい値で、別の色とみなすか (hqx と同じく YUV は整数に切り捨てて比べる)
+fn differ(a: Pixel, b: Pixel) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya as i32 - yb as i32).abs() > 48 || (ua as i32 - ub as i32).abs() > 7 || (va as i32 - vb as i32).abs() > 6
+}
+
+// 重み付きの平均
+fn blend(colors: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = colors.iter().map(|(_, w)| w).sum();
+    let mix = |channel: fn(&Pixel) -> u8| {
+        (colors.iter().map(|(c, w)| channel(c) as u32 * w).sum::<u32>() / total) as u8
+    };
+    (mix(|c| c.0), mix(|c| c.1), mix(|c| c.2))
+}
+
+// hqx の左上の角の補間方法。周りの8ドットが中央と別の色か (下位ビットから a b c d f g h i) で引く
+// hq2x.c の switch (256通り) の PIXEL00_xx を並べ直したもので、他の角は近傍を回して同じ表を使う
+// hq3x.c の switch も同じ場合分けなので、hq3x の角と辺の中央もこの表から決める
+//
+//   a b c   0: e2 b1 d1   1: e2 a1 b1   2: e2 a1 d1   3: e3 a1   4: e3 d1   5: e3 b1
+//   d e f   6..=11: b と d が別の色か (同じ色なら輪郭が角を斜めに横切る) で分かれる
+//   g h i   12: b と f、13: d と h が別の色かで分かれる (隣の角の 10, 11 と対になる)
+#[rustfmt::skip]
+const HQX: [u8; 256] = [
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 7, 6, 1, 5, 11, 10,
+    0, 0, 2, 12, 0, 0, 2, 12, 1, 5, 6, 6, 1, 5, 3, 6,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 11, 10, 1, 5, 8, 9,
+    0, 0, 2, 12, 0, 0, 2, 12, 1, 5, 8, 6, 1, 5, 3, 9,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 13, 6, 6, 1, 13, 8, 6,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 6, 1, 5, 8, 6,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 13, 3, 6, 1, 13, 3, 9,
+    0, 0, 2, 4, 0, 0, 2, 12, 1, 5, 8, 6, 1, 13, 3, 9,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 7, 6, 1, 5, 11, 10,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 6, 1, 5, 8, 6,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 11, 10, 1, 5, 8, 9,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 10, 1, 5, 3, 9,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 6, 1, 5, 8, 10,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 6, 1, 5, 3, 6,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 8, 6, 1, 5, 3, 9,
+    0, 0, 2, 4, 0, 0, 2, 4, 1, 5, 3, 6, 1, 5, 3, 9,
+];
+
+// 角を左上に持ってくるように近傍 (左上から右下の順) を回す。左上の角から時計回り
+const HQX_ROTATE: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 5, 8, 1, 4, 7, 0, 3, 6],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+    [6, 3, 0, 7, 4, 1, 8, 5, 2],
+];
+
+// 4つの角それぞれについて、左上に回した近傍と HQX の番号
+fn hqx_corners(p: &impl Fn(isize, isize) -> Pixel) -> [([Pixel; 9], u8); 4] {
+    let n: [Pixel; 9] = std::array::from_fn(|i| p(i as isize % 3 - 1, i as isize / 3 - 1));
+    let diff = n.map(|c| c != n[4] && differ(c, n[4]));
+    std::array::from_fn(|k| {
+        let r = HQX_ROTATE[k];
+        let pattern = [0, 1, 2, 3, 5, 6, 7, 8]
+            .iter()
+            .enumerate()
+            .fold(0, |pattern, (bit, &i)| pattern | (diff[r[i]] as usize) << bit);
+        (r.map(|i| n[i]), HQX[pattern])
+    })
+}
+
+// hq2x の左上の角 (q は左上に回した近傍)
+fn hq2x_corner(q: &[Pixel; 9], rule: u8) -> Pixel {
+    let [a, b, _, d, e, f, _, h, _] = *q;
+    match rule {
+        0 => blend(&[(e, 2), (b, 1), (d, 1)]),
+        1 => blend(&[(e, 2), (a, 1), (b, 1)]),
+        2 => blend(&[(e, 2), (a, 1), (d, 1)]),
+        3 => blend(&[(e, 3), (a, 1)]),
+        4 => blend(&[(e, 3), (d, 1)]),
+        5 => blend(&[(e, 3), (b, 1)]),
+        6 | 9 | 10 if differ(b, d) => e,
+        7 | 8 | 11 if differ(b, d) => blend(&[(e, 3), (a, 1)]),
+        6 | 7 => blend(&[(e, 2), (b, 1), (d, 1)]),
+        8 => blend(&[(e, 6), (b, 1), (d, 1)]),
+        9 => blend(&[(e, 14), (b, 1), (d, 1)]),
+        10 | 11 => blend(&[(e, 2), (b, 3), (d, 3)]),
+        12 if differ(b, f) => blend(&[(e, 3), (d, 1)]),
+        12 => blend(&[(e, 5), (b, 2), (d, 1)]),
+        13 if differ(d, h) => blend(&[(e, 3), (b, 1)]),
+        _ => blend(&[(e, 5), (d, 2), (b, 1)]),
+    }
+}
+
+fn hq2x(p: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 4] {
+    let corners = hqx_corners(p);
+    let mut out = [corners[0].0[4]; 4];
+    for ((q, rule), sub) in corners.iter().zip([0, 1, 3, 2]) {
+        out[sub] = hq2x_corner(q, *rule);
+    }
+    out
+}
+
+// hq3x の左上の角と、上・左の辺の中央を変える時はその色 (next, prev は時計回りで次と前の角の番号)
+fn hq3x_corner(q: &[Pixel; 9], rule: u8, next: u8, prev: u8) -> (Pixel, Option<Pixel>, Option<Pixel>) {
+    let [a, b, _, d, e, f, _, h, _] = *q;
+    let corner = |c: Pixel| (c, None, None);
+    match rule {
+        0 => corner(blend(&[(e, 2), (b, 1), (d, 1)])),
+        1..=3 => corner(blend(&[(e, 3), (a, 1)])),
+        4 => corner(blend(&[(e, 3), (d, 1)])),
+        5 => corner(blend(&[(e, 3), (b, 1)])),
+        6 | 9 | 10 if differ(b, d) => corner(e),
+        7 | 8 | 11 if differ(b, d) => corner(blend(&[(e, 3), (a, 1)])),
+        6 | 7 => {
+            // 辺を挟んだ隣の角も b, d で分かれる時は、その辺は中央の色のまま (hq3x.c と同じ)
+            let side = |n: Pixel, other: u8| (!(6..=11).contains(&other)).then(|| blend(&[(e, 7), (n, 1)]));
+            (blend(&[(e, 2), (b, 7), (d, 7)]), side(b, next), side(d, prev))
+        }
+        8 | 9 => corner(blend(&[(e, 2), (b, 1), (d, 1)])),
+        10 | 11 => {
+            // 輪郭が続く隣の角 (次が 13 か前が 12) の側の辺は隣のドットに寄せる
+            let (toward_b, toward_d) = (blend(&[(b, 3), (e, 1)]), blend(&[(d, 3), (e, 1)]));
+            let (near_b, near_d) = (blend(&[(e, 3), (b, 1)]), blend(&[(e, 3), (d, 1)]));
+            let mid = blend(&[(b, 1), (d, 1)]);
+            if next == 13 {
+                (mid, Some(toward_b), Some(near_d))
+            } else {
+                (mid, Some(near_b), Some(toward_d))
+            }
+        }
+        12 if differ(b, f) => corner(blend(&[(e, 3), (d, 1)])),
+        13 if differ(d, h) => corner(blend(&[(e, 3), (b, 1)])),
+        _ => corner(blend(&[(e, 2), (b, 1), (d, 1)])),
+    }
+}
+
+fn hq3x(p: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 9] {
+    let corners = hqx_corners(p);
+    let n = corners[0].0;
+    let e = n[4];
+    // 辺の中央は、隣のドットが同じ色とみなせる時だけ少し寄せる
+    let mut out: [Pixel; 9] = std::array::from_fn(|i| match i {
+        1 | 3 | 5 | 7 if !differ(n[i], e) => blend(&[(e, 3), (n[i], 1)]),
+        _ => e,
+    });
+    // 角の位置と、その角の上の辺 (左上に回した時) の位置。左の辺は前の角の上の辺
+    const CORNER: [usize; 4] = [0, 2, 8, 6];
+    const SIDE: [usize; 4] = [1, 5, 7, 3];
+    for (k, (q, rule)) in corners.iter().enumerate() {
+        let (next, prev) = (corners[(k + 1) % 4].1, corners[(k + 3) % 4].1);
+        let (corner, up, left) = hq3x_corner(q, *rule, next, prev);
+        out[CORNER[k]] = corner;
+        if let Some(c) = up {
+            out[SIDE[k]] = c;
+        }
+        if let Some(c) = left {
+            out[SIDE[(k + 3) % 4]] = c;
+        }
+    }
+    out
+}
+// xBR の色の距離
+fn distance(a: Pixel, b: Pixel) -> f32 {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() * 48.0 + (ua - ub).abs() * 7.0 + (va - vb).abs() * 6.0
+}
+
+// 2xBR (level 1)。右下の角の判定を90度ずつ回して4つの角に使う
+fn xbr2x(p: &impl Fn(isize, isize) -> Pixel) -> [Pixel; 4] {
+    let e = p(0, 0);
+    let mut out = [e; 4];
+    for k in 0..4 {
+        // (dx, dy) を時計回りに k * 90度 回す
+        let rotate = |dx: isize, dy: isize| (0..k).fold((dx, dy), |(x, y), _| (-y, x));
+        let q = |dx: isize, dy: isize| {
+            let (x, y) = rotate(dx, dy);
+            p(x, y)
+        };
+        let (b, c, d, f, g, h, i) = (q(0, -1), q(1, -1), q(-1, 0), q(1, 0), q(-1, 1), q(0, 1), q(1, 1));
+        let (f4, i4, h5, i5) = (q(2, 0), q(2, 1), q(0, 2), q(1, 2));
+
+        // 右下の角を横切る輪郭 (H-F) と、それに交わる向き (E-I) の強さ
+        let across = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + 4.0 * distance(h, f);
+        let along = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + 4.0 * distance(e, i);
+        if across < along && e != f && e != h {
+            let n = if distance(e, f) <= distance(e, h) { f } else { h };
+            let (x, y) = rotate(1, 1);
+            let sub = (if y > 0 { 2 } else { 0 }) + (if x > 0 { 1 } else { 0 });
+            out[sub] = blend(&[(e, 1), (n, 1)]);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale2x_diagonal() {
+        // 左上が白、右下が黒の斜めの境界
+        let w = (255, 255, 255);
+        let k = (0, 0, 0);
+        let src: Vec<u8> = [w, w, k, w, k, k, k, k, k]
+            .iter()
+            .flat_map(|p| [p.0, p.1, p.2])
+            .collect();
+        let mut scaler = Scaler::new(ScaleFilter::Scale2x);
+        let (out, width, height) = scaler.apply(&src, 3, 3);
+        assert_eq!((width, height), (6, 6));
+        let get = |x: usize, y: usize| {
+            let base = (y * width + x) * 3;
+            (out[base], out[base + 1], out[base + 2])
+        };
+        // 中央のドット (黒) の左上だけが白くなる
+        assert_eq!(get(2, 2), w);
+        assert_eq!(get(3, 2), k);
+        assert_eq!(get(2, 3), k);
+        assert_eq!(get(3, 3), k);
+    }
+
+    const W: Pixel = (255, 255, 255);
+    const K: Pixel = (0, 0, 0);
+
+    // 3x3 の画像を拡大して、中央のドットの部分を返す
+    fn scale_center(filter: ScaleFilter, image: [Pixel; 9]) -> Vec<Pixel> {
+        let src: Vec<u8> = image.iter().flat_map(|p| [p.0, p.1, p.2]).collect();
+        let mut scaler = Scaler::new(filter);
+        let scale = filter.scale();
+        let (out, width, _) = scaler.apply(&src, 3, 3);
+        let mut center = vec![];
+        for y in scale..scale * 2 {
+            for x in scale..scale * 2 {
+                let base = (y * width + x) * 3;
+                center.push((out[base], out[base + 1], out[base + 2]));
+            }
+        }
+        center
+    }
+
+    // 左上が白、右下が黒の斜めの境界
+    fn scale_diagonal(filter: ScaleFilter) -> Vec<Pixel> {
+        scale_center(filter, [W, W, K, W, K, K, K, K, K])
+    }
+
+    #[test]
+    fn test_hqx_reference() {
+        let g = |v| (v, v, v);
+        // 右上が白い斜めの境界 (hq2x.c, hq3x.c の case 22)
+        // hq2x は PIXEL01_20、hq3x は PIXEL01_3, PIXEL02_4, PIXEL12_3 になる
+        let image = [K, W, W, K, K, W, K, K, K];
+        assert_eq!(scale_center(ScaleFilter::Hq2x, image), vec![K, g(127), K, K]);
+        assert_eq!(
+            scale_center(ScaleFilter::Hq3x, image),
+            vec![K, g(31), g(223), K, K, g(31), K, K, K]
+        );
+        // 同じ形を回したもの (case 11) は、結果も回した形になる
+        assert_eq!(scale_diagonal(ScaleFilter::Hq2x), vec![g(127), K, K, K]);
+        assert_eq!(
+            scale_diagonal(ScaleFilter::Hq3x),
+            vec![g(223), g(31), K, g(31), K, K, K, K, K]
+        );
+        // 上と右上の輪郭が上の辺に沿って続く (case 19)
+        // hq2x は PIXEL00_60, PIXEL01_90、hq3x は PIXEL00_2, PIXEL01_6, PIXEL02_5, PIXEL12_1 になる
+        let image = [W, W, K, K, K, W, K, K, K];
+        assert_eq!(scale_center(ScaleFilter::Hq2x, image), vec![g(63), g(191), K, K]);
+        assert_eq!(
+            scale_center(ScaleFilter::Hq3x, image),
+            vec![g(63), g(191), W, K, K, g(63), K, K, K]
+        );
+    }
+
+    #[test]
+    fn test_hqx_threshold() {
+        // YUV の差がしきい値 (Y: 48) 以下の色は同じとみなして混ぜる
+        let near = (40, 40, 40);
+        let far = (60, 60, 60);
+        assert_eq!(scale_center(ScaleFilter::Hq2x, [K, near, K, K, K, K, K, K, K])[0], (10, 10, 10));
+        assert_eq!(scale_center(ScaleFilter::Hq2x, [K, far, K, K, K, K, K, K, K])[0], K);
+    }
+
+    #[test]
+    fn test_xbr_diagonal() {
+        // 輪郭を横切る左上の角だけ半分混ぜる
+        assert_eq!(scale_diagonal(ScaleFilter::Xbr), vec![(127, 127, 127), K, K, K]);
+    }
+
+    #[test]
+    fn test_flat_image() {
+        // 一色の画像はどのフィルタでもそのまま
+        for filter in ScaleFilter::CYCLE {
+            let src = vec![0x40; 4 * 4 * 3];
+            let mut scaler = Scaler::new(filter);
+            let (out, _, _) = scaler.apply(&src, 4, 4);
+            assert!(out.iter().all(|c| *c == 0x40), "{}", filter.name());
+        }
+    }
+
+    #[test]
+    fn test_overscan() {
+        assert_eq!(
+            Overscan::parse("8").unwrap(),
+            Overscan {
+                top: 8,
+                bottom: 8,
+                left: 0,
+                right: 0
+            }
+        );
+        assert_eq!(
+            Overscan::parse("8,0,4,2").unwrap(),
+            Overscan {
+                top: 8,
+                bottom: 0,
+                left: 4,
+                right: 2
+            }
+        );
+        assert!(Overscan::parse("8,8,8").is_err());
+        assert!(Overscan::parse("120").is_err());
+
+        // 4x3 の画像の上1ライン、左右1ドットずつ (256ドットに対する割合) を切る
+        let src: Vec<u8> = (0..4 * 3 * 3).map(|n| n as u8).collect();
+        let overscan = Overscan {
+            top: 1,
+            bottom: 0,
+            left: 64,
+            right: 64,
+        };
+        let mut out = vec![];
+        assert_eq!(overscan.crop(&src, 4, 3, &mut out), (2, 2));
+        assert_eq!(out, vec![15, 16, 17, 18, 19, 20, 27, 28, 29, 30, 31, 32]);
+    }
+
+    #[test]
+    fn test_filter_names() {
+        for filter in ScaleFilter::CYCLE {
+            assert_eq!(ScaleFilter::parse(&filter.name()), Some(filter));
+        }
+        assert_eq!(ScaleFilter::parse("nearest4"), Some(ScaleFilter::Nearest(4)));
+        assert_eq!(ScaleFilter::Xbr.next(), ScaleFilter::Nearest(2));
+    }
+}
@@ -0,0 +1,186 @@
+// スクリーンショットをPNGで保存する (F8)
+//
+// 元の256x240の画面を <ROM名>-<日時>-<フレーム番号>.png に書き出す (日時はローカル時刻)
+// PNGのテキストチャンクに ROM名 (Title) とフレーム番号 (Frame) を入れる
+// main.rs では環境変数で保存先等を変えられる
+//
+//   RSCOM_SCREENSHOT_DIR=shots    : 保存先のディレクトリ (省略時はカレントディレクトリ)
+//   RSCOM_SCREENSHOT_FILTERED=1   : NTSCフィルタ、切り取り、拡大後の画面も -filtered.png に保存する
+use crate::frame::Frame;
+use chrono::Local;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub struct Screenshot {
+    pub dir: PathBuf,
+    pub rom_name: String,
+    // 表示しているままの画面も保存する
+    pub also_save_filtered: bool,
+}
+
+impl Screenshot {
+    pub fn new(rom_path: &str) -> Self {
+        let rom_name = Path::new(rom_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "rscom".to_string());
+        Screenshot {
+            dir: PathBuf::from("."),
+            rom_name,
+            also_save_filtered: false,
+        }
+    }
+
+    pub fn from_env(rom_path: &str) -> Self {
+        let mut screenshot = Screenshot::new(rom_path);
+        if let Ok(dir) = env::var("RSCOM_SCREENSHOT_DIR") {
+            screenshot.dir = PathBuf::from(dir);
+        }
+        screenshot.also_save_filtered = env::var("RSCOM_SCREENSHOT_FILTERED").is_ok();
+        screenshot
+    }
+
+    // 元の画面を保存して、そのパスを返す
+    pub fn save_frame(&self, frame: &Frame, frame_number: u64) -> io::Result<PathBuf> {
+        self.save(&frame.data, Frame::WIDTH, Frame::HEIGHT, frame_number, "")
+    }
+
+    // フィルタを通した後の画面 (RGB) を保存する
+    pub fn save_filtered(
+        &self,
+        rgb: &[u8],
+        width: usize,
+        height: usize,
+        frame_number: u64,
+    ) -> io::Result<PathBuf> {
+        self.save(rgb, width, height, frame_number, "-filtered")
+    }
+
+    fn save(
+        &self,
+        rgb: &[u8],
+        width: usize,
+        height: usize,
+        frame_number: u64,
+        suffix: &str,
+    ) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!(
+            "{}-{}-{}{}.png",
+            self.rom_name,
+            Local::now().format("%Y%m%d-%H%M%S"),
+            frame_number,
+            suffix
+        ));
+        let frame_number = frame_number.to_string();
+        let text = [
+            ("Title", self.rom_name.as_str()),
+            ("Frame", frame_number.as_str()),
+            ("Software", "rscom"),
+        ];
+        let png = encode_png(rgb, width, height, &text)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(&path, png)?;
+        Ok(path)
+    }
+}
+
+// RGB (8bit x 3) の画像をPNGにする。text は (キーワード, 値) で、
+// 値が Latin-1 なら tEXt、日本語のROM名等それ以外は UTF-8 の iTXt チャンクにする
+pub fn encode_png(
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+    text: &[(&str, &str)],
+) -> io::Result<Vec<u8>> {
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    for (keyword, value) in text {
+        if value.chars().all(|c| (c as u32) < 0x100) {
+            encoder.add_text_chunk(keyword.to_string(), value.to_string())?;
+        } else {
+            encoder.add_itxt_chunk(keyword.to_string(), value.to_string())?;
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    writer.finish()?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_png() {
+        let mut frame = Frame::new();
+        frame.set_pixel(255, 239, (0x12, 0x34, 0x56));
+        let png = encode_png(
+            &frame.data,
+            Frame::WIDTH,
+            Frame::HEIGHT,
+            &[("Title", "nestest"), ("Frame", "42")],
+        )
+        .unwrap();
+
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(data[..info.buffer_size()], frame.data[..]);
+
+        let text: Vec<(&str, &str)> = reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+            .collect();
+        assert_eq!(text, vec![("Title", "nestest"), ("Frame", "42")]);
+    }
+
+    #[test]
+    fn test_non_latin1_text() {
+        let png = encode_png(
+            &[0; 3],
+            1,
+            1,
+            &[("Title", "スーパーマリオブラザーズ"), ("Frame", "1")],
+        )
+        .unwrap();
+
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.utf8_text.len(), 1);
+        assert_eq!(info.utf8_text[0].keyword, "Title");
+        assert_eq!(
+            info.utf8_text[0].get_text().unwrap(),
+            "スーパーマリオブラザーズ"
+        );
+        assert_eq!(info.uncompressed_latin1_text.len(), 1);
+        assert_eq!(info.uncompressed_latin1_text[0].keyword, "Frame");
+    }
+
+    #[test]
+    fn test_save_non_latin1_rom_name() {
+        let dir = std::env::temp_dir().join(format!("rscom-screenshot-{}", std::process::id()));
+        let mut screenshot = Screenshot::new("roms/ドラゴンクエスト.nes");
+        screenshot.dir = dir.clone();
+        let path = screenshot.save_frame(&Frame::new(), 7).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("ドラゴンクエスト-") && name.ends_with("-7.png"));
+        assert!(fs::metadata(&path).unwrap().len() > 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_size() {
+        assert!(encode_png(&[0; 5], 2, 1, &[]).is_err());
+    }
+}
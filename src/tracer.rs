@@ -0,0 +1,319 @@
+// ファイルに出力するトレースロガー
+//
+// log::trace! (標準エラー) とは別に、実行した命令をファイルに書き出す。
+// main.rs では環境変数で設定する
+//
+//   RSCOM_TRACE=trace.log       : 出力先 (指定すると起動直後からトレースする)
+//   RSCOM_TRACE_PC=C000-CFFF    : PCの範囲で絞る (カンマ区切りで複数可)
+//   RSCOM_TRACE_BANK=0,7        : PRG-ROMのバンク番号で絞る
+//   RSCOM_TRACE_COLUMNS=ppu,cyc,bank : 追加する列
+//   RSCOM_TRACE_RING=10000      : 直近N命令だけ覚えておき、ブレーク・クラッシュ時に書き出す
+//
+// F9 でトレースの開始/停止を切り替える (RSCOM_TRACE が無ければ trace.log に書き出す)
+//
+// 出力例 (bankを指定した時はPCの前にバンク番号が付く。RAM上なら "--")
+//   07:C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+use crate::bus::Bus;
+use crate::cpu::{trace_at, CPU};
+use crate::MAPPER;
+use log::{error, info};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+// RSCOM_TRACE 無しでF9で開始した時の出力先
+pub const DEFAULT_TRACE_PATH: &str = "trace.log";
+
+pub struct TraceLogger {
+    // 範囲はどちらも両端を含む。空なら絞らない
+    pub pc_ranges: Vec<(u16, u16)>,
+    pub banks: Vec<usize>,
+    pub show_ppu: bool,
+    pub show_cycles: bool,
+    pub show_bank: bool,
+    pub enabled: bool,
+    // SDLのホットキー等から立てると次の命令で開始/停止を切り替える
+    pub toggle_request: Rc<Cell<bool>>,
+
+    writer: Option<BufWriter<File>>,
+    // リングバッファモードの時のみ Some
+    ring: Option<VecDeque<String>>,
+    ring_size: usize,
+}
+
+impl Default for TraceLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceLogger {
+    pub fn new() -> Self {
+        TraceLogger {
+            pc_ranges: vec![],
+            banks: vec![],
+            show_ppu: false,
+            show_cycles: false,
+            show_bank: false,
+            enabled: false,
+            toggle_request: Rc::new(Cell::new(false)),
+            writer: None,
+            ring: None,
+            ring_size: 0,
+        }
+    }
+
+    // 環境変数から作る (RSCOM_TRACE が無ければF9で開始するまで無効)
+    pub fn from_env() -> io::Result<Self> {
+        let mut tracer = TraceLogger::new();
+
+        if let Ok(ranges) = env::var("RSCOM_TRACE_PC") {
+            tracer.pc_ranges = parse_ranges(&ranges).map_err(invalid_input)?;
+        }
+        if let Ok(banks) = env::var("RSCOM_TRACE_BANK") {
+            tracer.banks = banks
+                .split(',')
+                .map(|b| b.trim().parse().map_err(|_| format!("invalid bank '{}'", b)))
+                .collect::<Result<_, _>>()
+                .map_err(invalid_input)?;
+        }
+        if let Ok(columns) = env::var("RSCOM_TRACE_COLUMNS") {
+            for column in columns.split(',') {
+                match column.trim() {
+                    "ppu" => tracer.show_ppu = true,
+                    "cyc" => tracer.show_cycles = true,
+                    "bank" => tracer.show_bank = true,
+                    "" => {}
+                    c => return Err(invalid_input(format!("unknown column '{}'", c))),
+                }
+            }
+        }
+        if let Ok(size) = env::var("RSCOM_TRACE_RING") {
+            let size = size
+                .trim()
+                .parse()
+                .map_err(|_| invalid_input(format!("invalid ring size '{}'", size)))?;
+            tracer.set_ring(size);
+        }
+        if let Ok(path) = env::var("RSCOM_TRACE") {
+            tracer.open(&path)?;
+            tracer.enabled = true;
+        }
+        Ok(tracer)
+    }
+
+    pub fn open(&mut self, path: &str) -> io::Result<()> {
+        self.writer = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    // 0ならリングバッファを使わずに毎命令書き出す
+    pub fn set_ring(&mut self, size: usize) {
+        self.ring_size = size;
+        self.ring = if size > 0 {
+            Some(VecDeque::with_capacity(size))
+        } else {
+            None
+        };
+    }
+
+    pub fn start(&mut self) {
+        if self.writer.is_none() {
+            if let Err(e) = self.open(DEFAULT_TRACE_PATH) {
+                error!("can't start trace: {}: {}", DEFAULT_TRACE_PATH, e);
+                return;
+            }
+            info!("trace: {}", DEFAULT_TRACE_PATH);
+        }
+        self.enabled = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.enabled = false;
+        self.flush();
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().ok();
+        }
+    }
+
+    // step_with_callback()のコールバック内 (オペコードをフェッチした後) で呼ぶ
+    pub fn log(&mut self, cpu: &CPU<Bus>) {
+        if self.toggle_request.replace(false) {
+            if self.enabled {
+                self.stop();
+            } else {
+                self.start();
+            }
+        }
+        if !self.enabled || self.writer.is_none() {
+            return;
+        }
+
+        let pc = cpu.program_counter.wrapping_sub(1);
+        if !self.pc_matches(pc) {
+            return;
+        }
+        let bank = if self.show_bank || !self.banks.is_empty() {
+            MAPPER.lock().unwrap().prg_bank(pc)
+        } else {
+            None
+        };
+        if !self.bank_matches(bank) {
+            return;
+        }
+
+        let mut line = String::new();
+        if self.show_bank {
+            match bank {
+                Some(bank) => line.push_str(&format!("{:02X}:", bank)),
+                None => line.push_str("--:"),
+            }
+        }
+        line.push_str(&trace_at(cpu, pc));
+        if self.show_ppu {
+            let ppu = cpu.bus.ppu();
+            line.push_str(&format!(" PPU:{:>3},{:>3}", ppu.scanline(), ppu.dot()));
+        }
+        if self.show_cycles {
+            line.push_str(&format!(" CYC:{}", cpu.bus.cycles()));
+        }
+        self.record(line);
+    }
+
+    fn pc_matches(&self, pc: u16) -> bool {
+        self.pc_ranges.is_empty() || self.pc_ranges.iter().any(|(s, e)| *s <= pc && pc <= *e)
+    }
+
+    // バンクで絞っている時、RAM上 (None) の命令は出さない
+    fn bank_matches(&self, bank: Option<usize>) -> bool {
+        self.banks.is_empty() || bank.map(|b| self.banks.contains(&b)).unwrap_or(false)
+    }
+
+    // リングバッファモードなら溜めておき、それ以外はすぐに書き出す
+    fn record(&mut self, line: String) {
+        match &mut self.ring {
+            Some(ring) => {
+                if ring.len() == self.ring_size {
+                    ring.pop_front();
+                }
+                ring.push_back(line);
+            }
+            None => {
+                if let Some(writer) = &mut self.writer {
+                    writeln!(writer, "{}", line).ok();
+                }
+            }
+        }
+    }
+
+    // リングバッファの中身を書き出す (ブレーク・クラッシュ時)
+    pub fn dump(&mut self, reason: &str) {
+        let (ring, writer) = match (&mut self.ring, &mut self.writer) {
+            (Some(ring), Some(writer)) => (ring, writer),
+            _ => {
+                self.flush();
+                return;
+            }
+        };
+        writeln!(writer, "---- {} (last {} instructions) ----", reason, ring.len()).ok();
+        for line in ring.drain(..) {
+            writeln!(writer, "{}", line).ok();
+        }
+        writer.flush().ok();
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let hex = s.trim().trim_start_matches('$');
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid address '{}'", s))
+}
+
+// "C000-CFFF,E000" => [(C000, CFFF), (E000, E000)]
+pub fn parse_ranges(s: &str) -> Result<Vec<(u16, u16)>, String> {
+    s.split(',')
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| match r.split_once('-') {
+            Some((start, end)) => Ok((parse_addr(start)?, parse_addr(end)?)),
+            None => parse_addr(r).map(|addr| (addr, addr)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            parse_ranges("C000-CFFF, $E000 ,"),
+            Ok(vec![(0xC000, 0xCFFF), (0xE000, 0xE000)])
+        );
+        assert_eq!(parse_ranges(""), Ok(vec![]));
+        assert!(parse_ranges("C000-").is_err());
+        assert!(parse_ranges("10000").is_err());
+        assert!(parse_ranges("G000").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut tracer = TraceLogger::new();
+        assert!(tracer.pc_matches(0x0000));
+        assert!(tracer.bank_matches(None));
+
+        tracer.pc_ranges = vec![(0xC000, 0xCFFF), (0xE000, 0xE000)];
+        assert!(tracer.pc_matches(0xC000));
+        assert!(tracer.pc_matches(0xCFFF));
+        assert!(tracer.pc_matches(0xE000));
+        assert!(!tracer.pc_matches(0xBFFF));
+        assert!(!tracer.pc_matches(0xE001));
+
+        tracer.banks = vec![0, 7];
+        assert!(tracer.bank_matches(Some(7)));
+        assert!(!tracer.bank_matches(Some(1)));
+        assert!(!tracer.bank_matches(None));
+    }
+
+    #[test]
+    fn test_ring_buffer_dump() {
+        let path = env::temp_dir().join(format!("rscom_trace_test_{}.log", std::process::id()));
+        let mut tracer = TraceLogger::new();
+        tracer.open(path.to_str().unwrap()).unwrap();
+        tracer.set_ring(3);
+        for n in 0..5 {
+            tracer.record(format!("line {}", n));
+        }
+        // ダンプするまでは書き出さない
+        tracer.flush();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        tracer.dump("break");
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            "---- break (last 3 instructions) ----\nline 2\nline 3\nline 4\n"
+        );
+        // 書き出した分は空になる
+        tracer.dump("again");
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with("---- again (last 0 instructions) ----\n"));
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,413 @@
+// PPUの中身を見るビューアのウィンドウ (ROMハック、デバッグ用)
+//
+//   F1 : ネームテーブル4枚 (赤枠は今のスクロール位置)
+//   F2 : パターンテーブル2枚 (F5 で色に使うパレットを切り替える)
+//   F3 : OAM 64個 (スプライトと Y, タイル, 属性, X)
+//   F4 : パレットRAM 32色
+//
+// 表示はスキャンライン RSCOM_VIEWER_SCANLINE (省略時は241 = VBlankの始め) の時点の内容
+// (NTSCは0～261、PAL/Dendyは0～311。ビューアを表示している間だけ取る)
+use crate::palette::Palette;
+use crate::ppu::PpuSnapshot;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
+use sdl2::VideoSubsystem;
+
+type Pixel = (u8, u8, u8);
+
+const SCROLL_FRAME_COLOR: Pixel = (0xFF, 0x00, 0x00);
+const TEXT_COLOR: Pixel = (0xFF, 0xFF, 0xFF);
+const GRID_COLOR: Pixel = (0x40, 0x40, 0x40);
+
+// RGBの画像
+pub struct Image {
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            data: vec![0; width * height * 3],
+            width,
+            height,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Pixel {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    fn set(&mut self, x: usize, y: usize, rgb: Pixel) {
+        if x < self.width && y < self.height {
+            let base = (y * self.width + x) * 3;
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, rgb: Pixel) {
+        for dy in 0..height {
+            for dx in 0..width {
+                self.set(x + dx, y + dy, rgb);
+            }
+        }
+    }
+
+    // 3x5ドットの16進数
+    fn draw_hex(&mut self, x: usize, y: usize, value: u8, rgb: Pixel) {
+        for (n, digit) in [value >> 4, value & 0x0F].iter().enumerate() {
+            let glyph = HEX_FONT[*digit as usize];
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        self.set(x + n * 4 + col, y + row, rgb);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[rustfmt::skip]
+const HEX_FONT: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111], [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111], [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001], [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111], [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111], [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b111, 0b101, 0b111, 0b101, 0b101], [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b111, 0b100, 0b100, 0b100, 0b111], [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111], [0b111, 0b100, 0b111, 0b100, 0b100],
+];
+
+// 4枚のネームテーブルを 512x480 に並べ、スクロール位置に枠を描く
+pub fn nametable_image(snapshot: &PpuSnapshot, palette: &Palette) -> Image {
+    let mut image = Image::new(512, 480);
+    let bank = snapshot.ctrl().background_pattern_addr();
+    for (n, nametable) in snapshot.nametables.iter().enumerate() {
+        let (base_x, base_y) = ((n % 2) * 256, (n / 2) * 240);
+        for i in 0..0x3C0 {
+            let (column, row) = (i % 32, i / 32);
+            let attr = nametable[0x3C0 + row / 4 * 8 + column / 4];
+            let shift = (row % 4 / 2) * 4 + (column % 4 / 2) * 2;
+            let palette_idx = (attr >> shift) & 0b11;
+            let tile_addr = bank + nametable[i] as u16 * 16;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let value = snapshot.tile_pixel(tile_addr, y, x);
+                    let entry = if value == 0 {
+                        0
+                    } else {
+                        palette_idx as usize * 4 + value as usize
+                    };
+                    let rgb = palette.colors[snapshot.palette_entry(entry) as usize];
+                    image.set(base_x + column * 8 + x, base_y + row * 8 + y, rgb);
+                }
+            }
+        }
+    }
+
+    // スクロールの枠 (512x480 で折り返す)
+    let nametable = (snapshot.ctrl().nametable_addr() - 0x2000) / 0x400;
+    let left = (nametable as usize % 2) * 256 + snapshot.scroll_x as usize;
+    let top = (nametable as usize / 2) * 240 + snapshot.scroll_y as usize;
+    for d in 0..256 {
+        image.set((left + d) % 512, top % 480, SCROLL_FRAME_COLOR);
+        image.set((left + d) % 512, (top + 239) % 480, SCROLL_FRAME_COLOR);
+    }
+    for d in 0..240 {
+        image.set(left % 512, (top + d) % 480, SCROLL_FRAME_COLOR);
+        image.set((left + 255) % 512, (top + d) % 480, SCROLL_FRAME_COLOR);
+    }
+    image
+}
+
+// $0000 と $1000 のパターンテーブルを 256x128 に並べる
+// palette_idx は 0～3 が背景、4～7 がスプライトのパレット
+pub fn pattern_table_image(snapshot: &PpuSnapshot, palette: &Palette, palette_idx: u8) -> Image {
+    let mut image = Image::new(256, 128);
+    for table in 0..2 {
+        for tile in 0..256 {
+            let addr = (table * 0x1000 + tile * 16) as u16;
+            let (base_x, base_y) = (table * 128 + (tile % 16) * 8, (tile / 16) * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let value = snapshot.tile_pixel(addr, y, x) as usize;
+                    let entry = if value == 0 {
+                        0
+                    } else {
+                        (palette_idx as usize % 8) * 4 + value
+                    };
+                    let rgb = palette.colors[snapshot.palette_entry(entry) as usize];
+                    image.set(base_x + x, base_y + y, rgb);
+                }
+            }
+        }
+    }
+    image
+}
+
+// OAMの1個分の大きさ (スプライト + "YY TT AA XX")
+const OAM_CELL_WIDTH: usize = 60;
+const OAM_CELL_HEIGHT: usize = 20;
+
+// 64個のスプライトを 4列 x 16行 に並べ、横に Y, タイル, 属性, X を書く
+pub fn oam_image(snapshot: &PpuSnapshot, palette: &Palette) -> Image {
+    let mut image = Image::new(OAM_CELL_WIDTH * 4, OAM_CELL_HEIGHT * 16);
+    let ctrl = snapshot.ctrl();
+    let height = if ctrl.is_sprite_8x16_mode() { 16 } else { 8 };
+    for n in 0..64 {
+        let entry = &snapshot.oam[n * 4..n * 4 + 4];
+        let (tile_idx, attr) = (entry[1], entry[2]);
+        let (base_x, base_y) = ((n % 4) * OAM_CELL_WIDTH, (n / 4) * OAM_CELL_HEIGHT);
+        image.fill(base_x + 1, base_y + 1, 10, 18, GRID_COLOR);
+
+        for row in 0..height {
+            let flipped_row = if attr & 0x80 != 0 {
+                height - 1 - row
+            } else {
+                row
+            };
+            let addr = if height == 16 {
+                let bank: u16 = if tile_idx & 1 == 0 { 0x0000 } else { 0x1000 };
+                bank + ((tile_idx & 0xFE) as u16 + (flipped_row / 8) as u16) * 16
+            } else {
+                ctrl.sprite_pattern_addr() + tile_idx as u16 * 16
+            };
+            for col in 0..8 {
+                let flipped_col = if attr & 0x40 != 0 { 7 - col } else { col };
+                let value = snapshot.tile_pixel(addr, flipped_row % 8, flipped_col) as usize;
+                if value != 0 {
+                    let entry = 0x10 + (attr & 0b11) as usize * 4 + value;
+                    let rgb = palette.colors[snapshot.palette_entry(entry) as usize];
+                    image.set(base_x + 2 + col, base_y + 2 + row, rgb);
+                }
+            }
+        }
+        for (i, value) in entry.iter().enumerate() {
+            image.draw_hex(base_x + 14 + i * 11, base_y + 7, *value, TEXT_COLOR);
+        }
+    }
+    image
+}
+
+// パレットRAMの32色を 16色 x 2段 に並べる (上が背景、下がスプライト)
+pub fn palette_image(snapshot: &PpuSnapshot, palette: &Palette) -> Image {
+    const SWATCH: usize = 16;
+    let mut image = Image::new(SWATCH * 16, SWATCH * 2);
+    for index in 0..32 {
+        let rgb = palette.colors[(snapshot.palette[index] & 0x3F) as usize];
+        let (x, y) = ((index % 16) * SWATCH, (index / 16) * SWATCH);
+        image.fill(x, y, SWATCH, SWATCH, rgb);
+        image.draw_hex(x + 4, y + 5, snapshot.palette[index], TEXT_COLOR);
+    }
+    image
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewerKind {
+    Nametable,
+    PatternTable,
+    Oam,
+    Palette,
+}
+
+struct ViewerWindow {
+    kind: ViewerKind,
+    canvas: WindowCanvas,
+    visible: bool,
+}
+
+// ビューアのウィンドウ毎のテクスチャ (大きさは変わらないので、最初に表示した時に1回だけ作る)
+// テクスチャは TextureCreator より長く生きられないので、Viewers とは別に持つ
+pub struct ViewerTextures<'a> {
+    creators: &'a [TextureCreator<WindowContext>],
+    textures: Vec<Option<Texture<'a>>>,
+}
+
+impl<'a> ViewerTextures<'a> {
+    // creators は Viewers::texture_creators() で作ったもの
+    pub fn new(creators: &'a [TextureCreator<WindowContext>]) -> Self {
+        ViewerTextures {
+            creators,
+            textures: creators.iter().map(|_| None).collect(),
+        }
+    }
+
+    fn get(&mut self, n: usize, image: &Image) -> Option<&mut Texture<'a>> {
+        if self.textures[n].is_none() {
+            let texture = self.creators[n]
+                .create_texture_streaming(
+                    PixelFormatEnum::RGB24,
+                    image.width as u32,
+                    image.height as u32,
+                )
+                .ok()?;
+            self.textures[n] = Some(texture);
+        }
+        self.textures[n].as_mut()
+    }
+}
+
+// ビューアのウィンドウ一式 (最初は全部隠しておく)
+pub struct Viewers {
+    windows: Vec<ViewerWindow>,
+    // パターンテーブルの色に使うパレット (0～7)
+    pub pattern_palette: u8,
+}
+
+impl Viewers {
+    pub fn new(video: &VideoSubsystem) -> Result<Self, String> {
+        let specs = [
+            (ViewerKind::Nametable, "Nametables", 512, 480),
+            (ViewerKind::PatternTable, "Pattern tables", 512, 256),
+            (
+                ViewerKind::Oam,
+                "OAM",
+                OAM_CELL_WIDTH as u32 * 8,
+                OAM_CELL_HEIGHT as u32 * 32,
+            ),
+            (ViewerKind::Palette, "Palette", 512, 64),
+        ];
+        let mut windows = vec![];
+        for (kind, title, width, height) in specs {
+            let window = video
+                .window(title, width, height)
+                .hidden()
+                .build()
+                .map_err(|e| e.to_string())?;
+            let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+            windows.push(ViewerWindow {
+                kind,
+                canvas,
+                visible: false,
+            });
+        }
+        Ok(Viewers {
+            windows,
+            pattern_palette: 0,
+        })
+    }
+
+    // ウィンドウ毎の TextureCreator (ViewerTextures に渡す)
+    pub fn texture_creators(&self) -> Vec<TextureCreator<WindowContext>> {
+        self.windows
+            .iter()
+            .map(|w| w.canvas.texture_creator())
+            .collect()
+    }
+
+    // どれかが表示中ならスナップショットを取る必要がある
+    pub fn is_active(&self) -> bool {
+        self.windows.iter().any(|w| w.visible)
+    }
+
+    pub fn toggle(&mut self, kind: ViewerKind) {
+        if let Some(w) = self.windows.iter_mut().find(|w| w.kind == kind) {
+            w.visible = !w.visible;
+            if w.visible {
+                w.canvas.window_mut().show();
+            } else {
+                w.canvas.window_mut().hide();
+            }
+        }
+    }
+
+    // ビューアのウィンドウの閉じるボタンなら隠してtrue
+    pub fn close(&mut self, window_id: u32) -> bool {
+        match self
+            .windows
+            .iter_mut()
+            .find(|w| w.canvas.window().id() == window_id)
+        {
+            Some(w) => {
+                w.visible = false;
+                w.canvas.window_mut().hide();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        snapshot: &PpuSnapshot,
+        palette: &Palette,
+        textures: &mut ViewerTextures,
+    ) {
+        for (n, w) in self.windows.iter_mut().enumerate() {
+            if !w.visible {
+                continue;
+            }
+            let image = match w.kind {
+                ViewerKind::Nametable => nametable_image(snapshot, palette),
+                ViewerKind::PatternTable => {
+                    pattern_table_image(snapshot, palette, self.pattern_palette)
+                }
+                ViewerKind::Oam => oam_image(snapshot, palette),
+                ViewerKind::Palette => palette_image(snapshot, palette),
+            };
+            let texture = match textures.get(n, &image) {
+                Some(texture) => texture,
+                None => continue,
+            };
+            texture.update(None, &image.data, image.width * 3).ok();
+            w.canvas.copy(texture, None, None).ok();
+            w.canvas.present();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot() -> PpuSnapshot {
+        PpuSnapshot {
+            scanline: 241,
+            nametables: vec![[0; 0x400]; 4],
+            chr: vec![0; 0x2000],
+            oam: [0xFF; 256],
+            palette: [0x0F; 32],
+            ctrl: 0,
+            scroll_x: 0,
+            scroll_y: 0,
+        }
+    }
+
+    #[test]
+    fn test_nametable_scroll_frame() {
+        let mut snapshot = snapshot();
+        // 右下のネームテーブル ($2C00) から (8, 16) ずらした位置
+        snapshot.ctrl = 0b11;
+        snapshot.scroll_x = 8;
+        snapshot.scroll_y = 16;
+        let image = nametable_image(&snapshot, &Palette::default());
+        assert_eq!(image.get(256 + 8, 240 + 16), SCROLL_FRAME_COLOR);
+        // 右端と下端は512x480で折り返す
+        assert_eq!(
+            image.get((256 + 8 + 255) % 512, 240 + 100),
+            SCROLL_FRAME_COLOR
+        );
+        assert_eq!(image.get(300, (240 + 16 + 239) % 480), SCROLL_FRAME_COLOR);
+        assert_ne!(image.get(100, 100), SCROLL_FRAME_COLOR);
+    }
+
+    #[test]
+    fn test_pattern_table_palette() {
+        let mut snapshot = snapshot();
+        // $1000 のタイル0の左上のドットを色番号3に
+        snapshot.chr[0x1000] = 0x80;
+        snapshot.chr[0x1008] = 0x80;
+        snapshot.palette[4 * 5 + 3] = 0x16;
+        let palette = Palette::default();
+        let image = pattern_table_image(&snapshot, &palette, 5);
+        assert_eq!(image.get(128, 0), palette.colors[0x16]);
+        assert_eq!(image.get(129, 0), palette.colors[0x0F]);
+    }
+}
@@ -0,0 +1,140 @@
+// GDBスタブをlocalhostで立てて、スクリプトのクライアントからパケットを送って確認する
+use rscom::bus::Mem;
+use rscom::cpu::CPU;
+use rscom::gdb::GdbStub;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+struct RamBus {
+    ram: Vec<u8>,
+}
+
+impl Mem for RamBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn read_byte(&mut self) -> u8 {
+        let mut buf = [0u8; 1];
+        self.stream.read_exact(&mut buf).expect("connection closed");
+        buf[0]
+    }
+
+    fn send(&mut self, data: &str) {
+        let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+        assert_eq!(self.read_byte(), b'+', "no ack for {}", data);
+    }
+
+    fn reply(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+        let mut data = vec![];
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        let sum = [self.read_byte(), self.read_byte()];
+        let sum = u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap();
+        assert_eq!(sum, data.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    fn request(&mut self, data: &str) -> String {
+        self.send(data);
+        self.reply()
+    }
+}
+
+#[test]
+fn gdb_stub_scripted_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let mut stub = GdbStub::accept(&listener).unwrap();
+        let mut cpu = CPU::new(RamBus {
+            ram: vec![0xEA; 0x10000],
+        });
+        while stub.is_attached() {
+            stub.hook(&mut cpu);
+            if !stub.is_attached() {
+                break;
+            }
+            cpu.step();
+        }
+    });
+
+    let mut gdb = Client {
+        stream: TcpStream::connect(addr).unwrap(),
+    };
+    // 返信が来ない時はハングせずに失敗させる
+    gdb.stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    assert!(gdb.request("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+    assert!(gdb.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    assert_eq!(gdb.request("?"), "S05");
+
+    // 0200: LDA #$42 / STA $10 / INX / JMP $0204
+    assert_eq!(gdb.request("M200,8:a9428510e84c0402"), "OK");
+    assert_eq!(gdb.request("m200,8"), "a9428510e84c0402");
+    assert_eq!(gdb.request("P5=0002"), "OK");
+    assert_eq!(gdb.request("p5"), "0002");
+
+    // 1命令実行
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.request("g"), "42000024fd0202");
+
+    // ブレークポイントまで実行
+    assert_eq!(gdb.request("Z0,204,1"), "OK");
+    assert_eq!(gdb.request("c"), "S05");
+    assert_eq!(gdb.request("p5"), "0402");
+    assert_eq!(gdb.request("m10,1"), "42");
+    assert_eq!(gdb.request("c"), "S05");
+    assert_eq!(gdb.request("p1"), "01");
+
+    // レジスタ書き込み
+    assert_eq!(gdb.request("P0=7f"), "OK");
+    assert_eq!(gdb.request("p0"), "7f");
+    assert_eq!(gdb.request("p9"), "E01");
+
+    // Ctrl-C で止める
+    assert_eq!(gdb.request("z0,204,1"), "OK");
+    gdb.send("c");
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.reply(), "S02");
+
+    // 返信のackの代わりに次のパケットが来ても、その先頭を取りこぼさない
+    gdb.send("p1");
+    while gdb.read_byte() != b'#' {}
+    gdb.read_byte();
+    gdb.read_byte();
+    assert_eq!(gdb.request("p0"), "7f");
+
+    assert_eq!(gdb.request("D"), "OK");
+    server.join().unwrap();
+}
@@ -0,0 +1,96 @@
+// nestest.nes のゴールデンログ(nestest.log)との比較テスト。
+//
+// ROMとログは同梱していないので、ローカルに用意して下記のように実行する。
+//
+//   RSCOM_NESTEST_ROM=rom/nestest.nes RSCOM_NESTEST_LOG=rom/nestest.log \
+//       cargo test --test nestest -- --nocapture
+//
+// RSCOM_NESTEST_ROM : テストROM (未指定ならスキップ)
+// RSCOM_NESTEST_LOG : 比較するリファレンスログ
+// RSCOM_NESTEST_PC  : 開始PC (16進, 省略時 C000 = nestestの自動テストモード)
+//
+// 1命令毎に trace_with_timing() の出力とログの行を比較し、
+// 最初に食い違った所で直前の数行と一緒に差分を出して止まる。
+use rscom::apu::APU;
+use rscom::bus::{Bus, Mem};
+use rscom::cartridge::load_rom;
+use rscom::cpu::{trace_with_timing, CPU};
+use rscom::MAPPER;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+
+const CONTEXT_LINES: usize = 5;
+
+fn diff_column(expected: &str, actual: &str) -> usize {
+    expected
+        .chars()
+        .zip(actual.chars())
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()))
+}
+
+#[test]
+fn nestest_golden_log() {
+    let rom_path = match env::var("RSCOM_NESTEST_ROM") {
+        Ok(path) => path,
+        Err(_) => {
+            println!("RSCOM_NESTEST_ROM is not set. skip nestest.");
+            return;
+        }
+    };
+    let log_path = env::var("RSCOM_NESTEST_LOG").expect("RSCOM_NESTEST_LOG is not set");
+    let start_pc = env::var("RSCOM_NESTEST_PC")
+        .map(|pc| u16::from_str_radix(pc.trim_start_matches('$'), 16).expect("RSCOM_NESTEST_PC"))
+        .unwrap_or(0xC000);
+
+    let reference = fs::read_to_string(&log_path).expect("can't read reference log");
+
+    let rom = load_rom(&rom_path);
+    MAPPER.lock().unwrap().set_rom(&rom);
+    let bus = Bus::new(rom, APU::new_headless(), |_, _| {});
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    cpu.program_counter = start_pc;
+
+    let mut history: VecDeque<String> = VecDeque::new();
+    for (i, expected) in reference.lines().enumerate() {
+        let expected = expected.trim_end();
+        if expected.is_empty() {
+            continue;
+        }
+
+        let mut actual = String::new();
+        cpu.step_with_callback(&mut |cpu| {
+            actual = trace_with_timing(cpu);
+        });
+
+        if actual != expected {
+            let mut msg = format!("nestest diverged at line {}\n", i + 1);
+            for line in history.iter() {
+                msg.push_str(&format!("      {}\n", line));
+            }
+            msg.push_str(&format!("  exp {}\n", expected));
+            msg.push_str(&format!("  got {}\n", actual));
+            msg.push_str(&format!("      {}^", " ".repeat(diff_column(expected, &actual))));
+            panic!("{}", msg);
+        }
+
+        history.push_back(actual);
+        if history.len() > CONTEXT_LINES {
+            history.pop_front();
+        }
+
+        if cpu.is_jammed() {
+            break;
+        }
+    }
+
+    // nestestは $02/$03 にエラーコードを残す (00 = OK)
+    let official = cpu.peek(0x0002);
+    let unofficial = cpu.peek(0x0003);
+    println!(
+        "nestest result: $02={:02X} $03={:02X}",
+        official, unofficial
+    );
+}
@@ -0,0 +1,400 @@
+// 自己診断しないゲームROM用のスクリーンショット(フレームハッシュ)回帰テスト。
+//
+// スクリプト通りの入力でNフレーム動かし、指定フレームの Frame をハッシュして
+// tests/screenshot/hashes.txt と比較する。
+//
+// "pattern" はこのファイルの pattern_rom() で組み立てるテストROM
+// (背景、属性、パレット、スプライト、OAM DMA、横スクロール、パッド入力) で、常に比較する。
+// common.rs の [動作OK] のゲームはROMを同梱できないので、rom/nes/ 以下に無いタイトルはスキップする。
+// ゲームのハッシュはROMを持っている人が RSCOM_SCREENSHOT_UPDATE で追加する。
+//
+//   cargo test --release --test screenshot -- --nocapture
+//
+// RSCOM_SCREENSHOT_UPDATE : 指定するとハッシュを比較せずに hashes.txt を書き換える
+// RSCOM_SCREENSHOT_ONLY   : 対象タイトルを絞る (例: "galaga,xevious")
+//
+// 不一致のフレームは target/screenshot/<名前>_<フレーム>.ppm に書き出す
+// 1フレームも比較しなかった時 (全部スキップした時) は失敗にする
+use rscom::apu::APU;
+use rscom::bus::Bus;
+use rscom::cartridge::load_rom;
+use rscom::cpu::CPU;
+use rscom::frame::Frame;
+use rscom::gamepad::{Button, GamePad};
+use rscom::mapper::MapperMMC;
+use rscom::ppu::PPU;
+use rscom::{render, MAPPER};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const HASH_FILE: &str = "tests/screenshot/hashes.txt";
+const DUMP_DIR: &str = "target/screenshot";
+const PATTERN_ROM: &str = "target/screenshot/pattern.nes";
+
+// 無限ループ対策 (NMIが10秒来なかったら諦める)
+const NMI_TIMEOUT_CYCLES: usize = 29_781 * 600;
+
+const BUTTONS: [Button; 8] = [
+    Button::RIGHT,
+    Button::LEFT,
+    Button::DOWN,
+    Button::UP,
+    Button::START,
+    Button::SELECT,
+    Button::BUTTON_B,
+    Button::BUTTON_A,
+];
+
+// (開始フレーム, 終了フレーム, ボタン) 開始 <= フレーム < 終了 の間ボタンを押す
+type Input = (u32, u32, Button);
+
+struct Case {
+    name: &'static str,
+    rom: &'static str,
+    input: &'static [Input],
+    shots: &'static [u32],
+}
+
+// タイトル画面 → STARTでゲーム開始、を撮る共通スクリプト
+const PRESS_START: &[Input] = &[(120, 126, Button::START)];
+const SHOTS: &[u32] = &[60, 180, 360];
+
+const CASES: &[Case] = &[
+    Case {
+        name: "pattern",
+        rom: PATTERN_ROM,
+        // 右を押している間スクロールする
+        input: &[(30, 90, Button::RIGHT)],
+        shots: &[10, 60, 120],
+    },
+    // [Mapper0]
+    Case { name: "Alter_Ego", rom: "rom/nes/mapper_0/Alter_Ego.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "BombSweeper", rom: "rom/nes/mapper_0/BombSweeper.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "donkeykong", rom: "rom/nes/mapper_0/donkeykong.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "elevatoraction", rom: "rom/nes/mapper_0/elevatoraction.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "excitebike", rom: "rom/nes/mapper_0/excitebike.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "galaga", rom: "rom/nes/mapper_0/galaga.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "mario_bros", rom: "rom/nes/mapper_0/mario_bros.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "pacman", rom: "rom/nes/mapper_0/pacman.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "popeye", rom: "rom/nes/mapper_0/popeye.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "ikki", rom: "rom/nes/mapper_0/ikki.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "sky_destroyer", rom: "rom/nes/mapper_0/sky_destroyer.nes", input: PRESS_START, shots: SHOTS },
+    Case {
+        name: "Super_Mario_Bros",
+        rom: "rom/nes/mapper_0/Super_Mario_Bros.nes",
+        // スタートして右に走ってジャンプ (スクロールとスプライト0ヒット)
+        input: &[
+            (120, 126, Button::START),
+            (200, 400, Button::RIGHT),
+            (300, 320, Button::BUTTON_A),
+        ],
+        shots: &[60, 180, 300, 400],
+    },
+    Case { name: "tower_of_druaga", rom: "rom/nes/mapper_0/tower_of_druaga.nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "xevious", rom: "rom/nes/mapper_0/xevious.nes", input: PRESS_START, shots: SHOTS },
+    // [MapperMMC 2]
+    Case { name: "Dragon_Quest_2", rom: "rom/nes/Dragon Quest 2 (J).nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "Makaimura", rom: "rom/nes/Makaimura (J).nes", input: PRESS_START, shots: SHOTS },
+    Case { name: "Rockman", rom: "rom/nes/Rockman (J).nes", input: PRESS_START, shots: SHOTS },
+    // [MapperMMC 3]
+    Case { name: "Dragon_Quest", rom: "rom/nes/Dragon Quest.nes", input: PRESS_START, shots: SHOTS },
+];
+
+// テストROMを組み立てる6502のコード (後ろへの分岐だけ使う)
+struct Asm {
+    code: Vec<u8>,
+}
+
+impl Asm {
+    const ORIGIN: u16 = 0xC000;
+
+    fn here(&self) -> u16 {
+        Asm::ORIGIN + self.code.len() as u16
+    }
+
+    fn op(&mut self, bytes: &[u8]) -> &mut Self {
+        self.code.extend_from_slice(bytes);
+        self
+    }
+
+    fn abs(&mut self, opcode: u8, addr: u16) -> &mut Self {
+        let [lo, hi] = addr.to_le_bytes();
+        self.op(&[opcode, lo, hi])
+    }
+
+    fn branch_back(&mut self, opcode: u8, target: u16) -> &mut Self {
+        let offset = target as i32 - (self.here() as i32 + 2);
+        self.op(&[opcode, offset as i8 as u8])
+    }
+}
+
+// NROM (PRG 16KB, CHR 8KB, 垂直ミラー) のテストROM
+#[rustfmt::skip]
+fn pattern_rom() -> Vec<u8> {
+    const PALETTE: [u8; 32] = [
+        0x0F, 0x16, 0x27, 0x30, 0x0F, 0x1A, 0x2A, 0x3A, 0x0F, 0x12, 0x22, 0x32, 0x0F, 0x05, 0x15, 0x25,
+        0x0F, 0x30, 0x26, 0x16, 0x0F, 0x21, 0x11, 0x01, 0x0F, 0x29, 0x19, 0x09, 0x0F, 0x24, 0x14, 0x04,
+    ];
+    // Y, タイル, 属性, X (スプライト0は毎フレーム右に動かす)
+    const SPRITES: [u8; 16] = [
+        40, 4, 0x00, 16, 80, 4, 0x01, 120, 80, 4, 0x22, 124, 200, 4, 0xC3, 250,
+    ];
+
+    let mut asm = Asm { code: vec![] };
+    let reset = asm.here();
+    asm.op(&[0x78, 0xD8, 0xA2, 0xFF, 0x9A, 0xE8]); // SEI, CLD, LDX #$FF, TXS, INX
+    asm.abs(0x8E, 0x2000).abs(0x8E, 0x2001); // STX $2000, STX $2001
+    for _ in 0..2 {
+        let wait = asm.here();
+        asm.abs(0x2C, 0x2002).branch_back(0x10, wait); // BIT $2002, BPL
+    }
+
+    // パレット
+    asm.op(&[0xA9, 0x3F]).abs(0x8D, 0x2006).op(&[0xA9, 0x00]).abs(0x8D, 0x2006);
+    asm.op(&[0xA2, 0x00]); // LDX #0
+    let palette_loop = asm.here();
+    let palette_operand = asm.code.len() + 1;
+    asm.abs(0xBD, 0x0000).abs(0x8D, 0x2007).op(&[0xE8, 0xE0, 0x20]); // LDA PALETTE,X / STA / INX / CPX #32
+    asm.branch_back(0xD0, palette_loop);
+
+    // $2000-$27FF: タイル番号 = (X / 4) ^ ページ & 3 (属性テーブルも同じ式で埋まる)
+    asm.op(&[0xA9, 0x20]).abs(0x8D, 0x2006).op(&[0xA9, 0x00]).abs(0x8D, 0x2006);
+    asm.op(&[0xA0, 0x00]); // LDY #0
+    let page_loop = asm.here();
+    asm.op(&[0x84, 0x00, 0xA2, 0x00]); // STY $00, LDX #0
+    let cell_loop = asm.here();
+    asm.op(&[0x8A, 0x4A, 0x4A, 0x45, 0x00, 0x29, 0x03]); // TXA, LSR, LSR, EOR $00, AND #3
+    asm.abs(0x8D, 0x2007).op(&[0xE8]).branch_back(0xD0, cell_loop);
+    asm.op(&[0xC8, 0xC0, 0x08]).branch_back(0xD0, page_loop); // INY, CPY #8
+
+    // $0200 のOAMを画面外で埋めてから SPRITES を置く
+    asm.op(&[0xA2, 0x00, 0xA9, 0xF0]);
+    let clear_loop = asm.here();
+    asm.abs(0x9D, 0x0200).op(&[0xE8]).branch_back(0xD0, clear_loop);
+    asm.op(&[0xA2, 0x00]);
+    let sprite_loop = asm.here();
+    let sprites_operand = asm.code.len() + 1;
+    asm.abs(0xBD, 0x0000).abs(0x9D, 0x0200).op(&[0xE8, 0xE0, 0x10]);
+    asm.branch_back(0xD0, sprite_loop);
+
+    // スクロール位置は $01、NMIと描画を有効にして待つ
+    asm.op(&[0xA9, 0x00, 0x85, 0x01]);
+    asm.op(&[0xA9, 0x80]).abs(0x8D, 0x2000).op(&[0xA9, 0x1E]).abs(0x8D, 0x2001);
+    let idle = asm.here();
+    asm.abs(0x4C, idle);
+
+    // NMI: OAM DMA、パッドの右でスクロール、スプライト0を動かす
+    let nmi = asm.here();
+    asm.op(&[0xA9, 0x00]).abs(0x8D, 0x2003).op(&[0xA9, 0x02]).abs(0x8D, 0x4014);
+    asm.op(&[0xA9, 0x01]).abs(0x8D, 0x4016).op(&[0xA9, 0x00]).abs(0x8D, 0x4016);
+    for _ in 0..8 {
+        asm.abs(0xAD, 0x4016);
+    }
+    asm.op(&[0x29, 0x01, 0xF0, 0x02, 0xE6, 0x01]); // AND #1, BEQ +2, INC $01
+    asm.abs(0xEE, 0x0203); // INC $0203
+    asm.abs(0xAD, 0x2002).op(&[0xA5, 0x01]).abs(0x8D, 0x2005);
+    asm.op(&[0xA9, 0x00]).abs(0x8D, 0x2005);
+    let rti = asm.here();
+    asm.op(&[0x40]);
+
+    let palette = asm.here();
+    asm.op(&PALETTE);
+    let sprites = asm.here();
+    asm.op(&SPRITES);
+    asm.code[palette_operand..palette_operand + 2].copy_from_slice(&palette.to_le_bytes());
+    asm.code[sprites_operand..sprites_operand + 2].copy_from_slice(&sprites.to_le_bytes());
+
+    let mut prg = asm.code;
+    assert!(prg.len() <= 0x3FFA);
+    prg.resize(0x3FFA, 0xFF);
+    for vector in [nmi, reset, rti] {
+        prg.extend_from_slice(&vector.to_le_bytes());
+    }
+
+    // タイル0は透明、1～3は色1～3の模様、4はスプライト用の枠
+    let mut chr = vec![0u8; 0x2000];
+    for row in 0..8 {
+        let checker = if row % 2 == 0 { 0xAA } else { 0x55 };
+        chr[16 + row] = checker;
+        chr[32 + 8 + row] = 0xF0;
+        chr[48 + row] = 0xFF;
+        chr[48 + 8 + row] = 1 << row;
+        let frame = if row == 0 || row == 7 { 0xFF } else { 0x81 };
+        chr[64 + row] = frame;
+        chr[64 + 8 + row] = frame;
+    }
+
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x01, 0x00];
+    rom.resize(16, 0);
+    rom.extend_from_slice(&prg);
+    rom.extend_from_slice(&chr);
+    rom
+}
+
+// FNV-1a (64bit) Rustのバージョンで値が変わらないように自前で
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+fn dump_ppm(name: &str, frame_no: u32, frame: &Frame) {
+    let _ = fs::create_dir_all(DUMP_DIR);
+    let mut ppm = b"P6\n256 240\n255\n".to_vec();
+    ppm.extend_from_slice(&frame.data);
+    let _ = fs::write(format!("{}/{}_{}.ppm", DUMP_DIR, name, frame_no), ppm);
+}
+
+// 指定フレームのハッシュを返す (描画した Frame も一緒に)
+fn run_case(case: &Case) -> Vec<(u32, u64, Frame)> {
+    let rom = load_rom(case.rom);
+    // 前のタイトルのバンク状態が残らないようにマッパーごと作り直す
+    *MAPPER.lock().unwrap() = Box::new(MapperMMC::new());
+    MAPPER.lock().unwrap().set_rom(&rom);
+
+    let last = *case.shots.iter().max().unwrap_or(&0);
+    let frame_no = Cell::new(0u32);
+    let results: RefCell<Vec<(u32, u64, Frame)>> = RefCell::new(vec![]);
+
+    let bus = Bus::new(rom, APU::new_headless(), |ppu: &PPU, gamepad: &mut GamePad| {
+        let n = frame_no.get() + 1;
+        frame_no.set(n);
+
+        if case.shots.contains(&n) {
+            let mut frame = Frame::new();
+            render::render(ppu, &mut frame);
+            results.borrow_mut().push((n, fnv1a(&frame.data), frame));
+        }
+
+        // 次のフレームの入力
+        for button in BUTTONS {
+            let pressed = case
+                .input
+                .iter()
+                .any(|(from, to, b)| b.contains(button) && *from <= n && n < *to);
+            gamepad.set_button_pressed_status(button, pressed);
+        }
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+
+    let mut last_frame = 0;
+    let mut last_frame_cycles = 0;
+    while frame_no.get() < last {
+        cpu.step();
+        if cpu.is_jammed() {
+            panic!("{}: CPU halted at ${:04X}", case.name, cpu.program_counter);
+        }
+        let cycles = cpu.bus.cycles();
+        if frame_no.get() != last_frame {
+            last_frame = frame_no.get();
+            last_frame_cycles = cycles;
+        } else if cycles - last_frame_cycles > NMI_TIMEOUT_CYCLES {
+            panic!("{}: no NMI after frame {}", case.name, last_frame);
+        }
+    }
+    drop(cpu);
+
+    results.into_inner()
+}
+
+fn load_hashes() -> BTreeMap<(String, u32), u64> {
+    let mut hashes = BTreeMap::new();
+    let text = fs::read_to_string(HASH_FILE).unwrap_or_default();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() != 3 {
+            panic!("{}: invalid line '{}'", HASH_FILE, line);
+        }
+        let frame_no: u32 = cols[1].parse().expect("invalid frame");
+        let hash = u64::from_str_radix(cols[2], 16).expect("invalid hash");
+        hashes.insert((cols[0].to_string(), frame_no), hash);
+    }
+    hashes
+}
+
+fn save_hashes(hashes: &BTreeMap<(String, u32), u64>) {
+    let mut text = String::from("# <name> <frame> <fnv1a64 of Frame::data>\n");
+    text.push_str("# RSCOM_SCREENSHOT_UPDATE=1 cargo test --release --test screenshot で更新\n");
+    for ((name, frame_no), hash) in hashes {
+        text.push_str(&format!("{} {} {:016x}\n", name, frame_no, hash));
+    }
+    fs::write(HASH_FILE, text).expect("can't write hash file");
+}
+
+#[test]
+fn screenshot_hashes() {
+    let update = env::var("RSCOM_SCREENSHOT_UPDATE").is_ok();
+    let only: Option<Vec<String>> = env::var("RSCOM_SCREENSHOT_ONLY")
+        .ok()
+        .map(|names| names.split(',').map(|n| n.trim().to_string()).collect());
+
+    fs::create_dir_all(DUMP_DIR).expect("can't create output directory");
+    fs::write(PATTERN_ROM, pattern_rom()).expect("can't write test ROM");
+
+    let mut hashes = load_hashes();
+    let mut failures: Vec<String> = vec![];
+    let mut compared = 0;
+    for case in CASES {
+        if let Some(only) = &only {
+            if !only.iter().any(|n| n == case.name) {
+                continue;
+            }
+        }
+        if !Path::new(case.rom).exists() {
+            println!("SKIP  {} ({} not found)", case.name, case.rom);
+            continue;
+        }
+
+        for (frame_no, hash, frame) in run_case(case) {
+            let key = (case.name.to_string(), frame_no);
+            if update {
+                hashes.insert(key, hash);
+                continue;
+            }
+            compared += 1;
+            match hashes.get(&key) {
+                Some(expected) if *expected == hash => {
+                    println!("OK    {} frame {}", case.name, frame_no);
+                }
+                Some(expected) => {
+                    dump_ppm(case.name, frame_no, &frame);
+                    failures.push(format!(
+                        "{} frame {}: expected {:016x}, got {:016x}",
+                        case.name, frame_no, expected, hash
+                    ));
+                }
+                None => {
+                    dump_ppm(case.name, frame_no, &frame);
+                    failures.push(format!(
+                        "{} frame {}: no stored hash (run with RSCOM_SCREENSHOT_UPDATE=1)",
+                        case.name, frame_no
+                    ));
+                }
+            }
+        }
+    }
+
+    if update {
+        save_hashes(&hashes);
+        println!("updated {}", HASH_FILE);
+        return;
+    }
+    assert!(compared > 0, "no screenshots were compared (all cases skipped)");
+    assert!(
+        failures.is_empty(),
+        "screenshot mismatch (see {}):\n{}",
+        DUMP_DIR,
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,228 @@
+// Tom Harte / SingleStepTests (https://github.com/SingleStepTests/65x02) の
+// テストベクタでCPUを1命令ずつ検証する。
+//
+// テストデータは巨大なので同梱していない。NES用(デシマルモード無し)の
+// nes6502 ディレクトリをローカルに取得して、下記のように実行する。
+//
+//   RSCOM_SST_DIR=path/to/65x02/nes6502/v1 cargo test --test single_step -- --nocapture
+//
+// RSCOM_SST_DIR  : 00.json ～ ff.json が置かれたディレクトリ (未指定ならスキップ)
+// RSCOM_SST_OPS  : 対象オペコードを絞る (例: "a9,0b,9e")
+//
+// サイクル毎のバスアクセス (ダミーの読み書き) はモデル化していないので、
+// 比較するのはレジスタ、メモリとサイクル数のみ
+use rscom::bus::Mem;
+use rscom::cpu::CPU;
+use rscom::opcode::CPU_OPS_CODES;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// 64KBフラットRAM
+struct TestBus {
+    ram: Vec<u8>,
+}
+
+impl TestBus {
+    fn new() -> Self {
+        TestBus {
+            ram: vec![0; 0x10000],
+        }
+    }
+}
+
+impl Mem for TestBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn poke(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+}
+
+fn as_u8(v: &Value) -> u8 {
+    v.as_u64().expect("number") as u8
+}
+
+fn as_u16(v: &Value) -> u16 {
+    v.as_u64().expect("number") as u16
+}
+
+fn run_case(case: &Value) -> Result<(), String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut cpu = CPU::new(TestBus::new());
+    cpu.program_counter = as_u16(&initial["pc"]);
+    cpu.stack_pointer = as_u8(&initial["s"]);
+    cpu.register_a = as_u8(&initial["a"]);
+    cpu.register_x = as_u8(&initial["x"]);
+    cpu.register_y = as_u8(&initial["y"]);
+    cpu.status = as_u8(&initial["p"]);
+    for entry in initial["ram"].as_array().expect("ram") {
+        cpu.bus.ram[as_u16(&entry[0]) as usize] = as_u8(&entry[1]);
+    }
+
+    let cycles = cpu.step();
+
+    let mut errors: Vec<String> = vec![];
+    let regs = [
+        ("pc", expected["pc"].as_u64(), cpu.program_counter as u64),
+        ("s", expected["s"].as_u64(), cpu.stack_pointer as u64),
+        ("a", expected["a"].as_u64(), cpu.register_a as u64),
+        ("x", expected["x"].as_u64(), cpu.register_x as u64),
+        ("y", expected["y"].as_u64(), cpu.register_y as u64),
+        ("p", expected["p"].as_u64(), cpu.status as u64),
+    ];
+    for (name, want, got) in regs {
+        let want = want.expect("register");
+        if want != got {
+            errors.push(format!("{}: expected {:02X}, got {:02X}", name, want, got));
+        }
+    }
+
+    for entry in expected["ram"].as_array().expect("ram") {
+        let addr = as_u16(&entry[0]);
+        let want = as_u8(&entry[1]);
+        let got = cpu.bus.ram[addr as usize];
+        if want != got {
+            errors.push(format!("${:04X}: expected {:02X}, got {:02X}", addr, want, got));
+        }
+    }
+
+    let bus_cycles = case["cycles"].as_array().expect("cycles");
+    if bus_cycles.len() != cycles as usize {
+        errors.push(format!(
+            "cycles: expected {}, got {}",
+            bus_cycles.len(),
+            cycles
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn op_name(code: u8) -> String {
+    CPU_OPS_CODES
+        .iter()
+        .find(|op| op.code == code)
+        .map(|op| op.name.clone())
+        .unwrap_or_default()
+}
+
+#[test]
+fn single_step_tests() {
+    let dir = match env::var("RSCOM_SST_DIR") {
+        Ok(dir) => dir,
+        Err(_) => {
+            println!("RSCOM_SST_DIR is not set. skip SingleStepTests.");
+            return;
+        }
+    };
+    let filter: Option<Vec<u8>> = env::var("RSCOM_SST_OPS").ok().map(|ops| {
+        ops.split(',')
+            .map(|op| u8::from_str_radix(op.trim(), 16).expect("RSCOM_SST_OPS"))
+            .collect()
+    });
+
+    let mut failed_ops: Vec<String> = vec![];
+    for code in 0x00..=0xFFu8 {
+        if let Some(filter) = &filter {
+            if !filter.contains(&code) {
+                continue;
+            }
+        }
+
+        let name = op_name(code);
+        // JAMはCPUが停止するだけなので対象外
+        if name == "*JAM" {
+            continue;
+        }
+
+        let path = Path::new(&dir).join(format!("{:02x}.json", code));
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(_) => continue,
+        };
+        let cases: Value = serde_json::from_str(&json).expect("invalid test vector");
+        let cases = cases.as_array().expect("test vector must be an array");
+
+        let mut passed = 0;
+        let mut first_error: Option<String> = None;
+        for case in cases {
+            match run_case(case) {
+                Ok(()) => passed += 1,
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(format!("[{}] {}", case["name"], e));
+                    }
+                }
+            }
+        }
+
+        println!(
+            "{:02X} {:<5} {:>6}/{:<6} {}",
+            code,
+            name,
+            passed,
+            cases.len(),
+            first_error.clone().unwrap_or_default()
+        );
+        if first_error.is_some() {
+            failed_ops.push(format!("{:02X}({})", code, name));
+        }
+    }
+
+    assert!(
+        failed_ops.is_empty(),
+        "SingleStepTests failed: {}",
+        failed_ops.join(" ")
+    );
+}
+
+// テストデータが無くてもランナー自体が動くことを確認する
+#[test]
+fn single_step_runner_inline_vectors() {
+    let cases: Value = serde_json::from_str(
+        r#"[
+        {
+            "name": "0b anc",
+            "initial": { "pc": 768, "s": 253, "a": 192, "x": 0, "y": 0, "p": 36,
+                         "ram": [[768, 11], [769, 128]] },
+            "final":   { "pc": 770, "s": 253, "a": 128, "x": 0, "y": 0, "p": 165,
+                         "ram": [[768, 11], [769, 128]] },
+            "cycles": [[768, 11, "read"], [769, 128, "read"]]
+        },
+        {
+            "name": "9e shx page cross",
+            "initial": { "pc": 512, "s": 253, "a": 0, "x": 5, "y": 32, "p": 36,
+                         "ram": [[512, 158], [513, 240], [514, 18]] },
+            "final":   { "pc": 515, "s": 253, "a": 0, "x": 5, "y": 32, "p": 36,
+                         "ram": [[272, 1]] },
+            "cycles": [[512, 158, "read"], [513, 240, "read"], [514, 18, "read"],
+                       [4880, 0, "read"], [272, 1, "write"]]
+        }
+    ]"#,
+    )
+    .unwrap();
+
+    for case in cases.as_array().unwrap() {
+        if let Err(e) = run_case(case) {
+            panic!("{}: {}", case["name"], e);
+        }
+    }
+}
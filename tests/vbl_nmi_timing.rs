@@ -0,0 +1,47 @@
+// VBlank/NMIとOAM DMAのタイミングのテストROMを rscom-test で実行する
+//
+// ROMは同梱していないので、ローカルに用意して下記のように実行する。
+// 結果を $6000 に書き込む blargg の ppu_vbl_nmi (vbl_nmi_timing の後継) の rom_singles と、
+// sprdma_and_dmc_dma 等を1つのディレクトリに置く。
+//
+//   RSCOM_VBL_NMI_ROMS=rom/ppu_vbl_nmi cargo test --release --test vbl_nmi_timing -- --nocapture
+//
+// RSCOM_VBL_NMI_ROMS    : ROMのディレクトリ (未指定ならスキップ)
+// RSCOM_VBL_NMI_TIMEOUT : 1ROMあたりのタイムアウト秒 (省略時 60)
+//
+// 全部のROMが通らなければ失敗する。ROMが1つも無い時も失敗にする。
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn vbl_nmi_timing_roms() {
+    let dir = match env::var("RSCOM_VBL_NMI_ROMS") {
+        Ok(dir) => dir,
+        Err(_) => {
+            println!("RSCOM_VBL_NMI_ROMS is not set. skip vbl_nmi_timing.");
+            return;
+        }
+    };
+    let timeout = env::var("RSCOM_VBL_NMI_TIMEOUT").unwrap_or_else(|_| "60".to_string());
+    assert!(Path::new(&dir).is_dir(), "{} is not a directory", dir);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rscom-test"))
+        .arg("--timeout")
+        .arg(&timeout)
+        .arg(&dir)
+        .output()
+        .expect("can't run rscom-test");
+    let report = String::from_utf8_lossy(&output.stdout);
+    print!("{}", report);
+    // 最後の行は "<通った数>/<ROMの数> passed"
+    let total = report
+        .lines()
+        .last()
+        .and_then(|line| line.strip_suffix(" passed"))
+        .and_then(|count| count.split('/').nth(1))
+        .and_then(|total| total.parse::<usize>().ok())
+        .unwrap_or(0);
+    assert!(total > 0, "no .nes files in {}", dir);
+    assert!(output.status.success(), "some timing ROMs failed");
+}